@@ -20,6 +20,7 @@ const CONFIG: Cfg = Cfg {
 };
 
 const SCRIPT_TAG: &str = r#"<script type="text/javascript" src="client.js"></script>"#;
+const ARGON2_TAG: &str = r#"<script type="text/javascript" src="argon2.js"></script>"#;
 
 fn main() {
     // This could be done in a loop, but since some
@@ -45,9 +46,15 @@ fn main() {
     js_client.push_str("</script>");
     #[cfg(feature = "tls")] let js_client = js_client.replace("ws://", "wss://");
 
+    // argon2.js - load into html pages, same as client.js
+    println!("cargo:rerun-if-changed=page/argon2.js");
+    let js_argon2 = [r#"<script type="text/javascript">"#, include_str!("page/argon2.js"), "</script>"].concat();
+
     // index - load, minify
     println!("cargo:rerun-if-changed=page/index.html");
-    let html_index = include_str!("page/index.html").replace(SCRIPT_TAG, &js_client);
+    let html_index = include_str!("page/index.html")
+        .replace(SCRIPT_TAG, &js_client)
+        .replace(ARGON2_TAG, &js_argon2);
     let html_index_data = html_index.as_bytes();
     #[cfg(feature = "minify")] let html_index_data = &minify(html_index_data, &CONFIG);
     let mut file = File::create(["target/index", suf, ext].concat()).unwrap();
@@ -55,9 +62,17 @@ fn main() {
 
     // mobile - load, minify (copy paste of index)
     println!("cargo:rerun-if-changed=page/mobile.html");
-    let html_mobile = include_str!("page/mobile.html").replace(SCRIPT_TAG, &js_client);
+    let html_mobile = include_str!("page/mobile.html")
+        .replace(SCRIPT_TAG, &js_client)
+        .replace(ARGON2_TAG, &js_argon2);
     let html_mobile_data = html_mobile.as_bytes();
     #[cfg(feature = "minify")] let html_mobile_data = &minify(html_mobile_data, &CONFIG);
     let mut file = File::create(["target/mobile", suf, ext].concat()).unwrap();
     file.write_all(html_mobile_data).unwrap();
+
+    // tools - load (not minified)
+    println!("cargo:rerun-if-changed=page/tools.html");
+    let html_tools = include_str!("page/tools.html").replace(ARGON2_TAG, &js_argon2);
+    let mut file = File::create("target/tools.html").unwrap();
+    file.write_all(html_tools.as_bytes()).unwrap();
 }
@@ -0,0 +1,288 @@
+// Argon2id (RFC 9106) in plain javascript, so the key derivation doesn't
+// depend on a CDN. Blocking; 19 MiB and 2 passes take about a second.
+// argon2id({password, salt, parallelism, iterations, memorySize, hashLength})
+// takes options named as hash-wasm's (memorySize in KiB) and gives a Uint8Array.
+argon2id = function() {
+  const block_words = 256;  // 1 KiB blocks as 32 bit halves of 64 bit words
+  const sync_points = 4;
+  const version = 0x13;
+  const type_id = 2;  // argon2id
+
+  // *******************************BLAKE2B************************************
+  const blake2b_iv = new Uint32Array([  // low, high
+    0xf3bcc908, 0x6a09e667, 0x84caa73b, 0xbb67ae85,
+    0xfe94f82b, 0x3c6ef372, 0x5f1d36f1, 0xa54ff53a,
+    0xade682d1, 0x510e527f, 0x2b3e6c1f, 0x9b05688c,
+    0xfb41bd6b, 0x1f83d9ab, 0x137e2179, 0x5be0cd19,
+  ]);
+  const sigma = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3,
+    11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4,
+    7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8,
+    9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13,
+    2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9,
+    12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11,
+    13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10,
+    6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5,
+    10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0,
+  ];
+
+  function add64(v, a, b) {  // v[a] += v[b]
+    var lo = v[a] + v[b];
+    v[a + 1] += v[b + 1] + (lo > 0xffffffff ? 1 : 0);
+    v[a] = lo;
+  };
+  function add64_words(v, a, m, b) {  // v[a] += m[b]
+    var lo = v[a] + m[b];
+    v[a + 1] += m[b + 1] + (lo > 0xffffffff ? 1 : 0);
+    v[a] = lo;
+  };
+  function blake2b_mix(v, m, a, b, c, d, x, y) {
+    add64(v, a, b);
+    add64_words(v, a, m, x);
+    var lo = v[d] ^ v[a], hi = v[d + 1] ^ v[a + 1];  // >>> 32
+    v[d] = hi; v[d + 1] = lo;
+    add64(v, c, d);
+    lo = v[b] ^ v[c]; hi = v[b + 1] ^ v[c + 1];  // >>> 24
+    v[b] = (lo >>> 24) | (hi << 8); v[b + 1] = (hi >>> 24) | (lo << 8);
+    add64(v, a, b);
+    add64_words(v, a, m, y);
+    lo = v[d] ^ v[a]; hi = v[d + 1] ^ v[a + 1];  // >>> 16
+    v[d] = (lo >>> 16) | (hi << 16); v[d + 1] = (hi >>> 16) | (lo << 16);
+    add64(v, c, d);
+    lo = v[b] ^ v[c]; hi = v[b + 1] ^ v[c + 1];  // >>> 63
+    v[b] = (hi >>> 31) | (lo << 1); v[b + 1] = (lo >>> 31) | (hi << 1);
+  };
+  function blake2b_compress(h, chunk, counter, last) {
+    var v = new Uint32Array(32);
+    var m = new Uint32Array(32);
+    v.set(h);
+    v.set(blake2b_iv, 16);
+    v[24] ^= counter % 0x100000000;  // counter < 2^53, plenty
+    v[25] ^= Math.floor(counter / 0x100000000);
+    if (last) {v[28] = ~v[28]; v[29] = ~v[29];}
+    for (var i = 0; i < 32; i++) {
+      m[i] = chunk[4*i] | chunk[4*i + 1] << 8 | chunk[4*i + 2] << 16 | chunk[4*i + 3] << 24;
+    }
+    for (var r = 0; r < 12; r++) {
+      var s = sigma.slice(16 * (r % 10), 16 * (r % 10) + 16).map((x) => 2 * x);
+      blake2b_mix(v, m, 0, 8, 16, 24, s[0], s[1]);
+      blake2b_mix(v, m, 2, 10, 18, 26, s[2], s[3]);
+      blake2b_mix(v, m, 4, 12, 20, 28, s[4], s[5]);
+      blake2b_mix(v, m, 6, 14, 22, 30, s[6], s[7]);
+      blake2b_mix(v, m, 0, 10, 20, 30, s[8], s[9]);
+      blake2b_mix(v, m, 2, 12, 22, 24, s[10], s[11]);
+      blake2b_mix(v, m, 4, 14, 16, 26, s[12], s[13]);
+      blake2b_mix(v, m, 6, 8, 18, 28, s[14], s[15]);
+    }
+    for (var i = 0; i < 16; i++) {h[i] ^= v[i] ^ v[i + 16];}
+  };
+  function blake2b(input, out_length) {  // unkeyed, 1 to 64 bytes out
+    var h = new Uint32Array(blake2b_iv);
+    h[0] ^= 0x01010000 ^ out_length;
+    var chunk = new Uint8Array(128);
+    var pos = 0;
+    // the last chunk is compressed with the final flag, even if it's full
+    while (input.length - pos > 128) {
+      blake2b_compress(h, input.subarray(pos, pos + 128), pos + 128, false);
+      pos += 128;
+    }
+    chunk.set(input.subarray(pos));
+    blake2b_compress(h, chunk, input.length, true);
+    var out = new Uint8Array(out_length);
+    for (var i = 0; i < out_length; i++) {out[i] = h[i >> 2] >>> (8 * (i & 3));}
+    return out;
+  };
+  function concat(parts) {  // of Uint8Arrays and u32 numbers (little endian)
+    var bytes = parts.map((p) => typeof p == "number" ? le32(p) : p);
+    var out = new Uint8Array(bytes.reduce((n, p) => n + p.length, 0));
+    bytes.reduce((pos, p) => {out.set(p, pos); return pos + p.length;}, 0);
+    return out;
+  };
+  function le32(n) {return new Uint8Array([n, n >>> 8, n >>> 16, n >>> 24]);}
+  function blake2b_long(input, out_length) {  // H' of the RFC
+    input = concat([out_length, input]);
+    if (out_length <= 64) {return blake2b(input, out_length);}
+    var out = new Uint8Array(out_length);
+    var v = blake2b(input, 64);
+    var pos = 0;
+    for (; out_length - pos > 64; pos += 32) {  // first halves of each
+      out.set(v.subarray(0, 32), pos);
+      v = blake2b(v, Math.min(64, out_length - pos - 32));
+    }
+    out.set(v, pos);
+    return out;
+  };
+
+  // *******************************COMPRESSION********************************
+  function mul_high(a, b) {  // high 32 bits of the product of two u32s
+    // the float product is off by far less than 2^32, so this rounds right
+    return Math.round((a * b - (Math.imul(a, b) >>> 0)) / 0x100000000);
+  };
+  function mix(v, a, b, c, d) {  // blake2b's G with multiplications (BlaMka)
+    var al = v[a], ah = v[a + 1], bl = v[b], bh = v[b + 1];
+    var cl = v[c], ch = v[c + 1], dl = v[d], dh = v[d + 1];
+    var lo, hi, pl, ph, t;
+
+    // a = a + b + 2 * lo(a) * lo(b)
+    pl = Math.imul(al, bl) >>> 0; ph = mul_high(al, bl);
+    ph = (ph << 1 | pl >>> 31) >>> 0; pl = (pl << 1) >>> 0;
+    lo = al + bl; hi = ah + bh + (lo > 0xffffffff ? 1 : 0); lo >>>= 0;
+    t = lo + pl; al = t >>> 0; ah = (hi + ph + (t > 0xffffffff ? 1 : 0)) >>> 0;
+    // d = (d ^ a) >>> 32
+    t = dl ^ al; dl = dh ^ ah; dh = t;
+    // c = c + d + 2 * lo(c) * lo(d)
+    pl = Math.imul(cl, dl) >>> 0; ph = mul_high(cl >>> 0, dl >>> 0);
+    ph = (ph << 1 | pl >>> 31) >>> 0; pl = (pl << 1) >>> 0;
+    lo = (cl >>> 0) + (dl >>> 0); hi = ch + (dh >>> 0) + (lo > 0xffffffff ? 1 : 0); lo >>>= 0;
+    t = lo + pl; cl = t >>> 0; ch = (hi + ph + (t > 0xffffffff ? 1 : 0)) >>> 0;
+    // b = (b ^ c) >>> 24
+    lo = bl ^ cl; hi = bh ^ ch;
+    bl = (lo >>> 24 | hi << 8) >>> 0; bh = (hi >>> 24 | lo << 8) >>> 0;
+    // a = a + b + 2 * lo(a) * lo(b)
+    pl = Math.imul(al, bl) >>> 0; ph = mul_high(al, bl);
+    ph = (ph << 1 | pl >>> 31) >>> 0; pl = (pl << 1) >>> 0;
+    lo = al + bl; hi = ah + bh + (lo > 0xffffffff ? 1 : 0); lo >>>= 0;
+    t = lo + pl; al = t >>> 0; ah = (hi + ph + (t > 0xffffffff ? 1 : 0)) >>> 0;
+    // d = (d ^ a) >>> 16
+    lo = dl ^ al; hi = dh ^ ah;
+    dl = (lo >>> 16 | hi << 16) >>> 0; dh = (hi >>> 16 | lo << 16) >>> 0;
+    // c = c + d + 2 * lo(c) * lo(d)
+    pl = Math.imul(cl, dl) >>> 0; ph = mul_high(cl, dl);
+    ph = (ph << 1 | pl >>> 31) >>> 0; pl = (pl << 1) >>> 0;
+    lo = cl + dl; hi = ch + dh + (lo > 0xffffffff ? 1 : 0); lo >>>= 0;
+    t = lo + pl; cl = t >>> 0; ch = (hi + ph + (t > 0xffffffff ? 1 : 0)) >>> 0;
+    // b = (b ^ c) >>> 63
+    lo = bl ^ cl; hi = bh ^ ch;
+    bl = hi >>> 31 | lo << 1; bh = lo >>> 31 | hi << 1;
+
+    v[a] = al; v[a + 1] = ah; v[b] = bl; v[b + 1] = bh;
+    v[c] = cl; v[c + 1] = ch; v[d] = dl; v[d + 1] = dh;
+  };
+  function round(v, w) {  // blake2b round over 16 words; w: their indices
+    mix(v, w[0], w[4], w[8], w[12]);
+    mix(v, w[1], w[5], w[9], w[13]);
+    mix(v, w[2], w[6], w[10], w[14]);
+    mix(v, w[3], w[7], w[11], w[15]);
+    mix(v, w[0], w[5], w[10], w[15]);
+    mix(v, w[1], w[6], w[11], w[12]);
+    mix(v, w[2], w[7], w[8], w[13]);
+    mix(v, w[3], w[4], w[9], w[14]);
+  };
+  // word indices (of the low halves) of each row and column of a block
+  const rows = [], columns = [];
+  for (var i = 0; i < 8; i++) {
+    var row = [], column = [];
+    for (var j = 0; j < 16; j++) {
+      row.push(2 * (16 * i + j));
+      column.push(2 * (2 * i + 16 * (j >> 1) + (j & 1)));
+    }
+    rows.push(row);
+    columns.push(column);
+  }
+  const r_block = new Uint32Array(block_words);
+  const t_block = new Uint32Array(block_words);
+  function compress(mem, prev, ref, next, with_xor) {  // offsets into mem
+    for (var i = 0; i < block_words; i++) {r_block[i] = mem[prev + i] ^ mem[ref + i];}
+    t_block.set(r_block);
+    if (with_xor) {
+      for (var i = 0; i < block_words; i++) {t_block[i] ^= mem[next + i];}
+    }
+    for (var i = 0; i < 8; i++) {round(r_block, rows[i]);}
+    for (var i = 0; i < 8; i++) {round(r_block, columns[i]);}
+    for (var i = 0; i < block_words; i++) {mem[next + i] = t_block[i] ^ r_block[i];}
+  };
+
+  // *******************************ARGON2ID***********************************
+  function hash(password, salt, lanes, passes, memory_kib, tag_length) {
+    var blocks = Math.max(memory_kib, 2 * sync_points * lanes);
+    blocks -= blocks % (sync_points * lanes);
+    var lane_length = blocks / lanes;
+    var segment_length = lane_length / sync_points;
+    var mem = new Uint32Array(blocks * block_words);
+
+    var h0 = blake2b(concat([
+      lanes, tag_length, memory_kib, passes, version, type_id,
+      password.length, password, salt.length, salt,
+      0, 0,  // no secret, no associated data
+    ]), 64);
+    for (var lane = 0; lane < lanes; lane++) {
+      for (var i = 0; i < 2; i++) {
+        var first = blake2b_long(concat([h0, i, lane]), 1024);
+        mem.set(new Uint32Array(first.buffer), (lane * lane_length + i) * block_words);
+      }
+    }
+
+    var zero_block = new Uint32Array(block_words);
+    var input = new Uint32Array(block_words);  // for data independent addresses
+    var addresses = new Uint32Array(3 * block_words);  // zero, input and result
+    function next_addresses() {
+      input[12] += 1;  // counter
+      addresses.set(input, block_words);
+      compress(addresses, 0, block_words, 2 * block_words, false);
+      addresses.copyWithin(block_words, 2 * block_words, 3 * block_words);
+      compress(addresses, 0, block_words, 2 * block_words, false);
+    };
+    for (var pass = 0; pass < passes; pass++) {
+      for (var slice = 0; slice < sync_points; slice++) {
+        for (var lane = 0; lane < lanes; lane++) {
+          var independent = pass == 0 && slice < sync_points / 2;
+          if (independent) {
+            input.set(zero_block);
+            input[0] = pass; input[2] = lane; input[4] = slice;
+            input[6] = blocks; input[8] = passes; input[10] = type_id;
+          }
+          var start = pass == 0 && slice == 0 ? 2 : 0;  // first blocks are set
+          if (independent && start == 2) {next_addresses();}
+          for (var index = start; index < segment_length; index++) {
+            var column = slice * segment_length + index;
+            var curr = lane * lane_length + column;
+            var prev = column == 0 ? curr + lane_length - 1 : curr - 1;
+            var j1, j2;
+            if (independent) {
+              if (index % 128 == 0) {next_addresses();}
+              j1 = addresses[2 * block_words + 2 * (index % 128)];
+              j2 = addresses[2 * block_words + 2 * (index % 128) + 1];
+            } else {
+              j1 = mem[prev * block_words];
+              j2 = mem[prev * block_words + 1];
+            }
+            var ref_lane = pass == 0 && slice == 0 ? lane : j2 % lanes;
+            var same_lane = ref_lane == lane;
+            var area;  // blocks that can be referenced
+            if (pass == 0) {
+              area = slice * segment_length;
+              if (slice == 0 || same_lane) {area += index - 1;}
+              else if (index == 0) {area -= 1;}
+            } else {
+              area = lane_length - segment_length;
+              if (same_lane) {area += index - 1;}
+              else if (index == 0) {area -= 1;}
+            }
+            var x = mul_high(j1, j1);
+            var relative = area - 1 - mul_high(area, x);
+            var start_column = pass == 0 || slice == sync_points - 1
+              ? 0 : (slice + 1) * segment_length;
+            var ref = ref_lane * lane_length + (start_column + relative) % lane_length;
+            compress(mem, prev * block_words, ref * block_words, curr * block_words, pass > 0);
+          }
+        }
+      }
+    }
+
+    var last = mem.slice((lane_length - 1) * block_words, lane_length * block_words);
+    for (var lane = 1; lane < lanes; lane++) {
+      var offset = ((lane + 1) * lane_length - 1) * block_words;
+      for (var i = 0; i < block_words; i++) {last[i] ^= mem[offset + i];}
+    }
+    return blake2b_long(new Uint8Array(last.buffer), tag_length);
+  };
+
+  return function(options) {
+    return new Promise((resolve) => resolve(hash(
+      options.password, options.salt, options.parallelism,
+      options.iterations, options.memorySize, options.hashLength,
+    )));
+  };
+}();
@@ -16,30 +16,80 @@ main = function() {
   Discuss webpages with no comments section (set the title to page's url)`,
 ];
   const message_byte_size = 512;
-  const message_content_length = 396;
-  const cypher_length = message_content_length + 4 + 8 + 32;
+  const cypher_length = 440;
+  const message_content_length = 369;  // v2; 380 in v1, 396 in v0 cyphers
+  const cypher_v1 = 1;
+  const cypher_v2 = 2;
+  const cypher_iv_length = 16;  // v1: plaintext version + nonce
+  const aead_nonce_length = 12;  // v2: plaintext nonce after version
+  // key derivation; must match every other client (see constants.rs)
+  const kdf_params = {
+    parallelism: 1,
+    iterations: 2,
+    memorySize: 19 * 1024,  // KiB
+    hashLength: 32,
+  };
+  const kdf_title_salt = "publichat-title-v1";
+  const kdf_user_salt = "publichat-user-v1";
+  // AES-GCM is only available in secure contexts; without it we only read
+  const has_aead = window.crypto !== undefined && crypto.subtle !== undefined;
+  const no_aead_str = "Sending needs a secure connection (https or localhost); here messages can only be read";
   const fch_pad = [102,  99, 104];  // "fch"
   const qry_pad = [113, 114, 121];  // "qry"
   const snd_pad = [115, 110, 100];  // "snd"
+  const sub_pad = [115, 117,  98];  // "sub"
+  const uns_pad = [117, 110, 115];  // "uns"
   const end_pad = [101, 110, 100];  // "end"
   const rcv_pad = [109, 115, 103];  // "msg"
+  const hlo_pad = [104, 108, 111];  // "hlo"
+  const err_pad = [101, 114, 114];  // "err"
+  const ack_pad = [ 97,  99, 107];  // "ack"
+  const tim_pad = [116, 105, 109];  // "tim"
+  const protocol_version = 2;  // 2: snd packets carry a proof of work stamp
+  const features = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 3;  // fetch count, time queries, send acks, wide ids
+  var server_hello = null;  // {version, features} once the server answered
+  var use_hello = true;  // false once a server hung up on one; it speaks version 0
+  var ready = false;  // hello answered (or skipped); nothing else is sent before
+  var server_error = null;  // last err packet, shown on the socket button
+  var unacked = 0;  // messages sent that the server hasn't answered yet
   var max_message_id = Number.MIN_SAFE_INTEGER;
   var min_message_id = Number.MAX_SAFE_INTEGER;
+  var up_queries = 0;  // older messages asked for by scrolling, not answered yet
+  var jumping = false;  // asked for a date; its first answer hasn't come
+  var jump_at = null;  // id of the date's first message, until its answer comes
   var chat_id_hash = [];  // hash of current chat id
+  var subscribed_id = null;  // chat id the server pushes new messages for
+  var last_send = 0;  // server drops sockets that are quiet for too long
+  const keepalive_ms = 30000;
+  const jump_context = 20;  // messages shown from before a date jumped to
+  const jump_count = 50;  // and from it on
+  var chat_keys = {title: null, legacy: false, chat_key: [], chat_id: []};
+  var deriving = false;  // chat keys are being derived
+  var legacy = false;  // open chats from before Argon2; only when asked, never probed
+  var user_keys = {password: null, key_pair: null};
   var style = getComputedStyle(document.body);
   var send_button = document.getElementById("send_button");
   var socket_button = document.getElementById("socket_button");
   var sending_div = document.getElementById("sending_div");
   var message_entry = document.getElementById("message_entry");
+  var jump_date = document.getElementById("jump_date");
+  var legacy_button = document.getElementById("legacy_button");
   let message_list_div = document.getElementById("message_list");
   send_button.onclick = function() {send_message()};
   socket_button.onclick = function() {toggle_loop();};
   message_list_div.addEventListener("scroll", top_scroll_query);
   message_entry.addEventListener("keyup", keystroke_input);
+  jump_date.addEventListener("change", jump);
+  legacy_button.onclick = function() {toggle_legacy();};
+  if (!has_aead) {  // say so where the message would be typed
+    message_entry.placeholder = no_aead_str;
+    message_entry.disabled = true;
+    send_button.title = no_aead_str;
+    send_button.style.background = style.getPropertyValue("--status_err");
+  }
   
   const utf8decoder = new TextDecoder();
   const utf8encoder = new TextEncoder();
-  var reader = new FileReader();
   var socket = null;
   var loop = false;
   var recv_packets = 0;
@@ -119,14 +169,29 @@ main = function() {
   // *******************************OPEN_SOCKET********************************
   function open_socket() {
     set_status(0);
-    socket = new WebSocket("ws://" + location.host + "/ws");
+    const ws_proto = location.protocol == "https:" ? "wss://" : "ws://";
+    socket = new WebSocket(ws_proto + location.host + "/ws");
+    socket.binaryType = "arraybuffer";  // parse synchronously, keeps push order
+    ready = false;
+    var socket_opened = false;
     socket.onopen = function() {
       console.log("socket opened");
-      setTimeout(function() {loop = true;}, 1000);
-      set_status(1);
+      socket_opened = true;
+      server_hello = null;
+      server_error = null;
+      unacked = 0;
+      up_queries = 0;
+      if (use_hello) {  // the rest waits for its answer
+        ws_send([].concat(hlo_pad, [protocol_version], pack_number(features, 4), end_pad));
+      } else {
+        start();
+      }
     };
     socket.onerror = function(e) {shutdown(e)};
-    socket.onclose = function(e) {shutdown(e)};
+    socket.onclose = function(e) {
+      if (socket_opened && !ready && use_hello) {without_hello(); return;}  // from before err packets
+      shutdown(e);
+    };
     socket.onmessage = function(e) {ws_receive(e)};
     reset_chat();
 
@@ -134,6 +199,21 @@ main = function() {
       landing_page();
     }
   };
+  function start() {
+    // the server knows what we speak; go
+    ready = true;
+    loop = true;
+    set_status(1);
+  };
+  function without_hello() {
+    // the server refused our hello; reconnect and speak version 0
+    console.log("Server doesn't take hellos; reconnecting without one");
+    use_hello = false;
+    socket.onclose = null;
+    socket.onerror = null;
+    socket.close();
+    open_socket();
+  };
   function ws_send(bytes) {
     if (socket.readyState != WebSocket.OPEN) {
       shutdown("Tried sending to dead socket");
@@ -141,18 +221,23 @@ main = function() {
     }
     var outgoing = new Uint8Array(bytes);
     socket.send(outgoing);
+    last_send = Date.now();
   };
 
   // *********************************SHUTDOWN/RESET***************************
   function shutdown(e) {
     loop = false;
+    subscribed_id = null;  // server forgets subscriptions of dead sockets
     set_status(4);  // red button top left
+    if (server_error !== null) {socket_button.title = server_error;}
     send_button.style.backgroundColor = style.getPropertyValue("--status_err");
     if (typeof e != "string") {console.log("ws error! "+e.code+e.reason);}
     else {console.log(e);}
   };
   function reset_chat(){
     message_list_div.replaceChildren();
+    jumping = false;
+    jump_at = null;
     max_message_id = Number.MIN_SAFE_INTEGER;
     min_message_id = Number.MAX_SAFE_INTEGER;
   };
@@ -162,7 +247,7 @@ main = function() {
     if (socket.readyState != WebSocket.OPEN) {
       loop = false;
       open_socket();
-    } else {
+    } else if (ready) {
       loop = !loop;
       set_status({true: 1, false: 2}[loop]);
     }
@@ -174,17 +259,45 @@ main = function() {
     recv_packets += 1;
     caught_timeout = false;
 
-    var blob = message_event.data;
-    reader.readAsArrayBuffer(blob);
+    read_packet(message_event.data);
   };
-  reader.onload = function() {
-    var result = reader.result;
+  function read_packet(result) {
     var bytes_u8_array = new Uint8Array(result);
     var bytes = Array.from(bytes_u8_array);
     // read packet header
     var msg_padding = bytes.splice(0, 3);
+    if (msg_padding.every((b, i) => b == hlo_pad[i])) {
+      server_hello = {
+        version: bytes[0],
+        features: unpack_number(bytes.slice(1, 5)),
+        pow_bits: bytes[0] >= 2 ? bytes[5] : null,  // difficulty from version 2 on
+      };
+      console.log("protocol version " + server_hello.version);
+      start();
+      return;
+    }
+    if (msg_padding.every((b, i) => b == err_pad[i])) {
+      var text = new TextDecoder().decode(new Uint8Array(bytes.slice(2, 2 + bytes[1])));
+      if (!ready && (bytes[0] == 1 || bytes[0] == 2)) {  // doesn't know hellos, or ours
+        console.log("Server says: " + text);
+        without_hello();
+        return;
+      }
+      if (bytes[0] == 3) {  // rate limited: only that request was dropped
+        socket_button.title = "Server is throttling us: " + text;
+        console.log(socket_button.title);
+        return;
+      }
+      server_error = "Server error " + bytes[0] + ": " + text;
+      shutdown(server_error);
+      return;
+    }
+    if (msg_padding.every((b, i) => b == ack_pad[i])) {
+      read_ack(bytes[0], unpack_number(bytes.slice(1, 1 + id_size())));
+      return;
+    }
     var chat_id_byte = bytes.splice(0, 1);
-    var message_id = unpack_number(bytes.splice(0, 3));
+    var message_id = unpack_number(bytes.splice(0, id_size()));
     var message_count_and_direction = bytes.splice(0, 1)[0];
     var message_count = message_count_and_direction & 0x7f;
     var build_upwards = (message_count_and_direction & 0x80) == 0;
@@ -194,9 +307,43 @@ main = function() {
     if (msg_padding[2] != rcv_pad[2]) {shutdown("incorrect smrt pad 3");}
     if (chat_id_byte != chat_id_hash[0]) {return;}
     if (message_count*message_byte_size != bytes.length) {return}
-    
+
+    if (build_upwards && up_queries > 0) {
+      up_queries -= 1;
+      if (jumping || jump_at != null) {return;}  // from before the jump
+    } else if (build_upwards && jumping) {
+      // a jump's messages from just before the date; the rest come next
+      reset_chat();
+      jump_at = message_id + message_count;  // even if empty, the id is where the date falls
+      if (message_count === 0) {return;}  // nothing before it
+    } else if (!build_upwards && jump_at != null) {
+      if (message_id != jump_at) {return;}  // pushes; caught up on later
+      read_jump(bytes, message_id, message_count);
+      return;
+    }
+
     if (message_count === 0) {return;}
 
+    if (!build_upwards && max_message_id >= min_message_id) {
+      // pushes and query responses may overlap or skip ahead of our data
+      if (message_id < min_message_id) {  // fetch arrived after a push
+        var older = Math.min(min_message_id - message_id, message_count);
+        read_message_bytes(bytes.splice(0, older * message_byte_size), true);
+        min_message_id = message_id;
+        message_id += older;
+        message_count -= older;
+        if (message_count === 0) {return;}
+      }
+      if (message_id > max_message_id + 1) {query_messages(false); return;}
+      var known = max_message_id - message_id + 1;  // already displayed
+      if (known >= message_count) {return;}
+      if (known > 0) {
+        bytes.splice(0, known * message_byte_size);
+        message_id += known;
+        message_count -= known;
+      }
+    }
+
     if (build_upwards) {
       max_message_id = Math.max(max_message_id, message_id + message_count-1);
       min_message_id = message_id;
@@ -208,6 +355,17 @@ main = function() {
     read_message_bytes(bytes, build_upwards);
   };
 
+  function read_jump(bytes, message_id, message_count) {
+    // the date's first message and the ones after it, shown at the top
+    var before = max_message_id >= min_message_id ? jump_at - min_message_id : 0;
+    jump_at = null;
+    if (message_count === 0) {return;}  // nothing since the date
+    if (before === 0) {min_message_id = message_id;}
+    max_message_id = message_id + message_count - 1;
+    read_message_bytes(bytes, false);
+    message_list_div.children[before].scrollIntoView();
+  };
+
   function read_message_bytes(bytes, build_upwards) {
     if (bytes == null || bytes == []) {console.log("Received empty");return;}
     // Checks current scroll height BEFORE the message is added
@@ -270,27 +428,61 @@ main = function() {
     }
     return padded_message.slice(0, i);
   }
-  function bytes_to_message(bytes) {
-    // Break message server side
-    var server_time = unpack_number(bytes.splice(0, 8)); // 8 bytes
-    var cypher_block = bytes.splice(0, cypher_length); // 440 needs splicing
-    var signature = bytes.splice(0, 64);
-    var bytes_hash = sha3_256.array(cypher_block);
-
-    // decrypt message
-    var cnt = new aesjs.Counter(1);
-    var aes_cnt = new aesjs.ModeOfOperation.ctr(get_chat_key(), cnt);
-    var decrypted_bytes = Array.from(aes_cnt.decrypt(cypher_block));
+  function decrypt_legacy(cypher_block) {
+    // v1 if marked so and the chat key matches, otherwise fall back to v0
+    var chat_key = get_chat_key();
+    if (cypher_block[0] == cypher_v1) {
+      var cnt = new aesjs.Counter(cypher_block.slice(0, cypher_iv_length));
+      var aes_cnt = new aesjs.ModeOfOperation.ctr(chat_key, cnt);
+      var decrypted_bytes = Array.from(
+        aes_cnt.decrypt(cypher_block.slice(cypher_iv_length))
+      );
+      if (verify_chat_key(decrypted_bytes.slice(0, 4))) {return decrypted_bytes;}
+    }
+    var cnt = new aesjs.Counter(1);  // v0: same IV for every message
+    var aes_cnt = new aesjs.ModeOfOperation.ctr(chat_key, cnt);
+    return Array.from(aes_cnt.decrypt(cypher_block));
+  };
+  function get_aead_key(usage) {
+    return crypto.subtle.importKey(
+      "raw", new Uint8Array(get_chat_key()), "AES-GCM", false, [usage]
+    );
+  };
+  async function open_cypher(cypher_block) {
+    // returns [chat_verified, client_time, public_key, message_bytes]
+    // or null if the cypher is undecryptable (v2 failed authentication)
+    if (cypher_block[0] == cypher_v2 && has_aead) {
+      try {
+        var plain = await crypto.subtle.decrypt({
+            name: "AES-GCM",
+            iv: new Uint8Array(cypher_block.slice(1, 1 + aead_nonce_length)),
+            additionalData: new Uint8Array(cypher_block.slice(0, 1)),  // version
+          },
+          await get_aead_key("decrypt"),
+          new Uint8Array(cypher_block.slice(1 + aead_nonce_length)),  // data + tag
+        );
+        var decrypted_bytes = Array.from(new Uint8Array(plain));
+        var client_time = unpack_number(decrypted_bytes.splice(0, 8)); // 8 bytes
+        var public_key = decrypted_bytes.splice(0, 32); // 32 bytes
+        var length = unpack_number(decrypted_bytes.splice(0, 2)); // 2 bytes
+        return [true, client_time, public_key, decrypted_bytes.slice(0, length)];
+      } catch(e) {}  // might still be a v0 cypher starting with a 2
+    }
+    var decrypted_bytes = decrypt_legacy(cypher_block);
 
     // Break message client side
     var chat_key_4bytes = decrypted_bytes.splice(0, 4); // 4 bytes
     var client_time = unpack_number(decrypted_bytes.splice(0, 8)); // 8 bytes
     var public_key = decrypted_bytes.splice(0, 32); // 32 bytes
-    var padded_bytes = decrypted_bytes.splice(0, message_content_length);// 396
-    // username string
-    var username_colour = aesjs.utils.hex.fromBytes(public_key.slice(29, 32));
-    var username_str = btoa(String.fromCharCode(...public_key));
-    // date string
+    var padded_bytes = decrypted_bytes;  // 380 (v1) or 396 (v0)
+    var chat_verified = verify_chat_key(chat_key_4bytes);
+    if (cypher_block[0] == cypher_v2 && !chat_verified) {return null;}
+
+    // message string remove padding
+    var message_bytes = unpad_message(padded_bytes, chat_key_4bytes);
+    return [chat_verified, client_time, public_key, message_bytes];
+  };
+  function make_date_str(server_time) {
     var date = new Date(Number(server_time));
     var today = new Date();
     if (date.toDateString() === today.toDateString()) {  // sent today
@@ -300,24 +492,52 @@ main = function() {
     } else {
       var date_str = date.toLocaleString().slice(0, -10);  // date < this year
     }
-    date_str += " " + date.toLocaleTimeString().slice(0, -3);
-    // message string remove padding
-    var message_bytes = unpad_message(padded_bytes, chat_key_4bytes);
-    var message_str = utf8decoder.decode(new Uint8Array(message_bytes));
-    
-    var [msg_div, sig_div] = build_msg(
-      username_str, 
-      username_colour,
-      date_str, 
-      message_str
-    );
-    setTimeout(
-      ()=>verify_message(
-        sig_div, public_key, bytes_hash, signature, 
-        server_time, client_time, chat_key_4bytes
-      ), 0
-    );  // this is to make the signature checking async to the building of msg
-    return msg_div; 
+    return date_str + " " + date.toLocaleTimeString().slice(0, -3);
+  };
+  function bytes_to_message(bytes) {
+    // Break message server side
+    var server_time = unpack_number(bytes.splice(0, 8)); // 8 bytes
+    var cypher_block = bytes.splice(0, cypher_length); // 440 needs splicing
+    var signature = bytes.splice(0, 64);
+    var bytes_hash = sha3_256.array(cypher_block);
+    var date_str = make_date_str(server_time);
+
+    // placeholder keeps the message's position until it's decrypted
+    var placeholder_div = document.createElement("div");
+    placeholder_div.className = "message";
+
+    open_cypher(cypher_block).then((opened) => {
+      if (opened == null) {
+        var [msg_div, sig_div] = build_msg("Undecryptable", "808080", date_str, "");
+        sig_div.appendChild(make_verify_mark(false,
+          "Message could not be decrypted.\n" +
+          "It was encrypted with a different title or has been tampered with."
+        ));
+        placeholder_div.replaceWith(msg_div);
+        return;
+      }
+      var [chat_verified, client_time, public_key, message_bytes] = opened;
+
+      // username string
+      var username_colour = aesjs.utils.hex.fromBytes(public_key.slice(29, 32));
+      var username_str = btoa(String.fromCharCode(...public_key));
+      var message_str = utf8decoder.decode(new Uint8Array(message_bytes));
+
+      var [msg_div, sig_div] = build_msg(
+        username_str, 
+        username_colour,
+        date_str, 
+        message_str
+      );
+      placeholder_div.replaceWith(msg_div);
+      setTimeout(
+        ()=>verify_message(
+          sig_div, public_key, bytes_hash, signature, 
+          server_time, client_time, chat_verified
+        ), 0
+      );  // this is to make the signature checking async to the building of msg
+    });
+    return placeholder_div; 
   };
   function build_msg(username_str, user_colour, date_str, message_str) {
     var msg_div = document.createElement("div");
@@ -343,12 +563,11 @@ main = function() {
   };
   function verify_message(
     time_div, public_key, bytes_hash, signature, 
-    server_time, client_time, chat_key_4bytes
+    server_time, client_time, chat_verified
   ) {
     // Verifies the time, sign., and chat id
     // also adds check mark to each message
     let reason = "Message verified!";
-    let chat_verified = verify_chat_key(chat_key_4bytes);
     let time_verified = verify_time(server_time, client_time);
     let sig_verified = verify_signature(public_key, bytes_hash, signature);
     var verified = chat_verified && time_verified && sig_verified;
@@ -402,26 +621,41 @@ main = function() {
   };
 
   // *********************************MAINLOOP*********************************
-  function mainloop(old_title) {
+  function mainloop() {
     var title = get_title();
     if (title == "") {
+      unsubscribe();
+      chat_keys.title = null;
       landing_page();
-      setTimeout(function() {mainloop(title);}, 1000);
+      setTimeout(mainloop, 1000);
       return;
     }
     if (loop == false) {
-      setTimeout(function() {mainloop(title);}, 1000);
+      unsubscribe();  // paused: stop the server from pushing
+      setTimeout(mainloop, 1000);
       return;
     }
-    // check if chat title has changed (and we have received essages)
-    if (title == old_title && max_message_id >= min_message_id) {
-      query_messages(false);  // false means new messages
-    } else {
-      // update chat list to new title
-      reset_chat();
-      fetch_messages();
+    // check if chat title has changed
+    if (title != chat_keys.title || legacy != chat_keys.legacy) {
+      // update chat list to new title once its keys are derived
+      if (!deriving) {
+        unsubscribe();
+        reset_chat();
+        deriving = true;
+        derive_chat_keys(title, legacy).finally(() => {deriving = false;});
+      }
+    } else if (subscribed_id == null) {
+      // new chat, or resumed after pause: catch up on what was missed
+      subscribe();  // before fetch, so nothing is missed in between
+      if (max_message_id >= min_message_id) {
+        query_messages(false);  // false means new messages
+      } else {
+        fetch_messages();
+      }
+    } else if (Date.now() - last_send > keepalive_ms) {
+      subscribe();  // server ignores repeats; keeps the socket alive
     }
-    setTimeout(function() {mainloop(title);}, 500);
+    setTimeout(mainloop, 500);
   };
 
   function landing_page() {
@@ -438,114 +672,181 @@ main = function() {
       message_list_div.appendChild(msg_div);
     }
   }
+  // *********************************KEYS*************************************
+  function kdf(input, salt) {
+    return argon2id(Object.assign({
+      password: utf8encoder.encode(input),
+      salt: utf8encoder.encode(salt),
+    }, kdf_params)).then((hash) => Array.from(hash));
+  };
+  async function derive_chat_keys(title, legacy) {
+    // chats from before Argon2 have keys derived with plain SHA3
+    var chat_key = legacy ? sha3_256.array(title) : await kdf(title, kdf_title_salt);
+    chat_keys = {
+      title: title,
+      legacy: legacy,
+      chat_key: chat_key,
+      chat_id: sha3_256.array(chat_key),
+    };
+  };
+  function toggle_legacy() {
+    // mainloop derives the keys again and moves to the other chat
+    legacy = !legacy;
+    legacy_button.style.background = legacy ? style.getPropertyValue("--status_wait") : "";
+  };
+  async function get_key_pair() {
+    var secret = get_password();
+    if (user_keys.password !== secret) {
+      var ec = new elliptic.eddsa('ed25519');
+      var hashed_secret = await kdf(secret, kdf_user_salt);
+      user_keys = {password: secret, key_pair: ec.keyFromSecret(hashed_secret)};
+    }
+    return user_keys.key_pair;
+  };
+
   // *********************************QUERY/FETCH******************************
   function fetch_messages() {
     var chat_id = get_chat_id();
     chat_id_hash = chat_id;
-    ws_send([].concat(fch_pad, chat_id, end_pad));
+    // a screenful; messages are taller than 40px
+    var count = Math.min(Math.ceil(message_list_div.clientHeight / 40), 127);
+    ws_send([].concat(fch_pad, chat_id, fetch_args(count), end_pad));
     expect_response("fetch");
   };
+  function fetch_args(count) {
+    // fetch v1 asks for count messages; v0 gets the server's default
+    if (server_hello == null || (server_hello.features & features & 1 << 0) == 0) {return [];}
+    return [1, count];
+  };
+  function subscribe() {
+    subscribed_id = get_chat_id();
+    ws_send([].concat(sub_pad, subscribed_id, end_pad));
+  };
+  function unsubscribe() {
+    if (subscribed_id == null) {return;}
+    if (socket.readyState == WebSocket.OPEN) {
+      ws_send([].concat(uns_pad, subscribed_id, end_pad));
+    }
+    subscribed_id = null;
+  };
   function query_messages(up) {
     var chat_id = get_chat_id();
     if (up) { // query messages upward (old messages)
-      var query = [0x7f].concat(pack_number(min_message_id, 3));
+      var query = [0x7f].concat(pack_number(min_message_id, id_size()));
     } else { // query messages downward (new messages)
-      var query = [0xff].concat(pack_number(max_message_id, 3));
+      var query = [0xff].concat(pack_number(max_message_id, id_size()));
     }
     ws_send([].concat(qry_pad, chat_id, query, end_pad));
+    if (up) {up_queries += 1;}
     expect_response("query");
   };
   function top_scroll_query(e) {
+    if (jumping || jump_at != null) {return;}  // the list is about to be replaced
     if (message_list_div.scrollTop == 0  && max_message_id > min_message_id) {
-      query_messages(get_title(), true);
+      query_messages(true);
     }
   };
+  function jump(e) {
+    // show the chat from the date picked on; cleared, back to the newest
+    if (chat_keys.title == null || subscribed_id == null) {return;}
+    var chat_id = get_chat_id();
+    reset_chat();
+    if (jump_date.value === "") {
+      fetch_messages();
+      return;
+    }
+    if (server_hello == null || (server_hello.features & 1 << 1) == 0) {
+      jump_date.title = "This server can't jump to a date";
+      return;
+    }
+    var time = pack_number(new Date(jump_date.value).getTime(), 8);  // picked in local time
+    jumping = true;
+    ws_send([].concat(tim_pad, chat_id, [jump_context], time, end_pad));
+    ws_send([].concat(tim_pad, chat_id, [0x80 | jump_count], time, end_pad));
+    expect_response("jump");
+  };
 
   // *********************************SENDING**********************************
   function send_message() {
     var chat_id = get_chat_id();
-    var cypher = create_cypher_block();
-    if (cypher == null) {return;}
+    var message = get_message();    // known by peers
+    if (message == "" || chat_keys.title == null) {return;}
+    if (!ready) {send_button.title = "Still connecting; try again in a moment"; return;}
+    if (!has_aead) {send_button.title = no_aead_str; return;}  // no unauthenticated v1
+    if (utf8encoder.encode(message).length > message_content_length) {return;}
     // counter_div.textContent = "0/" + message_content_length;
-
-    var signature = sign(cypher);
-    outbound_bytes = [].concat(snd_pad, chat_id, cypher, signature, end_pad);
-
-    ws_send(outbound_bytes);
     message_entry.value = "";
-  };
-  function pad_message(message, chat_key) {
-    var message = utf8encoder.encode(message);  // make message into array
-
-    var padding_marker = chat_key[0];
-    var padding_length  = message_content_length - message.length;
-    var padding = new Uint8Array(padding_length);
-    
-    var possible_bytes = new Uint8Array(256 - 1);  // each byte 0-254 (no 255)
-    for (var i = 0; i < possible_bytes.length; i++) {possible_bytes[i] = i;}  // [0, 1, 2, ... 254]
-    possible_bytes[padding_marker] = 255; // exclude padding_marker
 
-    padding[0] = padding_marker;  // First byte should be the padding marker
-    for (var i = 1; i < padding_length; i++) {
-      var rand_byte = Math.floor(Math.random() * 256)  // evenly distributed on ints [0, 255]  (inclusive)
-      padding[i] = possible_bytes[rand_byte];  // on [0, 255] \ {marker}
+    get_key_pair().then(async (key_pair) => {
+      var cypher = await create_cypher_block(message, key_pair.pubBytes());
+      var signature = key_pair.sign(sha3_256.array(cypher)).toBytes();
+      var packet = [].concat(chat_id, cypher, signature);
+      if (server_hello != null && server_hello.pow_bits != null) {
+        packet = packet.concat(await make_stamp(packet, server_hello.pow_bits));
+      }
+      outbound_bytes = [].concat(snd_pad, packet, end_pad);
+      ws_send(outbound_bytes);
+      if (acks_on()) {unacked += 1; send_button.title = "Sending...";}
+    });
+  };
+  function id_size() {  // message ids on the wire: 8 bytes if both know wide ids
+    return server_hello != null && (server_hello.features & features & 1 << 3) != 0 ? 8 : 3;
+  };
+  function acks_on() {return server_hello != null && (server_hello.features & features & 1 << 2) != 0;}
+  function read_ack(status, message_id) {
+    unacked = Math.max(unacked - 1, 0);
+    if (status != 0) {
+      send_button.title = status == 3
+        ? "Sending too fast, wait a bit and try again"
+        : "Sending failed (error " + status + "), try again";
+      send_button.style.background = style.getPropertyValue("--status_err");
+    } else {
+      send_button.title = unacked > 0 ? "Sending..." : "Sent (#" + message_id + ")";
+      send_button.style.background = style.getPropertyValue("--borders1");
     }
-    
-    // GRISHA'S BETTER SOLUTION
-    // for (var i = 0; i < padding_length; i++) {
-    //   var rand_byte = Math.floor(Math.random() * 255)  // evenly distributed on ints [0, 254]
-    //   padding[i] = (rand_byte + (255-padding_marker)) % 256;  // on [0, 255] \ {marker}
-    // }
-
-    // concatinate the arrays
-    var padded_message = new Uint8Array(message_content_length);
-    padded_message.set(message);
-    padded_message.set(padding, message.length);
-    return padded_message;
-  };
-  function sign(cypher) {
-    //var EdDSA = require('elliptic').eddsa;
-    var ec = new elliptic.eddsa('ed25519');
-    var secret = get_password();
-    var hashed_secret = sha3_256.array(utf8encoder.encode(secret))
-    var key_pair = ec.keyFromSecret(hashed_secret);
-    var cypher_hash = sha3_256.array(cypher);
-    var signature = key_pair.sign(cypher_hash).toBytes();
-    return signature;
   };
-  function get_public_key() {
-    var ec = new elliptic.eddsa('ed25519');
-    var secret = get_password();
-    var hashed_secret = sha3_256.array(utf8encoder.encode(secret))
-    var key_pair = ec.keyFromSecret(hashed_secret);
-    return key_pair.pubBytes();
+  async function make_stamp(packet, bits) {
+    // finds 8 bytes that give sha3(packet + stamp) `bits` leading zero bits
+    for (var nonce = 0; ; nonce++) {
+      var stamp = pack_number(nonce, 8);
+      var hash = sha3_256.array(packet.concat(stamp));
+      var zeros = 0;
+      for (var i = 0; i < hash.length && hash[i] == 0; i++) {zeros += 8;}
+      if (i < hash.length) {zeros += Math.clz32(hash[i]) - 24;}
+      if (zeros >= bits) {return stamp;}
+      if (nonce % 4096 == 4095) {await new Promise((r) => setTimeout(r));}  // keep the page alive
+    }
   };
   function get_time_array() {
     const time = Date.now();
     return pack_number(time, 8);
   };
-  function get_chat_key() {
-    var title = get_title();
-    return sha3_256.array(title);
-  }
-  function get_chat_id() {
-    return sha3_256.array(get_chat_key());
-  };
-  function create_cypher_block() {
-    var message = get_message();    // known by peers
-    if (message == "") {return null;}
-    var chat_key = get_chat_key();  // known by peers
+  function get_chat_key() {return chat_keys.chat_key;}
+  function get_chat_id() {return chat_keys.chat_id;}
+  async function create_cypher_block(message, public_key) {
+    var message_bytes = Array.from(utf8encoder.encode(message));
+    var padding = crypto.getRandomValues(
+      new Uint8Array(message_content_length - message_bytes.length)
+    );
     var message_data = [].concat(
-      chat_key.slice(0,4),        // 4 bytes
-      get_time_array(),           // 8 bytes
-      get_public_key(),           // 32 bytes
-      ...pad_message(message, chat_key)     // 396 bytes
+      get_time_array(),                       // 8 bytes
+      public_key,                             // 32 bytes
+      pack_number(message_bytes.length, 2),   // 2 bytes
+      message_bytes,
+      Array.from(padding),                    // 369 bytes with message
+    );
+    var nonce = Array.from(crypto.getRandomValues(new Uint8Array(aead_nonce_length)));
+    return get_aead_key("encrypt").then((key) => crypto.subtle.encrypt({
+        name: "AES-GCM",
+        iv: new Uint8Array(nonce),
+        additionalData: new Uint8Array([cypher_v2]),
+      },
+      key,
+      new Uint8Array(message_data),
+    )).then((sealed) =>  // 1 + 12 + 411 + 16 bytes
+      [cypher_v2].concat(nonce, Array.from(new Uint8Array(sealed)))
     );
-    var cnt = new aesjs.Counter(1);
-    var aes_cnt = new aesjs.ModeOfOperation.ctr(chat_key, cnt);
-    return Array.from(aes_cnt.encrypt(message_data));
   };
-
   // *******************************CHAR_COUNTER*******************************
   //var counter_div = document.getElementById("content_counter");
   function keystroke_input(event) {
@@ -554,7 +855,7 @@ main = function() {
     // update colour and value of message length counter
     var textLength = message_entry.value.length;
     //counter_div.textContent = textLength + "/" + (message_content_length-1);
-    if(textLength >= message_content_length - 10){
+    if(textLength >= message_content_length - 10 || !has_aead){
       //sending_div.style.borderColor = style.getPropertyValue("--status_err");
       send_button.style.background = style.getPropertyValue("--status_err");
       //counter_div.style.color = "#ff2851";
@@ -565,5 +866,5 @@ main = function() {
     }
   };
 
-  mainloop("");
+  mainloop();
 };
@@ -0,0 +1,106 @@
+// Opens lots of idle SMRT connections subscribed to one chat,
+// then sends a single message and times how long the push takes to reach all.
+// Writes one junk message into a random chat on the server.
+// arguments: addr:port [connections] [server pid]
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use publichat::buffers::{
+    hash::Buf as HashBuf,
+    msg_head,
+    msg_in_c as msg_in,
+    msg_out_c as msg_out,
+    sub,
+};
+
+const DEFAULT_CONNECTIONS: usize = 10_000;
+const PUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn connect(addr: &std::net::SocketAddr, chat_id: &HashBuf) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut buf = sub::PREPAD;
+    let (cid_buf,) = sub::pad_split_mut(&mut buf);
+    cid_buf.copy_from_slice(chat_id);
+    stream.write_all(b"SMRT")?;
+    stream.write_all(&buf)?;
+    Ok(stream)
+}
+
+fn server_threads(pid: &str) -> Option<String> {
+    // linux only; good enough for a benchmark
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    status.lines()
+        .find(|l| l.starts_with("Threads:"))
+        .map(|l| l["Threads:".len()..].trim().to_string())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let addr = args.first().ok_or("No addr given")?
+        .to_socket_addrs()?
+        .next().ok_or("Zero addrs received?")?;
+    let count = match args.get(1) {
+        Some(c) => c.parse()?,
+        None => DEFAULT_CONNECTIONS,
+    };
+    let pid = args.get(2);
+
+    let mut rng = rand::thread_rng();
+    let mut chat_id = HashBuf::default();
+    rng.fill(&mut chat_id);
+
+    // open idle subscribers
+    println!("Opening {count} connections to {addr}...");
+    let start = Instant::now();
+    let mut conns = Vec::with_capacity(count);
+    for i in 0..count {
+        match connect(&addr, &chat_id) {
+            Ok(stream) => conns.push(stream),
+            Err(e) => {
+                println!("Connection {i} failed: {e}");
+                println!("(out of file descriptors? try `ulimit -n` on both ends)");
+                break;
+            },
+        }
+    }
+    println!("Opened {} connections in {:?}", conns.len(), start.elapsed());
+
+    // give the server a moment to process all subscriptions
+    std::thread::sleep(Duration::from_secs(1));
+    if let Some(threads) = pid.and_then(|pid| server_threads(pid)) {
+        println!("Server is running {threads} threads");
+    }
+
+    // send one (junk) message; the server doesn't look inside cyphers
+    let mut buf = msg_in::PREPAD;
+    let (cid_buf, cy_buf, sig_buf) = msg_in::pad_split_mut(&mut buf);
+    cid_buf.copy_from_slice(&chat_id);
+    rng.fill(cy_buf);
+    rng.fill(sig_buf);
+    let mut sender = TcpStream::connect(addr)?;
+    sender.write_all(b"SMRT")?;
+    let start = Instant::now();
+    sender.write_all(&buf)?;
+
+    // collect the push from everyone
+    let mut packet = [0; msg_head::SIZE + msg_out::SIZE];
+    let mut received = 0;
+    for stream in &mut conns {
+        let left = PUSH_TIMEOUT.saturating_sub(start.elapsed()).max(Duration::from_millis(1));
+        stream.set_read_timeout(Some(left))?;
+        if stream.read_exact(&mut packet).is_ok() && packet[..3] == msg_head::PAD {
+            received += 1;
+        }
+    }
+    println!(
+        "Push reached {received}/{} subscribers in {:?}",
+        conns.len(),
+        start.elapsed(),
+    );
+
+    Ok(())
+}
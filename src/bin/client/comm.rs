@@ -1,18 +1,26 @@
-use std::net::TcpStream;
+use std::io::Write;
 
 use publichat::helpers::*;
+use publichat::error::Error;
+use publichat::constants::FCH_V1;
 use publichat::buffers::{
     cypher::Buf as CypherBuf,
     hash::Buf as HashBuf,
     msg_in_c as msg_in,
+    msg_in_c_v2 as msg_in_v2,
+    hello,
     fetch,
+    fetch_v1,
     query,
+    query_wide,
+    time_query,
+    sub,
 };
 
-use crate::crypt::ed25519::SigBuf;
+use crate::crypt::{ed25519::SigBuf, pow};
 
 pub fn send_msg(
-    stream: &mut TcpStream,
+    stream: &mut impl Write,
     chat: &HashBuf,
     cypher: &CypherBuf,
     signature: &SigBuf,
@@ -29,7 +37,36 @@ pub fn send_msg(
     full_write(stream, &buf, "Failed to send message")
 }
 
-pub fn send_fetch(stream: &mut TcpStream, chat: &HashBuf) -> Res {
+pub fn send_msg_v2(
+    stream: &mut impl Write,
+    chat: &HashBuf,
+    cypher: &CypherBuf,
+    signature: &SigBuf,
+    pow_bits: u8,
+) -> Res {
+    // same as send_msg, with a proof of work stamp (protocol version 2)
+    let mut buf = msg_in_v2::PREPAD;
+    let (cid_buf, cy_buf, sig_buf, stamp_buf) = msg_in_v2::pad_split_mut(&mut buf);
+
+    cid_buf.copy_from_slice(chat);
+    cy_buf.copy_from_slice(cypher);
+    sig_buf.copy_from_slice(signature);
+    stamp_buf.copy_from_slice(&pow::stamp(&[chat.as_slice(), cypher, signature].concat(), pow_bits));
+
+    full_write(stream, &buf, "Failed to send message")
+}
+
+pub fn send_hello(stream: &mut impl Write, version: u8, features: u32) -> Res {
+    let mut buf = hello::PREPAD;
+    let (version_buf, features_buf) = hello::pad_split_mut(&mut buf);
+
+    version_buf[0] = version;
+    features_buf.copy_from_slice(&features.to_be_bytes());
+
+    full_write(stream, &buf, "Failed to send hello")
+}
+
+pub fn send_fetch(stream: &mut impl Write, chat: &HashBuf) -> Res {
     let mut buf = fetch::PREPAD;
     let (cid_buf,) = fetch::pad_split_mut(&mut buf);
 
@@ -38,20 +75,73 @@ pub fn send_fetch(stream: &mut TcpStream, chat: &HashBuf) -> Res {
     full_write(stream, &buf, "Failed to send fetch")
 }
 
+pub fn send_fetch_count(stream: &mut impl Write, chat: &HashBuf, count: Option<u8>) -> Res {
+    // fetch v1 if the server takes a count, otherwise its default amount
+    let Some(count) = count else { return send_fetch(stream, chat) };
+    if count > 0x7f { return Err(Error::limit("Fetch count too large")) }
+    let mut buf = fetch_v1::PREPAD;
+    let (cid_buf, version_buf, count_buf) = fetch_v1::pad_split_mut(&mut buf);
+
+    cid_buf.copy_from_slice(chat);
+    version_buf[0] = FCH_V1;
+    count_buf[0] = count;
+
+    full_write(stream, &buf, "Failed to send fetch")
+}
+
+pub fn send_subscribe(stream: &mut impl Write, chat: &HashBuf) -> Res {
+    let mut buf = sub::PREPAD;
+    let (cid_buf,) = sub::pad_split_mut(&mut buf);
+
+    cid_buf.copy_from_slice(chat);
+
+    full_write(stream, &buf, "Failed to send subscribe")
+}
+
 pub fn send_query(
-    stream: &mut TcpStream,
+    stream: &mut impl Write,
     chat: &HashBuf,
     forwards: bool,
     count: u8,
-    id: u32,
+    id: u64,
+    wide: bool,  // server takes 8-byte ids
 ) -> Res {
-    if count > 0x7f || id > 0xffffff { return Err("Query input too large") }
+    if count > 0x7f { return Err(Error::limit("Query input too large")) }
+    if wide {
+        let mut buf = query_wide::PREPAD;
+        let (cid_buf, args_buf, mid_buf) = query_wide::pad_split_mut(&mut buf);
+
+        cid_buf.copy_from_slice(chat);
+        args_buf[0] = if forwards {count | 0x80} else {count};
+        mid_buf.copy_from_slice(&id.to_be_bytes());
+
+        return full_write(stream, &buf, "Failed to send query")
+    }
+    if id > 0xffffff { return Err(Error::limit("Query input too large")) }
     let mut buf = query::PREPAD;
     let (cid_buf, args_buf, mid_buf) = query::pad_split_mut(&mut buf);
 
     cid_buf.copy_from_slice(chat);
     args_buf[0] = if forwards {count | 0x80} else {count};
-    mid_buf.copy_from_slice(&id.to_be_bytes()[1..]);
+    mid_buf.copy_from_slice(&id.to_be_bytes()[5..]);
 
     full_write(stream, &buf, "Failed to send query")
 }
+
+pub fn send_time_query(
+    stream: &mut impl Write,
+    chat: &HashBuf,
+    forwards: bool,
+    count: u8,
+    time: u64,  // server time, ms
+) -> Res {
+    if count > 0x7f { return Err(Error::limit("Query input too large")) }
+    let mut buf = time_query::PREPAD;
+    let (cid_buf, args_buf, time_buf) = time_query::pad_split_mut(&mut buf);
+
+    cid_buf.copy_from_slice(chat);
+    args_buf[0] = if forwards {count | 0x80} else {count};
+    time_buf.copy_from_slice(&time.to_be_bytes());
+
+    full_write(stream, &buf, "Failed to send time query")
+}
@@ -1,20 +1,44 @@
 use std::{time::Duration, collections::VecDeque};
 
 use publichat::buffers::hash::Buf as HashBuf;
+use publichat::constants::{FEATURE_FETCH_COUNT, FEATURE_SEND_ACK, FEATURE_TIME_QUERY, FEATURE_WIDE_IDS};
 use crate::msg::Message;
 
-pub const FQ_DELAY: Duration = Duration::from_millis(200);
-
 const DISP_FPS: u64 = 100;
 pub const _DISP_DELAY: Duration = Duration::from_millis(1000 / DISP_FPS);
 
+pub enum Scroll {  // asked of the display once a jump lands
+    Bottom,
+    To(usize),  // queue index of the message to show on top
+}
+
 pub struct GlobalState {
     pub queue: VecDeque<Message>,
     pub chat_key: HashBuf,
     pub chat_id: HashBuf,
-    pub min_id: u32,
-    pub max_id: u32,  // inclusive
+    pub min_id: u64,
+    pub max_id: u64,  // inclusive
+    pub wide: bool,  // ids take 8 bytes on the wire, not 3
+    pub status: Option<String>,  // last error from the server; shown in the header
+    pub sending: usize,  // messages the server hasn't acked yet
+    pub fetch_count: Option<u8>,  // asked for in fetches; None if the server picks
+    pub jumping: bool,  // asked for a date; its first answer hasn't come
+    pub jump_at: Option<u64>,  // id of the date's first message, until its answer comes
+    pub scroll: Option<Scroll>,
+}
+
+impl GlobalState {
+    pub fn reset(&mut self) {
+        // forgets all messages; the next packet starts the queue again
+        self.queue.clear();
+        (self.min_id, self.max_id) = (1, 0);
+    }
 }
 
+pub const FEATURES: u32 =  // the optional ones we know
+    FEATURE_FETCH_COUNT | FEATURE_SEND_ACK | FEATURE_TIME_QUERY | FEATURE_WIDE_IDS;
+pub const JUMP_CONTEXT: u8 = 20;  // messages loaded before a jump's target
+pub const JUMP_COUNT: u8 = 50;  // and from it on
+pub const KEEPALIVE_DELAY: Duration = Duration::from_secs(30);  // server drops idle sockets
 pub const VERIFY_TOLERANCE_MS: u64 = 10 * 1000;  // time between server and client
 pub const USER_ID_CHAR_COUNT: usize = 15;  // how many b64 chars are displayed
@@ -13,21 +13,113 @@ pub mod sha {
     }
 }
 
+pub mod pow {
+    use sha3::{Sha3_256, Digest};
+    use publichat::buffers::stamp;
+
+    pub fn stamp(packet: &[u8], bits: u8) -> stamp::Buf {
+        // tries stamps until SHA3(packet, stamp) starts with `bits` zero bits
+        let prefix = Sha3_256::new().chain_update(packet);  // hashed once, cloned per try
+        (0..u64::MAX).map(u64::to_be_bytes).find(|stamp| {
+            let hash = prefix.clone().chain_update(stamp).finalize();
+            let zeros = hash.iter().take_while(|b| **b == 0).count();
+            8 * zeros as u32 + hash.get(zeros).map_or(0, |b| b.leading_zeros()) >= bits.into()
+        }).expect("ran out of stamps")
+    }
+}
+
+pub mod kdf {
+    use argon2::{Argon2, Algorithm, Version, Params};
+    use publichat::buffers::hash;
+    use publichat::error::{Error, Kind};
+    use publichat::helpers::Res;
+    use publichat::constants::{
+        HASH_SIZE,
+        KDF_MEM_KIB,
+        KDF_ITERATIONS,
+        KDF_LANES,
+        KDF_TITLE_SALT,
+        KDF_USER_SALT,
+    };
+
+    fn derive(input: &[u8], salt: &[u8]) -> Res<hash::Buf> {
+        let params = Params::new(KDF_MEM_KIB, KDF_ITERATIONS, KDF_LANES, Some(HASH_SIZE))
+            .map_err(|e| Error::with_source(Kind::Crypto, "Invalid KDF parameters", e.to_string()))?;
+
+        let mut res = hash::DEFAULT;
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(input, salt, &mut res)
+            .map_err(|e| Error::with_source(Kind::Crypto, "Failed to derive key", e.to_string()))?;
+        Ok(res)
+    }
+
+    pub fn chat_key(title: &[u8]) -> Res<hash::Buf> {
+        derive(title, KDF_TITLE_SALT)
+    }
+
+    pub fn user_secret(user: &[u8]) -> Res<hash::Buf> {
+        derive(user, KDF_USER_SALT)
+    }
+}
+
 pub mod aes {
     use aes::{Aes256, cipher::{KeyIvInit, StreamCipher}};
     use ctr::Ctr128BE;
-    use publichat::buffers::{hash::Buf as HashBuf, cypher::Buf as CypherBuf};
+    use publichat::buffers::hash::Buf as HashBuf;
+    use publichat::constants::CYPHER_IV_SIZE;
 
-    pub fn apply(key: &HashBuf, buf: &mut CypherBuf) {
-        // applies AES in-place on buf as side-effect
-        const IV: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    pub type IvBuf = [u8; CYPHER_IV_SIZE];
+
+    // only used by v0 cyphers, which reuse it for every message
+    pub const LEGACY_IV: IvBuf = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
 
+    pub fn apply(key: &HashBuf, iv: &IvBuf, buf: &mut [u8]) {
+        // applies AES in-place on buf as side-effect
         // TODO: key is always the same; generate decrypter once in main?
-        let mut cypher = Ctr128BE::<Aes256>::new(key.into(), &IV.into());
+        let mut cypher = Ctr128BE::<Aes256>::new(key.into(), iv.into());
         cypher.apply_keystream(buf);
     }
 }
 
+pub mod aead {
+    use aes_gcm::{Aes256Gcm, KeyInit, AeadInPlace};
+    use publichat::buffers::{hash::Buf as HashBuf, cypher::Buf as CypherBuf};
+    use publichat::error::Error;
+    use publichat::helpers::Res;
+    use publichat::constants::{
+        CYPHER_VERSION_SIZE,
+        CYPHER_V2_TIME,
+        CYPHER_V2_TAG,
+    };
+
+    fn split(buf: &mut CypherBuf) -> (&[u8], &[u8], &mut [u8], &mut [u8]) {
+        // (version, nonce, data, tag) of a v2 cypher
+        let (head, rest) = buf.split_at_mut(CYPHER_V2_TIME);
+        let (data, tag) = rest.split_at_mut(CYPHER_V2_TAG - CYPHER_V2_TIME);
+        let (version, nonce) = head.split_at(CYPHER_VERSION_SIZE);
+        (version, nonce, data, tag)
+    }
+
+    pub fn seal(key: &HashBuf, buf: &mut CypherBuf) -> Res {
+        // encrypts data in-place and fills in the tag; version and nonce
+        // must already be set. Version is authenticated but not encrypted.
+        let (version, nonce, data, tag) = split(buf);
+        let res = Aes256Gcm::new(key.into())
+            .encrypt_in_place_detached(nonce.into(), version, data)
+            .map_err(|_| Error::crypto("Failed to encrypt cypher"))?;
+        tag.copy_from_slice(&res);
+        Ok(())
+    }
+
+    pub fn open(key: &HashBuf, buf: &mut CypherBuf) -> Res {
+        // decrypts data in-place if the tag matches (right key, untouched)
+        let (version, nonce, data, tag) = split(buf);
+        Aes256Gcm::new(key.into())
+            .decrypt_in_place_detached(nonce.into(), version, data, (&*tag).into())
+            .map_err(|_| Error::crypto("Undecryptable cypher"))  // opaque error
+    }
+}
+
 pub mod ed25519 {
     pub use ed25519_dalek::Keypair;  // allow use outside
     use ed25519_dalek::{
@@ -39,15 +131,17 @@ pub mod ed25519 {
         Verifier,
     };
     use publichat::buffers::{hash::Buf as HashBuf, cypher::Buf as CypherBuf};
+    use publichat::error::{Error, Kind};
+    use publichat::helpers::Res;
 
     pub type SigBuf = [u8; SIGNATURE_LENGTH];
 
-    pub fn make_keypair(input: &[u8]) -> Result<Keypair, &'static str> {
-        // hash input data to get a neat 32 bytes
-        let hash = super::sha::hash(input);
+    pub fn make_keypair(user: &[u8]) -> Res<Keypair> {
+        // derive a neat 32 bytes from the username
+        let hash = super::kdf::user_secret(user)?;
 
         let secret = SecretKey::from_bytes(&hash)
-            .map_err(|_| "Failed to make private key")?;
+            .map_err(|e| Error::with_source(Kind::Crypto, "Failed to make private key", e))?;
         let public = PublicKey::from(&secret);
 
         Ok(Keypair{secret, public})
@@ -62,12 +156,12 @@ pub mod ed25519 {
         cypher_hash: &HashBuf,
         pub_key: &HashBuf,
         signature: &SigBuf,
-    ) -> Result<bool, &'static str> {
+    ) -> Res<bool> {
         let pub_key = PublicKey::from_bytes(pub_key)
-            .map_err(|_| "Failed to make pub key")?;
+            .map_err(|e| Error::with_source(Kind::Crypto, "Failed to make pub key", e))?;
 
         let signature = Signature::from_bytes(signature)
-            .map_err(|_| "Failed to make signature")?;
+            .map_err(|e| Error::with_source(Kind::Crypto, "Failed to make signature", e))?;
 
         Ok(pub_key.verify(cypher_hash, &signature).is_ok())
     }
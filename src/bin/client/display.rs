@@ -43,6 +43,8 @@ pub struct Display<'a> {
     chat_name: &'a str,
     user_name: &'a str,
     known_count: usize,
+    known_status: Option<String>,
+    known_sending: bool,
     hidden: bool,
 }
 
@@ -76,6 +78,8 @@ impl<'a> Display<'a> {
             chat_name,
             user_name,
             known_count: 0,
+            known_status: None,
+            known_sending: false,
             hidden: true,
         };
 
@@ -121,10 +125,30 @@ impl<'a> Display<'a> {
                 Err(e) => break Err(e),  // Failed to read, clean up and exit
             }
 
-            let queue_len = self.state.lock().map_err(|_| {
-                use std::io::{Error, ErrorKind::Other};
-                Error::new(Other, "Failed to lock state")
-            })?.queue.len();
+            let (queue_len, status, sending, scroll) = {
+                let mut state = self.state.lock().map_err(|_| {
+                    use std::io::{Error, ErrorKind::Other};
+                    Error::new(Other, "Failed to lock state")
+                })?;
+                (state.queue.len(), state.status.clone(), state.sending > 0, state.scroll.take())
+            };
+
+            // a jump landed; show where
+            if let Some(scroll) = scroll {
+                self.view = match scroll {
+                    Scroll::Bottom => ViewPos::Last,
+                    Scroll::To(i) => ViewPos::Index{msg_id: i.try_into().unwrap_or(u16::MAX), chr_id: 0},
+                };
+                self.draw_messages()?;
+                self.draw_footer()?;
+            }
+
+            // re-draw if the server complained or caught up
+            if self.known_status != status || self.known_sending != sending {
+                self.known_status = status;
+                self.known_sending = sending;
+                self.draw_header()?;
+            }
 
             // re-draw if there are new messages
             if self.known_count != queue_len {
@@ -144,11 +168,15 @@ impl<'a> Display<'a> {
         // TODO: cache with each size change?
         let header_text = format!(
             "{:^w$}",
-            format!(
-                "chat: {}, user: {}",
-                if self.hidden {"******"} else {self.chat_name},
-                if self.hidden {"******"} else {self.user_name},
-            )
+            match &self.known_status {
+                Some(status) => status.clone(),
+                None => format!(
+                    "chat: {}, user: {}{}",
+                    if self.hidden {"******"} else {self.chat_name},
+                    if self.hidden {"******"} else {self.user_name},
+                    if self.known_sending {" (sending...)"} else {""},
+                ),
+            }
         );
 
         let header = style(header_text)
@@ -248,7 +276,7 @@ impl<'a> Display<'a> {
                 // cursor already at right position, draw one msg at a time
                 // TODO: print start.msg partial
                 if msg_id >= state.queue.len() as u16 { return Ok(()) }  // too far down
-                for msg in state.queue.range(1 + usize::from(msg_id)..) {
+                for msg in state.queue.range(usize::from(msg_id)..) {
                     let msg_height = req_lines(msg.len);
                     if msg_height <= remaining_lines {
                         // normal situation, whole message fits on screen
@@ -308,7 +336,7 @@ impl<'a> Display<'a> {
         self.view = match self.view {
             ViewPos::Last => ViewPos::Last,
             ViewPos::Index{mut msg_id, chr_id} => if up {
-                msg_id = msg_id.checked_sub(1).unwrap_or(0);
+                msg_id = msg_id.saturating_sub(1);
                 ViewPos::Index{ msg_id, chr_id }
             } else {
                 // TODO: possible ViewPos::Last
@@ -403,10 +431,7 @@ impl<'a> Display<'a> {
 
     fn handle_resize(&mut self, x: u16, y: u16) -> crossterm::Result<()> {
         if y < MIN_HEIGHT || x < MIN_WIDTH {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Terminal size not supported! Too small :(",
-            ))
+            return Err(io::Error::other("Terminal size not supported! Too small :("))
         }
         self.size = (x, y);
         self.refresh()
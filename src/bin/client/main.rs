@@ -1,15 +1,26 @@
 use std::collections::VecDeque;
-use std::error::Error;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
 use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
 use std::mem;
 
+use crossterm::terminal;
+
 use publichat::helpers::*;
+use publichat::error::{Error, Kind};
+use publichat::stream::Stream;
+use publichat::constants::*;
 use publichat::buffers::{
+    pad,
+    hello_out,
+    err_head,
+    ack,
+    ack_wide,
     msg_head,
+    msg_head_wide,
     msg_out_c as msg_out,
     cypher::Buf as CypherBuf,
 };
@@ -24,28 +35,108 @@ mod display;
 use display::Display;
 
 mod crypt;
-use crypt::{sha, ed25519};
+use crypt::{sha, kdf, ed25519};
 
 mod comm;
 
-// mutex lock shortuct
-macro_rules! lock { ($s:tt) => { $s.lock().map_err(|_| "Failed to lock state") } }
+mod tls;
+use tls::Trust;
 
-fn parse_header(header: &msg_head::Buf) -> Result<(u8, u32, u8, bool), &'static str> {
-    // returns (chat id byte, message id, message count, forward)
-    let (pad_buf, cid_buf, mid_buf, count_buf) = msg_head::split(header);
-    let mut msg_id = [0; 4];  // TODO: this is ugly. Consider combining cid and mid
-    msg_id[1..].copy_from_slice(mid_buf);  // can't fail
+// mutex lock shortuct
+macro_rules! lock { ($s:tt) => { $s.lock().map_err(|_| Error::internal("Failed to lock state")) } }
+
+fn parse_header(header: &[u8]) -> Res<(u8, u64, u8, bool)> {
+    // returns (chat id byte, message id, message count, forward).
+    // Takes msg_head or msg_head_wide; only the id's width differs
+    let (pad_buf, rest) = header.split_at(pad::SIZE);
+    let (cid_buf, rest) = rest.split_at(1);
+    let (mid_buf, count_buf) = rest.split_at(rest.len() - 1);
     if pad_buf == msg_head::PAD {
         Ok((
             cid_buf[0],  // can't fail
-            u32::from_be_bytes(msg_id),
+            mid_buf.iter().fold(0, |id, &b| id << 8 | u64::from(b)),
             count_buf[0] & 0b0111_1111,  // can't fail
             count_buf[0] & 0b1000_0000 > 0,
         ))
     } else {
         println!("{header:?}");
-        Err("Received invalid header padding")
+        Err(Error::protocol("Received invalid header padding"))
+    }
+}
+
+
+fn read_error(stream: &mut impl Read) -> Res<(u8, String)> {
+    // reads the rest of an error packet, after its pad; returns code and text
+    let mut buf = err_head::DEFAULT;
+    read_exact(stream, &mut buf[pad::SIZE..], "Failed to read error")?;
+    let (_, code_buf, len_buf) = err_head::split(&buf);
+    let mut text = vec![0; len_buf[0].into()];
+    read_exact(stream, &mut text, "Failed to read error text")?;
+    Ok((code_buf[0], format!("{}: {}", error_reason(code_buf[0]), String::from_utf8_lossy(&text))))
+}
+
+
+fn error_reason(code: u8) -> &'static str {
+    match code {
+        ERR_UNSUPPORTED_VERSION => "unsupported version",
+        ERR_BAD_FRAMING => "bad packet",
+        ERR_RATE_LIMITED => "rate limited",
+        ERR_TOO_LARGE => "too large",
+        ERR_SERVER_BUSY => "server busy",
+        ERR_SERVER => "server error",
+        ERR_NEEDS_WORK => "not enough proof of work",
+        _ => "unknown error",
+    }
+}
+
+
+fn connect(addr: SocketAddr, tls_name: &str, trust: Option<Trust>) -> Res<Stream> {
+    // an SMRT connection, before any packets
+    eprintln!("Connecting to server {addr:?}...");
+    let tcp = TcpStream::connect(addr).map_err(|e| Error::io("Failed to connect", e))?;
+    let mut stream = match trust {
+        Some(trust) => {
+            eprintln!("Starting TLS as {tls_name:?}...");
+            tls::connect(tcp, tls_name, trust)?
+        },
+        None => Stream::plain(tcp),
+    };
+    eprintln!("Connected!");
+    full_write(&mut stream, b"SMRT", "Failed to start SMRT")?;
+    Ok(stream)
+}
+
+
+fn handshake(stream: &mut (impl Read + Write)) -> Res<Option<(u8, u32, u8)>> {
+    // returns the agreed version, the server's features and its pow difficulty,
+    // or None if the server doesn't take hellos (it hangs up on them).
+    // Only used before the listener thread starts!
+    comm::send_hello(stream, PROTOCOL_VERSION, FEATURES)?;
+    let mut pad_buf = pad::DEFAULT;
+    match read_exact(stream, &mut pad_buf, "Failed to read handshake") {
+        Err(e) if e.kind() == Kind::Disconnected => return Ok(None),  // from before err packets
+        res => res?,
+    }
+    match pad_buf {
+        hello_out::PAD => {
+            let mut buf = hello_out::DEFAULT;
+            read_exact(stream, &mut buf[pad::SIZE..], "Failed to read hello")?;
+            let (_, version_buf, features_buf) = hello_out::split(&buf);
+            let mut pow_bits = [0];
+            if version_buf[0] >= POW_VERSION {
+                read_exact(stream, &mut pow_bits, "Failed to read pow difficulty")?;
+            }
+            Ok(Some((version_buf[0], u32::from_be_bytes(features_buf.try_into().unwrap()), pow_bits[0])))
+        },
+        err_head::PAD => {
+            let (code, text) = read_error(stream)?;
+            eprintln!("Server says: {text}");
+            match code {
+                ERR_BAD_FRAMING | ERR_UNSUPPORTED_VERSION => Ok(None),  // doesn't know hellos, or ours
+                _ => Err(Error::protocol("Server refused handshake")),
+            }
+        },
+        _ => Err(Error::protocol("Received invalid handshake")),
     }
 }
 
@@ -53,15 +144,41 @@ fn parse_header(header: &msg_head::Buf) -> Result<(u8, u32, u8, bool), &'static
 // Listener thread handles parsing data received from server
 // - Receive message packets; parse; break up into messages
 // - Insert into queue in correct place
-fn listener(mut stream: TcpStream, state: Arc<Mutex<GlobalState>>) -> Res {
-    let mut hed_buf = msg_head::DEFAULT;
+fn listener(mut stream: Stream, state: Arc<Mutex<GlobalState>>) -> Res {
+    let wide = lock!(state)?.wide;
+    let (hed_size, ack_size) = match wide {
+        true => (msg_head_wide::SIZE, ack_wide::SIZE),
+        false => (msg_head::SIZE, ack::SIZE),
+    };
+    let mut hed_buf = msg_head_wide::DEFAULT;  // the larger; only hed_size is used
     loop {
-        read_exact(&mut stream, &mut hed_buf, "Failed to read head buffer")?;
+        read_exact(&mut stream, &mut hed_buf[..pad::SIZE], "Failed to read packet pad")?;
+        if hed_buf[..pad::SIZE] == err_head::PAD {
+            let (code, error) = read_error(&mut stream)?;
+            if code == ERR_RATE_LIMITED {  // only that request was dropped
+                lock!(state)?.status = Some(error);
+                continue;
+            }
+            lock!(state)?.status = Some(format!("disconnected: {error}"));  // server hangs up
+            return Err(Error::protocol("Server sent an error"));
+        }
+        if hed_buf[..pad::SIZE] == ack::PAD {  // answer to one of our messages
+            let mut ack_buf = ack_wide::DEFAULT;  // status comes before the id, so either fits
+            read_exact(&mut stream, &mut ack_buf[pad::SIZE..ack_size], "Failed to read ack")?;
+            let (_, status_buf, _, _) = ack_wide::split(&ack_buf);
+            let mut s = lock!(state)?;
+            s.sending = s.sending.saturating_sub(1);
+            s.status = match status_buf[0] {
+                ACK_STORED => None,  // also clears an older failure
+                code => Some(format!("send failed: {}", error_reason(code))),
+            };
+            continue;
+        }
+        read_exact(&mut stream, &mut hed_buf[pad::SIZE..hed_size], "Failed to read head buffer")?;
         // TODO: what should happen when this fails?
         // I guess thread closes and require reconnect
 
-        let (chat, first_id, count, forward) = parse_header(&hed_buf)?;
-        if count == 0 { continue }  // skip no messages
+        let (chat, first_id, count, forward) = parse_header(&hed_buf[..hed_size])?;
 
         // read messages expected from header
         let mut buf = vec![0; count as usize * msg_out::SIZE];  // TODO: consider array
@@ -70,78 +187,99 @@ fn listener(mut stream: TcpStream, state: Arc<Mutex<GlobalState>>) -> Res {
         let mut s = lock!(state)?;
         if chat != s.chat_id[0] { continue }  // skip wrong chat
 
-        let last_id = first_id + count as u32 - 1;  // inclusive. Can't undeflow
-
-        if s.min_id > s.max_id {  // initial fetch
-            // handle initial fetch separately; skip all checks
-            for msg in buf.chunks_exact(msg_out::SIZE) {
-                let msg = Message::new(msg.try_into().unwrap(), &s.chat_key)?;
-                s.queue.push_back(msg);
-            }
-            s.min_id = first_id;
-            s.max_id = last_id;
-            continue;  // initial fetch finished, move to next packet
+        if !forward {  // only jumps ask backward: the messages just before the date
+            if !mem::take(&mut s.jumping) { continue }  // superseded
+            s.reset();
+            if count > 0 { insert(&mut s, first_id, &buf) }
+            s.jump_at = Some(first_id + u64::from(count));  // even if empty, the id is where the date falls
+            s.scroll = Some(Scroll::To(count.into()));
+            s.status = None;
+            continue;
+        }
+        if let Some(at) = s.jump_at {  // the rest of a jump comes next
+            if first_id != at { continue }  // pushes; caught up on later
+            s.jump_at = None;
         }
+        if count == 0 { continue }  // skip no messages
 
-        if s.max_id + 1 < first_id  // disconnected ahead
-           || s.min_id > last_id + 1  // disconnected behind
-           || (s.min_id <= first_id && last_id <= s.max_id)  // already have this
-           || (first_id < s.min_id && s.max_id < last_id)  // overflow on both sides
-        { continue }  // skip all these
-
-        if forward {
-            if last_id > s.max_id {  // good proper data here
-                let i = if first_id <= s.max_id {s.max_id-first_id+1} else {0};
-                assert_eq!(s.max_id + 1, first_id + i);
-                for msg in buf.chunks_exact(msg_out::SIZE).skip(i as usize) {
-                    let msg = Message::new(msg.try_into().unwrap(), &s.chat_key)?;
-                    s.queue.push_back(msg);
-                }
-                // buf.chunks_exact(MSG_OUT_SIZE)
-                //     .skip(i as usize)
-                //     .map(|msg| Message::new(msg.try_into().unwrap(), &s.chat_key)?)
-                //     .for_each(|msg| s.queue.push_back(msg));
-                s.max_id = last_id;
-            } else {  // points forwards but behind our data
-                continue;
-            }
-        } else {  // not forwards (for scrolling up)
-            todo!()
+        if s.min_id <= s.max_id && s.max_id + 1 < first_id {  // disconnected ahead; missed some pushes
+            let (chat_id, max_id) = (s.chat_id, s.max_id);
+            drop(s);  // don't hold the lock while writing
+            comm::send_query(&mut stream, &chat_id, true, 50, max_id, wide)?;
+            continue;
         }
+        if s.min_id <= s.max_id && s.min_id > first_id + u64::from(count) { continue }  // disconnected behind
+        insert(&mut s, first_id, &buf);
     }
 }
 
 
-// Requester thread handles sending requests (fetch & query) to server
-fn requester(mut stream: TcpStream, state: Arc<Mutex<GlobalState>>) -> Res {
-    let chat_id = lock!(state)?.chat_id;
+fn insert(s: &mut GlobalState, first_id: u64, buf: &[u8]) {
+    // adds the messages starting at first_id that the queue doesn't have yet.
+    // They must overlap or touch it, unless it's empty.
+    let last_id = first_id + (buf.len() / msg_out::SIZE) as u64 - 1;  // inclusive. Can't undeflow
+
+    if s.min_id > s.max_id {  // initial packet
+        // handle initial packet separately; skip all checks
+        for msg in buf.chunks_exact(msg_out::SIZE) {
+            let msg = Message::new(msg.try_into().unwrap(), &s.chat_key);
+            s.queue.push_back(msg);
+        }
+        s.min_id = first_id;
+        s.max_id = last_id;
+        return;
+    }
 
-    // Fetch until we get first message packet
-    while lock!(state)?.queue.is_empty() {
-        comm::send_fetch(&mut stream, &chat_id)?;
-        thread::sleep(FQ_DELAY);
+    // Fetches and pushes can arrive in any order, so
+    // add whatever is missing on either side of our data.
+    if first_id < s.min_id {  // older messages: prepend in reverse
+        let n = (s.min_id - first_id) as usize;
+        for msg in buf.chunks_exact(msg_out::SIZE).take(n).rev() {
+            let msg = Message::new(msg.try_into().unwrap(), &s.chat_key);
+            s.queue.push_front(msg);
+        }
+        s.min_id = first_id;
+    }
+    if last_id > s.max_id {  // newer messages: append
+        let i = s.max_id - first_id + 1;
+        for msg in buf.chunks_exact(msg_out::SIZE).skip(i as usize) {
+            let msg = Message::new(msg.try_into().unwrap(), &s.chat_key);
+            s.queue.push_back(msg);
+        }
+        s.max_id = last_id;
     }
+}
+
+
+// Requester thread subscribes to the chat and makes the initial fetch.
+// New messages are then pushed by the server; no polling needed.
+// Re-subscribing is harmless, so it doubles as a keepalive.
+fn requester(mut stream: Stream, state: Arc<Mutex<GlobalState>>) -> Res {
+    let (chat_id, fetch_count) = {
+        let s = lock!(state)?;
+        (s.chat_id, s.fetch_count)
+    };
+
+    // subscribe first so no message slips between fetch and subscription
+    comm::send_subscribe(&mut stream, &chat_id)?;
+    comm::send_fetch_count(&mut stream, &chat_id, fetch_count)?;
 
-    // Query for scroll or fetch for more
     loop {
-        comm::send_query(
-            &mut stream,
-            &chat_id,
-            true,
-            50,
-            state.lock().unwrap().max_id,  // TODO: edit ID
-        )?;
-        thread::sleep(FQ_DELAY);
+        thread::sleep(KEEPALIVE_DELAY);
+        comm::send_subscribe(&mut stream, &chat_id)?;
     }
 }
 
 
 // Sender threads sends messages to server as they come in from snd_rx
 fn sender(
-    mut stream: TcpStream,
+    mut stream: Stream,
     state: Arc<Mutex<GlobalState>>,
     snd_rx: mpsc::Receiver<String>,
     keypair: ed25519::Keypair,
+    acks: bool,  // server will answer each message
+    times: bool,  // server answers time queries
+    pow_bits: Option<u8>,  // None before protocol version 2
 ) -> Res {
     let chat_id = lock!(state)?.chat_id;
     let chat_key = lock!(state)?.chat_key;
@@ -150,38 +288,109 @@ fn sender(
     let mut signature_buf: ed25519::SigBuf;
 
     loop {
-        let msg = snd_rx.recv().map_err(|_| "Message sender hung up")?;  // blocks
+        let msg = snd_rx.recv().map_err(|_| Error::internal("Message sender hung up"))?;  // blocks
         if msg.split_whitespace().next().is_none() { continue; }  // empty msg
+        if let Some(date) = msg.strip_prefix("/jump").filter(|d| d.is_empty() || d.starts_with(' ')) {
+            jump(&mut stream, &state, date.trim(), times)?;
+            continue;
+        }
 
         cypher_buf = Message::make_cypher(&msg, &chat_key, keypair.public.as_bytes())?;
         signature_buf = ed25519::sign(&cypher_buf, &keypair);
-        comm::send_msg(&mut stream, &chat_id, &cypher_buf, &signature_buf)?;
+        match pow_bits {
+            Some(bits) => comm::send_msg_v2(&mut stream, &chat_id, &cypher_buf, &signature_buf, bits)?,
+            None => comm::send_msg(&mut stream, &chat_id, &cypher_buf, &signature_buf)?,
+        }
+        if acks { lock!(state)?.sending += 1 }
     }
 }
 
 
-fn main() -> Result<(), Box<dyn Error>> {  // TODO: return Res instead?
-    eprintln!("Starting client...");
-    // arguments: addr:port title user
+fn jump(stream: &mut impl Write, state: &Mutex<GlobalState>, date: &str, times: bool) -> Res {
+    // "/jump DATE" shows the chat from then on; "/jump" alone goes back to the newest
+    let mut s = lock!(state)?;
+    let (chat_id, fetch_count) = (s.chat_id, s.fetch_count);
+    if date.is_empty() {
+        (s.jumping, s.jump_at) = (false, None);
+        s.reset();
+        s.scroll = Some(Scroll::Bottom);
+        drop(s);  // don't hold the lock while writing
+        return comm::send_fetch_count(stream, &chat_id, fetch_count);
+    }
+    let Some(time) = msg::parse_date(date) else {
+        s.status = Some("usage: /jump [YYYY-MM-DD [HH:MM]] (UTC)".to_string());
+        return Ok(());
+    };
+    if !times {
+        s.status = Some("this server can't jump to a date".to_string());
+        return Ok(());
+    }
+    (s.jumping, s.jump_at) = (true, None);  // the listener takes it from here
+    drop(s);
+    comm::send_time_query(stream, &chat_id, false, JUMP_CONTEXT, time)?;
+    comm::send_time_query(stream, &chat_id, true, JUMP_COUNT, time)
+}
 
-    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
 
-    let server_addr = args.get(0).ok_or("No addr given")?
+fn main() -> Result<(), Box<dyn std::error::Error>> {  // TODO: return Res instead?
+    eprintln!("Starting client...");
+    // arguments: addr:port title user [--tls-ca FILE | --tls-pin FILE] [--tls-name NAME] [--legacy]
+
+    let mut args = Vec::new();
+    let (mut tls_ca, mut tls_pin, mut tls_name) = (None, None, None);
+    let mut legacy = false;  // chat from before Argon2; never tried unless asked for
+    let mut args_in = std::env::args().skip(1);
+    while let Some(arg) = args_in.next() {
+        let flag = match arg.as_str() {
+            "--legacy" => { legacy = true; continue },
+            "--tls-ca" => &mut tls_ca,
+            "--tls-pin" => &mut tls_pin,
+            "--tls-name" => &mut tls_name,
+            _ => { args.push(arg); continue },
+        };
+        *flag = Some(args_in.next().ok_or(format!("{arg}: missing value"))?);
+    }
+    let trust = match (&tls_ca, &tls_pin) {
+        (None, None) => None,
+        (Some(ca), None) => Some(Trust::Ca(Path::new(ca))),
+        (None, Some(pin)) => Some(Trust::Pin(Path::new(pin))),
+        _ => return Err("Give either --tls-ca or --tls-pin, not both".into()),
+    };
+
+    let server_addr = args.first().ok_or("No addr given")?
         .to_socket_addrs()?
         .next().ok_or("Zero addrs received?")?;
+    // name the certificate must be for; defaults to whatever was dialled
+    let tls_name = tls_name.unwrap_or_else(|| {
+        let host = args[0].rsplit_once(':').map_or(args[0].as_str(), |(host, _port)| host);
+        host.trim_start_matches('[').trim_end_matches(']').to_string()
+    });
 
     let chat = mem::take(args.get_mut(1).ok_or("No title given")?);
-    let chat_key = sha::hash(chat.as_bytes());
+    eprintln!("Deriving keys...");
+    let chat_key = match legacy {
+        // its id is quick to brute-force; only old chats should need it
+        true => sha::hash(chat.as_bytes()),
+        false => kdf::chat_key(chat.as_bytes())?,
+    };
     let chat_id = sha::hash(&chat_key);
 
     let user = mem::take(args.get_mut(2).ok_or("No username given")?);
     let keypair = ed25519::make_keypair(user.as_bytes())?;
 
-    eprintln!("Connecting to server {:?}...", server_addr);
-    let mut stream = TcpStream::connect(server_addr)?;
-    eprintln!("Connected!");
-
-    stream.write_all(b"SMRT")?;
+    let mut stream = connect(server_addr, &tls_name, trust)?;
+    let (version, features, pow_bits) = match handshake(&mut stream)? {
+        Some(hello) => hello,
+        None => {  // the server hung up; speak version 0 without a hello
+            eprintln!("Server doesn't take hellos; reconnecting without one");
+            stream = connect(server_addr, &tls_name, trust)?;
+            (0, 0, 0)
+        },
+    };
+    eprintln!("Speaking protocol version {version} (server features {features:#x})");
+    let pow_bits = (version >= POW_VERSION).then_some(pow_bits);
+    let features = features & FEATURES;  // the ones both sides know
+    let counts = features & FEATURE_FETCH_COUNT != 0;
 
     let queue = VecDeque::with_capacity(500);
     let state = GlobalState {
@@ -190,14 +399,27 @@ fn main() -> Result<(), Box<dyn Error>> {  // TODO: return Res instead?
         chat_id,
         min_id: 1,
         max_id: 0,
+        wide: features & FEATURE_WIDE_IDS != 0,
+        status: None,
+        sending: 0,
+        fetch_count: counts.then(|| {  // a screenful; messages take at least a line each
+            let rows = terminal::size().map_or(u16::MAX, |(_, rows)| rows);
+            u8::try_from(rows).unwrap_or(u8::MAX).min(MAX_MSG_COUNT)
+        }),
+        jumping: false,
+        jump_at: None,
+        scroll: None,
     };
     let state = Arc::new(Mutex::new(state));
 
     // mpsc for sending messages
     let (msg_tx, msg_rx) = mpsc::channel::<String>();
 
+    // the listener keeps the original: it may hold data already decrypted
+    let (stream_req, stream_snd) = (stream.share(), stream.share());
+
     // start listener thread
-    let stream_c = stream.try_clone()?;
+    let stream_c = stream;
     let state_c = state.clone();
     eprintln!("Starting listener thread...");
     thread::spawn(|| {
@@ -208,7 +430,7 @@ fn main() -> Result<(), Box<dyn Error>> {  // TODO: return Res instead?
     });
 
     // start requester thread
-    let stream_c = stream.try_clone()?;
+    let stream_c = stream_req;
     let state_c = state.clone();
     eprintln!("Starting requester thread...");
     thread::spawn(|| {
@@ -219,11 +441,13 @@ fn main() -> Result<(), Box<dyn Error>> {  // TODO: return Res instead?
     });
 
     // start sender thread
-    let stream_c = stream.try_clone()?;
+    let stream_c = stream_snd;
     let state_c = state.clone();
+    let acks = features & FEATURE_SEND_ACK != 0;
+    let times = features & FEATURE_TIME_QUERY != 0;
     eprintln!("Starting requester thread...");
-    thread::spawn(|| {
-        match sender(stream_c, state_c, msg_rx, keypair) {
+    thread::spawn(move || {
+        match sender(stream_c, state_c, msg_rx, keypair, acks, times, pow_bits) {
             Ok(_) => eprintln!("Sender loop finished"),
             Err(e) => eprintln!("Sender loop crashed: {e}"),
         };
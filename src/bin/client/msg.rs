@@ -3,7 +3,16 @@ use std::{str, fmt, time::{Duration, SystemTime, UNIX_EPOCH}};
 use crossterm::style::{Stylize, Color};
 use rand::Rng;
 
-use publichat::buffers::{hash::Buf as HashBuf, cypher, msg_out_c as msg_out};
+use publichat::buffers::{
+    hash::Buf as HashBuf,
+    cypher,
+    cypher_v1,
+    cypher_v2,
+    msg_out_c as msg_out,
+};
+use publichat::error::Error;
+use publichat::helpers::Res;
+use publichat::constants::{CYPHER_V1, CYPHER_V2, CYPHER_IV_SIZE, CYPHER_CHAT_KEY_SIZE};
 use crate::crypt::*;
 use crate::common::{
     VERIFY_TOLERANCE_MS,
@@ -15,7 +24,6 @@ pub struct Message {
     // time: Duration,
     // user: Hash,
     // text: Contents,
-    // verified: bool,
     pub len: u16,
     pub repr: String,  // TODO: duplicate storage?
     // TODO: consider just having strings instead of this struct
@@ -26,7 +34,10 @@ impl Message {
     pub fn new(  // parse server's bytes into message text
         mut bytes: msg_out::Buf,
         chat_key: &HashBuf,
-    ) -> Result<Self, &'static str> {
+    ) -> Self {
+        // anything that can't be read becomes a placeholder, so junk
+        // sent to the chat doesn't stop the rest from showing
+
         // deconstruct bytes
         let (st_buf, c_buf, s_buf) = msg_out::split_mut(&mut bytes);
 
@@ -39,28 +50,56 @@ impl Message {
         // prepare for signature check before cypher gets decrypted
         let hashed_cypher = sha::hash(cypher.as_slice());
 
-        // decrypt chat in-place
-        aes::apply(chat_key, cypher);
-        let cypher_data = cypher;  // rename variable for clarity
-
-        // deconstruct msg_data
-        let (ck_buf, ct_buf, pk_buf, msg_buf) = cypher::split(cypher_data);
+        // decrypt; v2 is authenticated, so its chat check is the tag itself
+        let version = cypher[0];
+        let mut cypher_data = *cypher;
+        let (ct_buf, pk_buf, message) = if version == CYPHER_V2
+            && aead::open(chat_key, &mut cypher_data).is_ok()
+        {
+            let (_, _, ct_buf, pk_buf, len_buf, msg_buf, _) = cypher_v2::split(&cypher_data);
+            let len = u16::from_be_bytes(len_buf.try_into().unwrap());
+            let Some(message) = msg_buf.get(..len.into()) else { return Self::undecryptable(server_time) };
+            (ct_buf, pk_buf, message)
+        } else {
+            // v1 if marked so and the chat key matches, otherwise
+            // fall back to the v0 layout (fixed IV, no plaintext part)
+            cypher_data = *cypher;
+            let is_v1 = version == CYPHER_V1 && {
+                let (iv, data) = cypher_data.split_at_mut(CYPHER_IV_SIZE);
+                aes::apply(chat_key, (&*iv).try_into().unwrap(), data);
+                chat_key.starts_with(&data[..CYPHER_CHAT_KEY_SIZE])
+            };
+
+            // deconstruct msg_data
+            let (ck_buf, ct_buf, pk_buf, msg_buf) = if is_v1 {
+                let (_, _, ck_buf, ct_buf, pk_buf, msg_buf) = cypher_v1::split(&cypher_data);
+                (ck_buf, ct_buf, pk_buf, msg_buf)
+            } else {
+                aes::apply(chat_key, &aes::LEGACY_IV, cypher);
+                cypher::split(cypher)
+            };
+
+            if !chat_key.starts_with(ck_buf) {
+                // another chat's key (or a failed v2 that isn't a v0 in
+                // disguise); what came out of decrypting it is noise
+                return Self::undecryptable(server_time);
+            }
+
+            // find padding
+            let Some(pad_start) = msg_buf.iter().rposition(|&b| b == chat_key[0]) else {
+                return Self::undecryptable(server_time)
+            };
+            (ct_buf, pk_buf, &msg_buf[..pad_start])
+        };
 
         // shadow to change types (unwraps CANNOT fail here; len check skipped!)
         let client_time = u64::from_be_bytes(ct_buf.try_into().unwrap());
         let pub_key: &HashBuf = pk_buf.try_into().unwrap();
 
-        // find padding
-        let pad_start = msg_buf.iter()
-            .rposition(|&b| b == chat_key[0])
-            .ok_or("Invalid pad: indicator not found")?;
-        let message = &msg_buf[..pad_start];
-
         // verify message, prep verification mark
         let verified =
-            chat_key.starts_with(ck_buf)
-            && server_time.abs_diff(client_time) < VERIFY_TOLERANCE_MS
-            && ed25519::verify(&hashed_cypher, pub_key, signature)?;
+            server_time.abs_diff(client_time) < VERIFY_TOLERANCE_MS
+            && ed25519::verify(&hashed_cypher, pub_key, signature).unwrap_or(false);  // bad key
         let v_mark = if verified { '✔'.green() } else { '✗'.red().rapid_blink() };
 
         // prep username string
@@ -75,18 +114,10 @@ impl Message {
         let user_c = user.on(colour).with(w_or_b(&colour));
 
         // prep time string
-        let time = Duration::from_millis(server_time);
-        let (hour, min, sec) = {  // TODO: use date/time-related crate (?)
-            let time_sec = time.as_secs();
-            (
-                (time_sec / 3600) % 24,
-                (time_sec / 60) % 60,
-                time_sec % 60,
-            )
-        };
+        let (hour, min, sec) = time_parts(server_time);
 
         // prep message string: check utf8 and sanitise for ansi
-        let msg = str::from_utf8(message).map_err(|_| "Non-utf8 message!")?;
+        let msg = String::from_utf8_lossy(message);
         let msg = msg.chars()
             .map(|c| if c.is_ascii_control() {'�'} else {c})
             .collect::<String>();
@@ -98,46 +129,59 @@ impl Message {
 
         const PREFIX_LEN: u16 = 1 + 1 + USER_ID_CHAR_COUNT as u16 + 1 + 8 + 1;
 
-        Ok(Self {
+        Self {
             // time,
             // user,
             // text: cypher,
-            // verified,
-            // length,
             len: PREFIX_LEN + msg.chars().count() as u16,
             repr: cached_str_repr,
-        })
+        }
+    }
+
+    fn undecryptable(server_time: u64) -> Self {
+        // placeholder for a message nothing can be read from
+        let (hour, min, sec) = time_parts(server_time);
+        let text = "<undecryptable>";
+        Self {
+            len: 1 + 1 + 8 + 1 + text.len() as u16,
+            repr: format!(
+                "{} {hour:0>2}:{min:0>2}:{sec:0>2} {}",
+                '✗'.red().rapid_blink(),
+                text.dark_grey(),
+            ),
+        }
     }
 
     pub fn make_cypher(
         text: &str,
         chat_key: &HashBuf,
         pub_key: &HashBuf,
-    ) -> Result<cypher::Buf, &'static str> {
+    ) -> Res<cypher::Buf> {
         let time: u64 = SystemTime::now()
             .duration_since(UNIX_EPOCH).expect("Woah, get with the times!")
             .as_millis().try_into().expect("Alright, futureboy");
         
-        let mut res = cypher::DEFAULT;
-        let (ck_buf, t_buf, pk_buf, msg_buf) = cypher::split_mut(&mut res);
+        let mut res = cypher_v2::DEFAULT;
+        let (v_buf, n_buf, t_buf, pk_buf, len_buf, msg_buf, _) = cypher_v2::split_mut(&mut res);
         
-        if text.len() > msg_buf.len() - 1 { return Err("Can't make cypher; msg too long") }
+        if text.len() > msg_buf.len() { return Err(Error::limit("Can't make cypher; msg too long")) }
+
+        // version and a fresh nonce (never encrypted)
+        let mut rng = rand::thread_rng();
+        v_buf[0] = CYPHER_V2;
+        rng.fill(n_buf);
 
         // copy in basic data
-        ck_buf.copy_from_slice(&chat_key[..ck_buf.len()]);
         t_buf.copy_from_slice(&time.to_be_bytes());
         pk_buf.copy_from_slice(pub_key);
+        len_buf.copy_from_slice(&(text.len() as u16).to_be_bytes());  // can't overflow
         msg_buf[..text.len()].copy_from_slice(text.as_bytes());
 
-        // padding
-        let mut rng = rand::thread_rng();
-        msg_buf[text.len()] = chat_key[0];  // pad indicator
-        msg_buf[text.len()+1..].fill_with(||
-            rng.gen_range(1u8..=0xff).wrapping_add(chat_key[0])
-        );
+        // padding; length is explicit so no indicator is needed
+        rng.fill(&mut msg_buf[text.len()..]);
 
-        // AES
-        aes::apply(chat_key, &mut res);
+        // AES-GCM; fills in the tag
+        aead::seal(chat_key, &mut res)?;
 
         Ok(res)
     }
@@ -149,9 +193,39 @@ impl fmt::Display for Message {
     }
 }
 
+fn time_parts(server_time: u64) -> (u64, u64, u64) {
+    // (hour, min, sec) of a timestamp in ms
+    // TODO: use date/time-related crate (?)
+    let time_sec = Duration::from_millis(server_time).as_secs();
+    (
+        (time_sec / 3600) % 24,
+        (time_sec / 60) % 60,
+        time_sec % 60,
+    )
+}
+
+pub fn parse_date(text: &str) -> Option<u64> {
+    // "YYYY-MM-DD" or "YYYY-MM-DD HH:MM", UTC like the times shown, in ms
+    let (date, time) = text.split_once(' ').unwrap_or((text, "00:00"));
+    let mut date = date.split('-').map(str::parse::<u64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (hour, min) = time.split_once(':')?;
+    let (hour, min) = (hour.parse::<u64>().ok()?, min.parse::<u64>().ok()?);
+    if !(1970..10000).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || min > 59 { return None }
+
+    // days since 1970-01-01 (Howard Hinnant's days_from_civil)
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era_days = y / 400 * 146_097;
+    let yoe = y % 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let days = era_days + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719_468;
+    Some(((days * 24 + hour) * 60 + min) * 60 * 1000)
+}
+
 fn w_or_b(colour: &Color) -> Color {  // TODO: where should this function be?
     // Return white for dark colours, black for light colours
-    return if let Color::Rgb{r, g, b} = colour {
+    if let Color::Rgb{r, g, b} = colour {
         let is_dark = (
               0.299 * f32::from(*r)
             + 0.587 * f32::from(*g)
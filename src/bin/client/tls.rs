@@ -0,0 +1,100 @@
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+
+use publichat::helpers::*;
+use publichat::error::{Error, Kind};
+use publichat::stream::{Stream, load_certs};
+
+#[derive(Clone, Copy)]
+pub enum Trust<'a> {
+    Ca(&'a Path),  // PEM file of certificates to trust
+    Pin(&'a Path),  // PEM file with the server's own certificate
+}
+
+// Accepts exactly one certificate, whoever signed it (self-signed is fine).
+// The handshake signatures are still checked, so the server must hold the key.
+#[derive(Debug)]
+struct Pinned {
+    cert: CertificateDer<'static>,
+    algs: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("certificate doesn't match pinned one".to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algs.supported_schemes()
+    }
+}
+
+pub fn connect(mut tcp: TcpStream, name: &str, trust: Trust) -> Res<Stream> {
+    // finishes the handshake here so bad certificates fail loudly
+    let config = match trust {
+        Trust::Ca(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert)
+                    .map_err(|e| Error::with_source(Kind::Crypto, "Invalid CA certificate", e))?;
+            }
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth()
+        },
+        Trust::Pin(path) => {
+            let cert = load_certs(path)?.swap_remove(0);  // not empty
+            let algs = CryptoProvider::get_default()
+                .map(|p| p.signature_verification_algorithms)
+                .unwrap_or(crypto::ring::default_provider().signature_verification_algorithms);
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(Pinned { cert, algs }))
+                .with_no_client_auth()
+        },
+    };
+
+    let name = ServerName::try_from(name.to_string())
+        .map_err(|e| Error::with_source(Kind::Crypto, "Invalid TLS server name", e))?;
+    let mut conn = ClientConnection::new(Arc::new(config), name)
+        .map_err(|e| Error::with_source(Kind::Crypto, "Failed to start TLS", e))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp).map_err(|e| Error::io("TLS handshake failed", e))?;
+    }
+    Ok(Stream::tls(tcp, conn))
+}
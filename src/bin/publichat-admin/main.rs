@@ -0,0 +1,200 @@
+// Maintenance of a server's data directory, from the outside.
+
+use std::{fs, path::{Path, PathBuf}, process::exit};
+
+use publichat::{db, layout, log};
+use publichat::helpers::Res;
+use publichat::error::{Error, Kind};
+use publichat::layout::Layout;
+
+const HELP: &str = "\
+Usage: publichat-admin COMMAND [OPTIONS] DATA_DIR
+
+Commands:
+  migrate     Move every chat from the flat layout into the sharded one.
+              Safe to run (and rerun) while a server with --layout sharded
+              is up; stop servers with the flat layout first.
+  fsck        Check every chat: file sizes, the index, and that server
+              times only go up. Stop the server first.
+      --repair           Cut half-written messages off (into .torn files, as
+                         the server does), finish conversions and migrations
+                         that stopped part way, and move broken chats and
+                         stray files to the quarantine directory
+      --quarantine DIR   Where they go [default: DATA_DIR/quarantine]
+
+fsck exits with 1 if any problem is left.
+";
+
+const PROGRESS_EVERY: usize = 10_000;  // chats
+const MOVED: &str = "moved to quarantine";
+
+fn migrate(dir: &Path) -> Res<(usize, usize)> {
+    // Returns chats moved and chats that couldn't be
+    let entries = fs::read_dir(dir).map_err(|e| Error::file("Failed to list data dir", e))?;
+    let mut chats = Vec::new();  // all names first; moving them changes the directory
+    for entry in entries {
+        let name = entry.map_err(|e| Error::file("Failed to list data dir", e))?.file_name();
+        let name = name.to_string_lossy();
+        let stem = name.split_once('.').map_or(&*name, |(stem, _)| stem);
+        chats.extend(layout::chat_id(stem));
+    }
+    chats.sort_unstable();
+    chats.dedup();  // a chat and its .torn file
+
+    let (mut moved, mut failed) = (0, 0);
+    for (i, chat) in chats.iter().enumerate() {
+        match layout::shard(dir, chat) {
+            Ok(true) => moved += 1,
+            Ok(false) => {},  // the server got to it first
+            Err(e) => { eprintln!("{}: {e}", layout::name(chat)); failed += 1 },
+        }
+        if (i + 1) % PROGRESS_EVERY == 0 { println!("{} of {} chats done", i + 1, chats.len()) }
+    }
+    Ok((moved, failed))
+}
+
+#[derive(Default)]
+struct Tally {  // what fsck found in all chats
+    chats: u64,
+    messages: u64,
+    bytes: u64,
+    largest: (u64, PathBuf),  // bytes
+    problems: u64,  // left as they were
+    repaired: u64,
+    failed: u64,  // couldn't be checked
+}
+
+fn move_out(from: &Path, to: &Path) -> Res {
+    // into the quarantine directory
+    if to.exists() { return Err(Error::corruption("Already in quarantine")) }
+    let parent = to.parent().unwrap();  // made by the caller's join
+    fs::create_dir_all(parent).map_err(|e| Error::file("Failed to create quarantine", e))?;
+    fs::rename(from, to).map_err(|e| Error::file("Failed to move to quarantine", e))
+}
+
+fn found(tally: &mut Tally, path: &Path, problem: String, fix: Option<&str>) {
+    // fix: how it was repaired
+    match fix {
+        Some(fix) => { println!("{}: {problem} ({fix})", path.display()); tally.repaired += 1 },
+        None => { println!("{}: {problem}", path.display()); tally.problems += 1 },
+    }
+}
+
+fn fsck(dir: &Path, repair: bool, quarantine: &Path) -> Res<Tally> {
+    let mut tally = Tally::default();
+    let chats = layout::chats(dir)?;
+    for (i, chat) in chats.iter().enumerate() {
+        let name = layout::name(chat);
+        let behind = layout::left_behind(dir, chat);
+        let half_moved = !behind.is_empty();
+        if half_moved {
+            let moved = repair && match layout::shard(dir, chat) {
+                Ok(_) => true,
+                Err(e) => { eprintln!("{name}: {e}"); false },
+            };
+            for path in behind {
+                let problem = "left behind by a migration".to_string();
+                found(&mut tally, &path, problem, moved.then_some("moved to its shard"));
+            }
+        }
+        for path in [Layout::Flat, Layout::Sharded].map(|l| layout::path(dir, l, chat)) {
+            // usually in just one; what a conversion left counts, unless a migration did
+            let converting = ["flat", "new"].iter().any(|ext| path.with_extension(ext).exists());
+            if !path.exists() && (!converting || half_moved) { continue }
+            tally.chats += 1;
+            let mut found = |problem: String, fix: Option<&str>| found(&mut tally, &path, problem, fix);
+
+            let check = match db::check(&path, repair) {
+                Ok(check) => check,
+                Err(e) if e.kind() == Kind::Corruption => {  // the server can't read it
+                    let moved = repair && match move_out(&path, &quarantine.join(&name)) {
+                        Ok(()) => true,
+                        Err(e) => { eprintln!("{}: {e}", path.display()); false },
+                    };
+                    found(e.to_string(), moved.then_some(MOVED));
+                    continue;
+                },
+                Err(e) => {
+                    eprintln!("{}: {e}", path.display());
+                    tally.failed += 1;
+                    continue;
+                },
+            };
+            for problem in check.problems { found(problem, None) }
+            for problem in check.repaired { found(problem, Some("repaired")) }
+            for stray in check.strays {
+                let file = stray.file_name().unwrap();  // listed in the chat
+                let moved = repair && match move_out(&stray, &quarantine.join(&name).join(file)) {
+                    Ok(()) => true,
+                    Err(e) => { eprintln!("{}: {e}", stray.display()); false },
+                };
+                found(format!("stray file {}", file.to_string_lossy()), moved.then_some(MOVED));
+            }
+
+            tally.messages += check.messages;
+            tally.bytes += check.bytes;
+            if check.bytes > tally.largest.0 { tally.largest = (check.bytes, path) }
+        }
+        if (i + 1) % PROGRESS_EVERY == 0 { println!("{} of {} chats done", i + 1, chats.len()) }
+    }
+    Ok(tally)
+}
+
+fn fsck_main(args: &[&str]) -> Option<i32> {
+    // exit code, or None if args don't make sense
+    let (mut repair, mut quarantine, mut dir) = (false, None, None);
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--repair" => repair = true,
+            "--quarantine" => quarantine = Some(PathBuf::from(args.next()?)),
+            _ if arg.starts_with('-') || dir.is_some() => return None,
+            _ => dir = Some(Path::new(arg)),
+        }
+    }
+    let dir = dir?;
+    let quarantine = quarantine.unwrap_or_else(|| dir.join("quarantine"));
+
+    log::init(log::Level::Error, None);  // repairs are reported below
+    let tally = match fsck(dir, repair, &quarantine) {
+        Ok(tally) => tally,
+        Err(e) => { eprintln!("{e}"); return Some(1) },
+    };
+    println!("Checked {} chats: {} messages, {} MiB", tally.chats, tally.messages, tally.bytes >> 20);
+    if tally.largest.0 > 0 {  // none read, if every chat was broken
+        println!("Largest: {} ({} MiB)", tally.largest.1.display(), tally.largest.0 >> 20);
+    }
+    println!("{} problems left, {} repaired", tally.problems, tally.repaired);
+    if tally.failed > 0 { eprintln!("{} chats couldn't be checked (see above)", tally.failed) }
+    Some(if tally.problems + tally.failed == 0 { 0 } else { 1 })
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["migrate", dir] => match migrate(Path::new(dir)) {
+            Ok((moved, 0)) => println!("Moved {moved} chats"),
+            Ok((moved, failed)) => {
+                eprintln!("Moved {moved} chats; {failed} failed (see above)");
+                exit(1);
+            },
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            },
+        },
+        ["fsck", ref rest @ ..] => match fsck_main(rest) {
+            Some(code) => exit(code),
+            None => {
+                eprint!("{HELP}");
+                exit(2);
+            },
+        },
+        [] | ["-h" | "--help"] => print!("{HELP}"),
+        _ => {
+            eprint!("{HELP}");
+            exit(2);
+        },
+    }
+}
@@ -0,0 +1,549 @@
+// Server settings: defaults, then the config file, then command line flags.
+// The config file is a small subset of TOML:
+//
+//     data_dir = "data/"
+//     bind = ["0.0.0.0:7878", "[::]:7878"]
+//
+//     [limits]
+//     idle_timeout = 120  # seconds
+//     send_ip = "30/60"  # 30 at once, then one every 2 seconds
+//
+//     [retention]
+//     max_age = 30  # days
+//
+//     [storage]
+//     backend = "files"  # or "memory", which needs no data_dir
+//     layout = "sharded"
+//     fsync = "always"
+//
+//     [log]
+//     level = "debug"
+//
+//     [tls]
+//     bind = ["0.0.0.0:7879"]
+//     cert = "cert.pem"
+//     key = "key.pem"
+
+use std::{
+    fs::File,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::ServerConfig;
+
+use publichat::helpers::{Limits, Rate};
+use publichat::constants::{MAX_MSG_COUNT, POW_BITS_MAX};
+use publichat::stream::{load_certs, load_key};
+use publichat::log::Level;
+use publichat::db::Fsync;
+use publichat::layout::Layout;
+use crate::retention::Retention;
+
+const IP_PORT_DEFAULT: &str = "localhost:7878";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Files,  // in the data directory
+    Memory,  // gone when the server stops
+}
+
+pub const HELP: &str = "\
+Usage: server [OPTIONS] [ADDR] [DATA_DIR]
+
+Options (each also settable in the config file, shown in brackets):
+  -h, --help                  Print this and exit
+  -c, --config FILE           Read settings from FILE; flags take precedence
+  -b, --bind ADDR             Listen on ADDR; repeat for more    [bind]
+  -d, --data-dir DIR          Where chats are stored             [data_dir]
+      --workers N             Threads handling packets           [limits.workers]
+      --max-connections N     Open connections in total          [limits.connections]
+      --max-per-ip N          Open connections per address       [limits.per_ip]
+      --fetch-max N           Most messages per fetch/query      [limits.fetch_max]
+      --fetch-default N       Messages sent for a fetch          [limits.fetch_default]
+      --cache-messages N      Newest messages in memory per chat [limits.cache_msgs]
+      --cache-chats N         Chats kept in memory               [limits.cache_chats]
+      --read-timeout SECS     To finish a started packet         [limits.read_timeout]
+      --idle-timeout SECS     Before quiet sessions are dropped  [limits.idle_timeout]
+      --write-timeout SECS    Before slow readers are dropped    [limits.write_timeout]
+      --pow-bits N            Proof of work per message; 0: off  [limits.pow_bits]
+      --rate-send-ip RATE     Messages sent per address          [limits.send_ip]
+      --rate-send-chat RATE   Messages sent per chat             [limits.send_chat]
+      --rate-fetch-ip RATE    Fetches per address                [limits.fetch_ip]
+      --rate-fetch-chat RATE  Fetches per chat                   [limits.fetch_chat]
+      --rate-query-ip RATE    Queries per address                [limits.query_ip]
+      --rate-query-chat RATE  Queries per chat                   [limits.query_chat]
+      --keep-messages N       Newest messages kept per chat      [retention.max_messages]
+      --keep-days DAYS        Age at which messages are dropped  [retention.max_age]
+      --disk-budget MIB       Space for all chats together       [retention.disk_budget]
+      --compact-every SECS    How often the above are applied    [retention.interval]
+      --store BACKEND         files, or memory (lost on exit)    [storage.backend]
+      --layout LAYOUT         flat, or sharded (in subdirs)      [storage.layout]
+      --fsync POLICY          always, never, or SECS (batched)   [storage.fsync]
+      --log-level LEVEL       error, info or debug               [log.level]
+      --log-file FILE         Append log to FILE, not stdout     [log.file]
+      --tls-bind ADDR         Listen for TLS on ADDR; repeatable [tls.bind]
+      --tls-cert FILE         PEM certificate chain for TLS      [tls.cert]
+      --tls-key FILE          PEM private key for TLS            [tls.key]
+
+ADDR defaults to localhost:7878, unless only TLS addresses are given.
+RATE is COUNT/SECS (COUNT at once, refilled over SECS), or off.
+Local addresses are not limited per address; they are likely a proxy.
+A sharded server still reads flat chats; `publichat-admin migrate` moves them.
+POLICY defaults to 1: messages are synced to disk within a second.
+Retention settings are off (0) by default. Space is freed in whole segments
+of 65536 messages (32 MiB), and the newest segment of a chat is always kept.
+DATA_DIR is required, in one way or another.
+";
+
+pub struct Config {
+    pub binds: Vec<SocketAddr>,
+    pub tls_binds: Vec<SocketAddr>,
+    pub tls: Option<Arc<ServerConfig>>,  // built from tls_cert and tls_key
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub limits: Limits,
+    pub retention: Retention,
+    pub backend: Backend,
+    pub layout: Layout,
+    pub fsync: Fsync,
+    pub log_level: Level,
+    pub log_file: Option<File>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            binds: Vec::new(),
+            tls_binds: Vec::new(),
+            tls: None,
+            tls_cert: None,
+            tls_key: None,
+            data_dir: None,
+            limits: Limits::default(),
+            retention: Retention::default(),
+            backend: Backend::Files,
+            layout: Layout::Flat,
+            fsync: Fsync::Every(Duration::from_secs(1)),
+            log_level: Level::Info,
+            log_file: None,
+        }
+    }
+}
+
+fn number<T>(value: &str, min: T, max: T) -> Result<T, String>
+where T: std::str::FromStr + PartialOrd + std::fmt::Display {
+    match value.parse::<T>() {
+        Ok(n) if min <= n && n <= max => Ok(n),
+        _ => Err(format!("expected a whole number from {min} to {max}, got {value:?}")),
+    }
+}
+
+fn seconds(value: &str) -> Result<Duration, String> {
+    number(value, 1, 24 * 60 * 60).map(Duration::from_secs)
+}
+
+fn rate(value: &str) -> Result<Rate, String> {
+    if value == "off" { return Ok(Rate::OFF) }
+    let (count, secs) = value.split_once('/')
+        .ok_or_else(|| format!("expected COUNT/SECS or off, got {value:?}"))?;
+    Ok(Rate { count: number(count, 1, 1 << 20)?, per: seconds(secs)? })
+}
+
+fn address(value: &str) -> Result<SocketAddr, String> {
+    value.to_socket_addrs()
+        .map_err(|e| format!("invalid address {value:?}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("{value:?} resolved to nothing"))
+}
+
+fn file(value: &str) -> Result<PathBuf, String> {
+    let path = Path::new(value);
+    if !path.is_file() { return Err(format!("not a file: {value:?}")) }
+    Ok(path.to_path_buf())
+}
+
+impl Config {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        // applies one setting; the error only describes the value
+        let limits = &mut self.limits;
+        match key {
+            "bind" => self.binds.push(address(value)?),
+            "data_dir" => {
+                let path = Path::new(value);
+                if !path.is_dir() { return Err(format!("not a directory: {value:?}")) }
+                self.data_dir = Some(path.to_path_buf());
+            },
+            "limits.workers" => limits.workers = number(value, 1, 1024)?,
+            "limits.connections" => limits.connections = number(value, 1, 1 << 20)?,
+            "limits.per_ip" => limits.per_ip = number(value, 1, 1 << 20)?,
+            "limits.fetch_max" => limits.fetch_max = number(value, 1, MAX_MSG_COUNT)?,
+            "limits.fetch_default" => limits.fetch_default = number(value, 1, MAX_MSG_COUNT)?,
+            "limits.cache_msgs" => limits.cache_msgs = number(value, 0, 1 << 16)?,
+            "limits.cache_chats" => limits.cache_chats = number(value, 1, 1 << 20)?,
+            "limits.read_timeout" => limits.read_timeout = seconds(value)?,
+            "limits.idle_timeout" => limits.idle_timeout = seconds(value)?,
+            "limits.write_timeout" => limits.write_timeout = seconds(value)?,
+            "limits.pow_bits" => limits.pow_bits = number(value, 0, POW_BITS_MAX)?,
+            "limits.send_ip" => limits.send_ip = rate(value)?,
+            "limits.send_chat" => limits.send_chat = rate(value)?,
+            "limits.fetch_ip" => limits.fetch_ip = rate(value)?,
+            "limits.fetch_chat" => limits.fetch_chat = rate(value)?,
+            "limits.query_ip" => limits.query_ip = rate(value)?,
+            "limits.query_chat" => limits.query_chat = rate(value)?,
+            "retention.max_messages" => self.retention.max_messages = number(value, 0, 1 << 40)?,
+            "retention.max_age" => {
+                let days: u64 = number(value, 0, 100 * 365)?;
+                self.retention.max_age = Duration::from_secs(days * 24 * 60 * 60);
+            },
+            "retention.disk_budget" => self.retention.disk_budget = number::<u64>(value, 0, 1 << 30)? << 20,
+            "retention.interval" => self.retention.interval = seconds(value)?,
+            "storage.backend" => {
+                self.backend = match value {
+                    "files" => Backend::Files,
+                    "memory" => Backend::Memory,
+                    _ => return Err(format!("expected files or memory, got {value:?}")),
+                }
+            },
+            "storage.layout" => {
+                self.layout = match value {
+                    "flat" => Layout::Flat,
+                    "sharded" => Layout::Sharded,
+                    _ => return Err(format!("expected flat or sharded, got {value:?}")),
+                }
+            },
+            "storage.fsync" => {
+                self.fsync = match value {
+                    "always" => Fsync::Always,
+                    "never" => Fsync::Never,
+                    secs => Fsync::Every(seconds(secs)
+                        .map_err(|_| format!("expected always, never or seconds, got {value:?}"))?),
+                }
+            },
+            "log.level" => {
+                self.log_level = match value {
+                    "error" => Level::Error,
+                    "info" => Level::Info,
+                    "debug" => Level::Debug,
+                    _ => return Err(format!("expected error, info or debug, got {value:?}")),
+                }
+            },
+            "log.file" => {
+                let file = File::options().append(true).create(true).open(value)
+                    .map_err(|e| format!("can't open {value:?}: {e}"))?;
+                self.log_file = Some(file);
+            },
+            "tls.bind" => self.tls_binds.push(address(value)?),
+            "tls.cert" => self.tls_cert = Some(file(value)?),
+            "tls.key" => self.tls_key = Some(file(value)?),
+            _ => return Err("unknown setting".to_string()),
+        }
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read config file {path:?}: {e}"))?;
+
+        let mut section = String::new();
+        for (i, line) in text.lines().enumerate() {
+            let at = || format!("{path}:{}", i + 1);
+            let line = match line.split_once('#') {  // no '#' in values, fine
+                Some((line, _comment)) => line.trim(),
+                None => line.trim(),
+            };
+            if line.is_empty() { continue }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = format!("{}.", name.trim());
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| format!("{}: expected `key = value`, got {line:?}", at()))?;
+            let key = format!("{section}{}", key.trim());
+            let value = value.trim();
+
+            // arrays (of strings) set the key once per element
+            let values: Vec<&str> = match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                Some(inner) => inner.split(',').map(str::trim).filter(|v| !v.is_empty()).collect(),
+                None => vec![value],
+            };
+            for value in values {
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                self.set(&key, value).map_err(|e| format!("{}: {key}: {e}", at()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_args(args: &[String]) -> Result<Option<Self>, String> {
+        // Returns None if help was asked for
+        let mut config = Self::default();
+
+        // the config file goes first, whatever its position, so flags override it
+        let mut args = args.iter();
+        let mut flags = Vec::new();
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            let key = match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-c" | "--config" => "config",
+                "-b" | "--bind" => "bind",
+                "-d" | "--data-dir" => "data_dir",
+                "--workers" => "limits.workers",
+                "--max-connections" => "limits.connections",
+                "--max-per-ip" => "limits.per_ip",
+                "--fetch-max" => "limits.fetch_max",
+                "--fetch-default" => "limits.fetch_default",
+                "--cache-messages" => "limits.cache_msgs",
+                "--cache-chats" => "limits.cache_chats",
+                "--read-timeout" => "limits.read_timeout",
+                "--idle-timeout" => "limits.idle_timeout",
+                "--write-timeout" => "limits.write_timeout",
+                "--pow-bits" => "limits.pow_bits",
+                "--rate-send-ip" => "limits.send_ip",
+                "--rate-send-chat" => "limits.send_chat",
+                "--rate-fetch-ip" => "limits.fetch_ip",
+                "--rate-fetch-chat" => "limits.fetch_chat",
+                "--rate-query-ip" => "limits.query_ip",
+                "--rate-query-chat" => "limits.query_chat",
+                "--keep-messages" => "retention.max_messages",
+                "--keep-days" => "retention.max_age",
+                "--disk-budget" => "retention.disk_budget",
+                "--compact-every" => "retention.interval",
+                "--store" => "storage.backend",
+                "--layout" => "storage.layout",
+                "--fsync" => "storage.fsync",
+                "--log-level" => "log.level",
+                "--log-file" => "log.file",
+                "--tls-bind" => "tls.bind",
+                "--tls-cert" => "tls.cert",
+                "--tls-key" => "tls.key",
+                flag if flag.starts_with('-') => {
+                    return Err(format!("Unknown option {flag}; see --help"))
+                },
+                _ => { positional.push(arg.as_str()); continue },
+            };
+            let value = args.next().ok_or_else(|| format!("{arg}: missing value"))?;
+            if key == "config" {
+                config.read_file(value)?;
+            } else {
+                flags.push((arg, key, value));
+            }
+        }
+
+        // flags given on the command line replace the file's binds
+        if flags.iter().any(|(_, key, _)| *key == "bind") || positional.len() == 2 {
+            config.binds.clear();
+        }
+        if flags.iter().any(|(_, key, _)| *key == "tls.bind") {
+            config.tls_binds.clear();
+        }
+        for (arg, key, value) in flags {
+            config.set(key, value).map_err(|e| format!("{arg}: {e}"))?;
+        }
+
+        // the old way: [ADDR] DATA_DIR
+        match positional[..] {
+            [] => {},
+            [dir] => config.set("data_dir", dir).map_err(|e| format!("DATA_DIR: {e}"))?,
+            [addr, dir] => {
+                config.set("bind", addr).map_err(|e| format!("ADDR: {e}"))?;
+                config.set("data_dir", dir).map_err(|e| format!("DATA_DIR: {e}"))?;
+            },
+            _ => return Err("Too many arguments; see --help".to_string()),
+        }
+
+        // settings that only make sense together
+        match config.backend {
+            Backend::Files if config.data_dir.is_none() => {
+                return Err("No data directory given; see --help".to_string());
+            },
+            Backend::Memory if config.retention.enabled() => {
+                return Err("Retention only applies to storage.backend = \"files\"".to_string());
+            },
+            _ => {},
+        }
+        if config.limits.fetch_default > config.limits.fetch_max {
+            return Err(format!(
+                "limits.fetch_default ({}) can't be above limits.fetch_max ({})",
+                config.limits.fetch_default,
+                config.limits.fetch_max,
+            ));
+        }
+        match (&config.tls_binds[..], &config.tls_cert, &config.tls_key) {
+            ([], None, None) => {},
+            ([], _, _) => return Err("tls.cert and tls.key need TLS addresses (tls.bind); see --help".to_string()),
+            (_, Some(cert), Some(key)) => {
+                let certs = load_certs(cert).map_err(|e| format!("tls.cert: {e}"))?;
+                let key = load_key(key).map_err(|e| format!("tls.key: {e}"))?;
+                let tls = ServerConfig::builder()
+                    .with_no_client_auth()
+                    .with_single_cert(certs, key)
+                    .map_err(|e| format!("tls: certificate doesn't fit key: {e}"))?;
+                config.tls = Some(Arc::new(tls));
+            },
+            _ => return Err("TLS addresses need both tls.cert and tls.key; see --help".to_string()),
+        }
+        if config.binds.is_empty() && config.tls_binds.is_empty() {
+            config.set("bind", IP_PORT_DEFAULT).map_err(|e| format!("default address: {e}"))?;
+        }
+
+        Ok(Some(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TempFile(PathBuf);  // removed after the test
+
+    impl TempFile {
+        fn new(text: &str) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!("publichat-config-{}-{n}.toml", std::process::id()));
+            std::fs::write(&path, text).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str { self.0.to_str().unwrap() }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) { let _ = std::fs::remove_file(&self.0); }
+    }
+
+    fn dir() -> String {
+        std::env::temp_dir().to_str().unwrap().to_string()
+    }
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Config::from_args(&args).map(|config| config.expect("asked for help"))
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{args:?} was taken"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn set_checks_values() {
+        let mut config = Config::default();
+        config.set("limits.workers", "1024").unwrap();
+        assert_eq!(config.limits.workers, 1024);
+        for bad in ["0", "1025", "-1", "four", ""] {
+            assert!(config.set("limits.workers", bad).unwrap_err().contains("1 to 1024"), "{bad}");
+        }
+        assert_eq!(config.limits.workers, 1024);  // untouched by the bad ones
+
+        config.set("limits.send_ip", "30/60").unwrap();
+        assert_eq!((config.limits.send_ip.count, config.limits.send_ip.per), (30, Duration::from_secs(60)));
+        config.set("limits.send_ip", "off").unwrap();
+        assert_eq!(config.limits.send_ip.count, 0);
+        for bad in ["30", "0/60", "30/0", "30/", "/60"] {
+            assert!(config.set("limits.send_ip", bad).is_err(), "{bad}");
+        }
+
+        config.set("retention.max_age", "2").unwrap();
+        assert_eq!(config.retention.max_age, Duration::from_secs(2 * 24 * 60 * 60));
+        config.set("retention.disk_budget", "3").unwrap();
+        assert_eq!(config.retention.disk_budget, 3 << 20);
+        config.set("storage.fsync", "5").unwrap();
+        assert!(matches!(config.fsync, Fsync::Every(d) if d == Duration::from_secs(5)));
+        config.set("storage.fsync", "always").unwrap();
+        assert!(matches!(config.fsync, Fsync::Always));
+        assert!(config.set("storage.fsync", "sometimes").unwrap_err().contains("always, never"));
+        config.set("storage.layout", "sharded").unwrap();
+        assert!(config.layout == Layout::Sharded);
+        assert!(config.set("storage.backend", "cloud").is_err());
+        config.set("log.level", "debug").unwrap();
+        assert_eq!(config.log_level, Level::Debug);
+
+        config.set("bind", "127.0.0.1:1").unwrap();
+        config.set("bind", "127.0.0.1:2").unwrap();
+        assert_eq!(config.binds.len(), 2);  // each one adds
+        assert!(config.set("bind", "nowhere").is_err());
+        assert!(config.set("data_dir", "/no/such/dir").unwrap_err().contains("not a directory"));
+        assert!(config.set("tls.cert", "/no/such/file").unwrap_err().contains("not a file"));
+        assert_eq!(config.set("limits.nothing", "1").unwrap_err(), "unknown setting");
+    }
+
+    #[test]
+    fn positional_args() {
+        let config = parse(&[&dir()]).unwrap();
+        assert_eq!(config.data_dir.as_deref(), Some(Path::new(&dir())));
+        assert_eq!(config.binds, [address(IP_PORT_DEFAULT).unwrap()]);
+
+        let config = parse(&["127.0.0.1:7000", &dir()]).unwrap();
+        assert_eq!(config.binds, [address("127.0.0.1:7000").unwrap()]);
+
+        assert!(error(&["127.0.0.1:7000", &dir(), "extra"]).contains("Too many"));
+        assert!(error(&["nowhere", &dir()]).starts_with("ADDR: "));
+        assert!(error(&["127.0.0.1:7000", "/no/such/dir"]).starts_with("DATA_DIR: "));
+        assert!(error(&[]).contains("No data directory"));
+        assert!(Config::from_args(&["--help".to_string()]).unwrap().is_none());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let file = TempFile::new(&format!("\
+            data_dir = \"{}\"  # a comment
+            bind = [\"127.0.0.1:7001\", \"127.0.0.1:7002\"]
+
+            [limits]
+            workers = 3
+            fetch_max = 50
+        ", dir()));
+
+        let config = parse(&["--config", file.path()]).unwrap();
+        assert_eq!(config.binds.len(), 2);
+        assert_eq!((config.limits.workers, config.limits.fetch_max), (3, 50));
+
+        // flags win, wherever the file is given; binds are replaced, not added to
+        let config = parse(&["--workers", "5", "-b", "127.0.0.1:7003", "-c", file.path()]).unwrap();
+        assert_eq!(config.binds, [address("127.0.0.1:7003").unwrap()]);
+        assert_eq!((config.limits.workers, config.limits.fetch_max), (5, 50));
+        let config = parse(&["-c", file.path(), "127.0.0.1:7004", &dir()]).unwrap();
+        assert_eq!(config.binds, [address("127.0.0.1:7004").unwrap()]);
+
+        let e = error(&["--workers", "0", "-c", file.path()]);
+        assert_eq!(e, "--workers: expected a whole number from 1 to 1024, got \"0\"");
+        assert_eq!(error(&["--workers"]), "--workers: missing value");
+        assert!(error(&["--wrokers", "5"]).starts_with("Unknown option --wrokers"));
+    }
+
+    #[test]
+    fn file_errors_say_where() {
+        let file = TempFile::new("[limits]\nworkers = 3\nidle_timeout = soon\n");
+        let e = error(&["-c", file.path(), &dir()]);
+        let at = format!("{}:3: limits.idle_timeout: ", file.path());
+        assert_eq!(e, at + "expected a whole number from 1 to 86400, got \"soon\"");
+        let file = TempFile::new("workers\n");
+        assert!(error(&["-c", file.path()]).ends_with(":1: expected `key = value`, got \"workers\""));
+        let file = TempFile::new("[limit]\nworkers = 3\n");
+        assert!(error(&["-c", file.path()]).ends_with(":2: limit.workers: unknown setting"));
+        assert!(error(&["-c", "/no/such/file"]).starts_with("Can't read config file"));
+    }
+
+    #[test]
+    fn settings_that_go_together() {
+        assert!(error(&["--fetch-default", "60", "--fetch-max", "50", &dir()]).contains("can't be above"));
+        assert!(error(&["--store", "memory", "--keep-days", "1"]).contains("Retention only applies"));
+        assert!(parse(&["--store", "memory"]).is_ok());  // needs no data directory
+
+        let file = TempFile::new("");  // any file passes until it's loaded
+        assert!(error(&["--tls-bind", "127.0.0.1:7005", &dir()]).contains("need both tls.cert and tls.key"));
+        assert!(error(&["--tls-cert", file.path(), "--tls-key", file.path(), &dir()]).contains("need TLS addresses"));
+        assert!(error(&["--tls-key", file.path(), &dir()]).contains("need TLS addresses"));
+    }
+}
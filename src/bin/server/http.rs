@@ -1,11 +1,11 @@
-use std::{net::TcpStream, sync::Arc, io::Read};
+use std::{sync::Arc, io::Write};
 
-use crate::smrt;
-use crate::ws::WsStream;
+use crate::ws;
 
 use publichat::helpers::*;
+use publichat::error::Error;
 
-fn send_code(code: u16, stream: &mut TcpStream) -> Res {
+pub fn send_code(code: u16, stream: &mut impl Write) -> Res {
     full_write(
         stream,
         format!("HTTP/1.1 {}\r\n\r\n", code).as_bytes(),
@@ -13,7 +13,7 @@ fn send_code(code: u16, stream: &mut TcpStream) -> Res {
     )
 }
 
-fn send_data(code: u16, data: &[u8], stream: &mut TcpStream) -> Res {
+fn send_data(code: u16, data: &[u8], stream: &mut impl Write, err: &'static str) -> Res {
     let header_string = format!(
         "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
         code,
@@ -23,11 +23,11 @@ fn send_data(code: u16, data: &[u8], stream: &mut TcpStream) -> Res {
     full_write(
         stream,
         &[header_string.as_bytes(), data].concat(),
-        "Failed to send file",
+        err,
     )
 }
 
-fn handle_robots(stream: &mut TcpStream) -> Res {
+fn handle_robots(stream: &mut impl Write) -> Res {
     const RESP_ROBOTS: &[u8] = b"\
         HTTP/1.1 200\r\n\
         Content-Length: 25\r\n\r\n\
@@ -36,59 +36,62 @@ fn handle_robots(stream: &mut TcpStream) -> Res {
     full_write(stream, RESP_ROBOTS, "Failed to send robots")
 }
 
-fn handle_version(stream: &mut TcpStream, globals: &Arc<Globals>) -> Res {
+fn handle_version(stream: &mut impl Write, globals: &Arc<Globals>) -> Res {
     // TODO: avoid allocations to pre-building packet?
-    // TODO: should functions like this map_err to show where the issue is?
-    send_data(200, &globals.git_hash, stream)
-        .map_err(|_| "Failed to send version")
+    send_data(200, &globals.git_hash, stream, "Failed to send version")
 }
 
-fn handle_ws(req: &str, mut stream: TcpStream, globals: &Arc<Globals>) -> Res {
-    // handshake
+fn handle_ws(req: &str, stream: &mut impl Write) -> Res {
+    // handshake; the caller carries on with SMRT over WS
     let key_in = match req.split("Sec-WebSocket-Key: ").nth(1) {
         Some(val) => &val[..24],
         _ => {
-            send_code(400, &mut stream)?;
-            return Err("Couldn't find WS key");
+            send_code(400, stream)?;
+            return Err(Error::protocol("Couldn't find WS key"));
         },
     };
-    WsStream::handshake(&mut stream, key_in)?;
-
-    // launch SMRT
-    let mut stream = WsStream::new(stream);
-    smrt::handle(&mut stream, globals)
+    ws::handshake(stream, key_in)
 }
 
-pub fn handle(mut stream: TcpStream, globals: &Arc<Globals>) -> Res {
-    // Handles GET requests
-    let mut buf = [0; 1024];  // todo: think more about sizes
-    stream.read(&mut buf).map_err(|_| "Failed to read HTTP packet")?;
-    let req = std::str::from_utf8(&buf).map_err(|_| "Recieved non-utf8 HTTP")?;
+const MAX_REQUEST: usize = 1024;  // todo: think more about sizes
 
-    if !req.ends_with("\0\0\0\0\0\0\0\0") {
-        // Received HTTP packet was (probably) bigger than 1 KiB
-        send_code(413, &mut stream)?;
-        return Err("Received very large HTTP packet; aborted.")
+pub fn request_len(buf: &[u8]) -> Option<usize> {
+    // Length of the request at the start of buf, once all of it has
+    // arrived. Anything too long counts as arrived, to be refused.
+    match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) if end + 4 <= MAX_REQUEST => Some(end + 4),
+        _ if buf.len() >= MAX_REQUEST => Some(MAX_REQUEST),
+        _ => None,
     }
+}
+
+pub fn handle(req: &[u8], stream: &mut impl Write, globals: &Arc<Globals>) -> Res<bool> {
+    // Handles GET requests; returns whether the connection is now a WS
+    if !req.ends_with(b"\r\n\r\n") {
+        // Received HTTP packet was bigger than 1 KiB
+        send_code(413, stream)?;
+        return Err(Error::limit("Received very large HTTP packet; aborted."))
+    }
+    let req = std::str::from_utf8(req).map_err(|_| Error::protocol("Recieved non-utf8 HTTP"))?;
 
     let path = match req.split(' ').nth(1) {  // path is 2nd word of GET
         Some(p) => p,
-        None => return Err("Failed to find HTTP path"),  // faulty HTTP
+        None => return Err(Error::protocol("Failed to find HTTP path")),  // faulty HTTP
     };
 
     match path {
-        "/" | ""         => send_data(200, FILE_INDEX_HTML, &mut stream)
-            .map_err(|_| "Failed to send index.html"),
-        "/favicon.ico"   => send_data(200, FILE_FAVICON_ICO, &mut stream)
-            .map_err(|_| "Failed to send favicon"),
-        "/mobile" | "/m" => send_data(200, FILE_MOBILE_HTML, &mut stream)
-            .map_err(|_| "Failed to send mobile.html"),
-        "/ws"            => handle_ws(req, stream, globals),  // start WS
-        "/robots.txt"    => handle_robots(&mut stream),
-        "/tools"         => send_data(200, FILE_TOOLS_HTML, &mut stream)
-            .map_err(|_| "Failed to send 404"),
-        "/version"       => handle_version(&mut stream, globals),
-        _                => send_data(404, FILE_404_HTML, &mut stream)
-            .map_err(|_| "Failed to send 404"),
-    }
+        "/" | ""         => send_data(200, FILE_INDEX_HTML, stream,
+            "Failed to send index.html"),
+        "/favicon.ico"   => send_data(200, FILE_FAVICON_ICO, stream,
+            "Failed to send favicon"),
+        "/mobile" | "/m" => send_data(200, FILE_MOBILE_HTML, stream,
+            "Failed to send mobile.html"),
+        "/ws"            => return handle_ws(req, stream).map(|_| true),  // start WS
+        "/robots.txt"    => handle_robots(stream),
+        "/tools"         => send_data(200, FILE_TOOLS_HTML, stream,
+            "Failed to send tools.html"),
+        "/version"       => handle_version(stream, globals),
+        _                => send_data(404, FILE_404_HTML, stream,
+            "Failed to send 404"),
+    }.map(|_| false)
 }
@@ -1,70 +1,106 @@
-use std::{net::{TcpListener, TcpStream, ToSocketAddrs}, path::Path, sync::Arc, thread::{self, Builder}};
+use std::{
+    net::{TcpListener, TcpStream, Shutdown},
+    sync::{Arc, Mutex, mpsc},
+    thread::Builder,
+    collections::HashMap,
+    time::Duration,
+};
 
-mod db;
 mod http;
 mod smrt;
 mod ws;
+mod wire;
+mod reactor;
+use reactor::Reactor;
+mod config;
+use config::{Backend, Config, HELP};
+mod retention;
 
-use publichat::helpers::*;
-
-const IP_PORT_DEFAULT: &str = "localhost:7878";
-
+use rustls::ServerConfig;
 
-fn handle_incoming(mut stream: TcpStream, globals: &Arc<Globals>) -> Res {
-    let mut pad_buf = [0; 4];
-
-    let mut http_handled: u8 = 0;
-    while {  // Handle repeated HTTP requests
-        stream.peek(&mut pad_buf)
-            .map_err(|_| "Failed to read protocol header (HTTP timeout?)")?;
-        &pad_buf == b"GET "
-    } {
-        http::handle(
-            stream.try_clone().map_err(|_| "Failed to clone")?,
-            globals
-        )?;
-
-        http_handled += 1;  // TODO: better system for dropping connections
-        if http_handled >= 3 {
-            stream.shutdown(std::net::Shutdown::Both)
-                .map_err(|_| "HTTP shutdown failed")?;
-            return Ok(());
-        }
-        if http_handled == 1 {
-            stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))
-                .map_err(|_| "Failed to set short timeout")?;
+use publichat::{db, log};
+use publichat::helpers::*;
+use publichat::constants::ERR_SERVER_BUSY;
+use publichat::store::{ChatStore, Memory};
+
+const REJECT_QUEUE_SIZE: usize = 16;  // rejections beyond this are just dropped
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+fn rejecter(rx: mpsc::Receiver<(TcpStream, &'static str)>) {
+    // tells HTTP and SMRT clients to come back later. TLS ones
+    // just get closed (no handshake, not worth the effort).
+    for (mut stream, reason) in rx {
+        let _ = stream.set_read_timeout(Some(REJECT_TIMEOUT));
+        let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT));
+        let mut pad_buf = [0; 4];
+        if matches!(stream.peek(&mut pad_buf), Ok(4)) {
+            let _ = match &pad_buf {
+                b"GET " => http::send_code(503, &mut stream),
+                b"SMRT" => smrt::send_error(&mut stream, ERR_SERVER_BUSY, reason),
+                _ => Ok(()),
+            };
         }
+        let _ = stream.shutdown(Shutdown::Both);
     }
+}
+
+fn accept(
+    listener: &TcpListener,
+    globals: &Arc<Globals>,
+    reactor: &Reactor,
+    reject_tx: &mpsc::SyncSender<(TcpStream, &'static str)>,
+    tls: Option<&Arc<ServerConfig>>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => { log!(Info, "failed to bind stream: {e}"); continue },
+        };
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => continue,  // already gone
+        };
 
-    // HTTP finished. Read either SMRT or fail.
-    if &pad_buf == b"SMRT" {
-        read_exact(&mut stream, &mut pad_buf, "Failed to remove SMRT buffer")?;
-        smrt::handle(stream, globals)
-    } else {
-        Err("Failed to match protocol header")
+        if let Err(reason) = reactor::claim(globals, addr.ip()) {
+            log!(Info, "Rejected {addr}: {reason}");
+            let _ = reject_tx.try_send((stream, reason));  // if full, dropping closes it
+            continue;
+        }
+        log!(Debug, "Handling {addr}");
+        reactor.add(stream, addr, tls);
     }
 }
 
 fn main() {
     let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match Config::from_args(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => { print!("{HELP}"); return },
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        },
+    };
+    log::init(config.log_level, config.log_file);
+
+    let files = match config.backend {
+        Backend::Files => {
+            let data_dir = config.data_dir.unwrap();  // checked by config
+            log!(Info, "Using directory {:?}", data_dir.canonicalize().unwrap());
+            Some(Arc::new(db::Files::new(data_dir, config.layout, &config.limits)))
+        },
+        Backend::Memory => {
+            log!(Info, "Keeping chats in memory; they are gone when the server stops");
+            None
+        },
+    };
 
     let globals = {
-
-        // Get chat directory path (last argument)
-        let data_dir = {
-            if let Some(path) = args.last() {
-                let path = Path::new(path);
-                if !path.is_dir() {
-                    println!("Not a directory: {:?}", path);
-                    std::process::exit(1);
-                }
-                path.to_path_buf()  // put on heap
-            } else {
-                println!("No path given");
-                std::process::exit(1);
-            }
+        let store: Arc<dyn ChatStore> = match &files {
+            Some(files) => files.clone(),
+            None => Arc::new(Memory::default()),
         };
-        println!("Using directory {:?}", data_dir.canonicalize().unwrap());
 
         // Get git hash
         let git_hash = {
@@ -72,7 +108,7 @@ fn main() {
                 .args(["rev-parse", "HEAD"])
                 .output()
                 .unwrap_or_else(|e| {
-                    println!("Failed to exec git command:\n\t{e}");
+                    log!(Error, "Failed to exec git command:\n\t{e}");
                     std::process::exit(1);
                 });
 
@@ -80,7 +116,7 @@ fn main() {
             if git_output.len() != 41
                 || *git_output.last().unwrap() != b'\n'
                 || std::str::from_utf8(&git_output).is_err() {
-                    println!("Received strange data from git");
+                    log!(Error, "Received strange data from git");
                     std::process::exit(1);
                 }
 
@@ -88,68 +124,89 @@ fn main() {
             git_hash.copy_from_slice(&git_output[..40]);
             git_hash
         };
-        println!("Using git hash {}", std::str::from_utf8(&git_hash).unwrap());
+        log!(Info, "Using git hash {}", std::str::from_utf8(&git_hash).unwrap());
 
         Arc::new(Globals {
-            data_dir,
+            store,
             git_hash,
+            limits: config.limits,
+            subs: Mutex::new(HashMap::new()),
+            conns: Mutex::new(HashMap::new()),
+            recent: Mutex::new(Recent::default()),
+            buckets: Mutex::new(HashMap::new()),
         })
     };
 
-    let listener = {
-        // try to get address from second-to-last arg
-        let addr = args.iter().rev().nth(1).ok_or("Address not given in args")
-            .and_then(|arg| arg.to_socket_addrs()
-                .map_err(|e| {println!("\t{e}"); "Invalid addr, see above"}))
-            .and_then(|mut addrs| addrs.next().ok_or("Empty addr iterator?"))
-            .unwrap_or_else(|e| {
-                println!("{e}; using default socket address...");
-                IP_PORT_DEFAULT.to_socket_addrs().ok()
-                    .and_then(|mut addrs| addrs.next())
-                    .unwrap_or_else(|| {
-                        println!("Failed to create socket address from default!");
-                        println!("Why is {IP_PORT_DEFAULT} an invalid socket addr?");
-                        std::process::exit(1);
-                    })
-            });
-
-        TcpListener::bind(addr).unwrap_or_else(|e| {
-            println!("Failed to bind TCP address {addr}:\n\t{e}");
+    // bind everything before starting anything
+    let plain = config.binds.iter().map(|addr| (addr, None));
+    let tls = config.tls_binds.iter().map(|addr| (addr, config.tls.clone()));
+    let listeners: Vec<_> = plain.chain(tls).map(|(addr, tls)| {
+        let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+            log!(Error, "Failed to bind TCP address {addr}:\n\t{e}");
             std::process::exit(1);
-        })
-    };
-    println!("Running on {}", listener.local_addr().unwrap());
-
-    for stream in listener.incoming() {
-        if let Ok(stream) = stream {
-            let globals = globals.clone();
+        });
+        let proto = if tls.is_some() { " (TLS)" } else { "" };
+        log!(Info, "Running on {}{proto}", listener.local_addr().unwrap());
+        (listener, tls)
+    }).collect();
+
+    if let Some(files) = files {
+        db::init(config.fsync).unwrap_or_else(|e| {
+            log!(Error, "Failed to create fsync thread: {e}");
+            std::process::exit(1);
+        });
+        if config.retention.enabled() {
+            retention::start(files, config.retention).unwrap_or_else(|e| {
+                log!(Error, "Failed to create retention thread: {e}");
+                std::process::exit(1);
+            });
+        }
+    }
 
-            let name = match stream.peer_addr() {
-                Ok(addr) => addr.to_string(),
-                Err(_) => "unknown".to_string(),
-            };
+    let reactor = Arc::new(Reactor::new(globals.clone()).unwrap_or_else(|e| {
+        log!(Error, "{e}");
+        std::process::exit(1);
+    }));
+    let (work_tx, work_rx) = mpsc::channel();
 
-            let builder = Builder::new().name(name);  // todo: stack size?
-            let handle = builder.spawn(move || {
-                println!("Handling {}", thread::current().name().unwrap());
-                if let Err(e) = handle_incoming(stream, &globals) {
-                    println!(
-                        "Finished {} with:\n\t{e}",
-                        thread::current().name().unwrap(),
-                    );
-                } else {
-                    println!(
-                        "Finished {} (no message)",
-                        thread::current().name().unwrap(),
-                    );
-                }
+    let reactor_c = reactor.clone();
+    Builder::new().name("poller".to_string()).spawn(move || reactor_c.run(&work_tx))
+        .unwrap_or_else(|e| {
+            log!(Error, "Failed to create poller thread: {e}");
+            std::process::exit(1);
+        });
+
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    for i in 0..globals.limits.workers {
+        let (reactor, work_rx) = (reactor.clone(), work_rx.clone());
+        Builder::new()
+            .name(format!("worker-{i}"))  // todo: stack size?
+            .spawn(move || reactor.work(&work_rx))
+            .unwrap_or_else(|e| {
+                log!(Error, "Failed to create worker thread: {e}");
+                std::process::exit(1);
             });
+    }
+    log!(Info, "Started {} workers", globals.limits.workers);
 
-            if let Err(e) = handle {
-                println!("Failed to create thread: {e}");
-            }
-        } else {
-            println!("failed to bind stream: {}", stream.err().unwrap());
-        }
+    let (reject_tx, reject_rx) = mpsc::sync_channel(REJECT_QUEUE_SIZE);
+    Builder::new().name("rejecter".to_string()).spawn(move || rejecter(reject_rx))
+        .unwrap_or_else(|e| {
+            log!(Error, "Failed to create rejecter thread: {e}");
+            std::process::exit(1);
+        });
+
+    // one accept loop per listener; the last one runs on this thread
+    let mut listeners = listeners;
+    let (last, last_tls) = listeners.pop().unwrap();  // config gives at least one
+    for (listener, tls) in listeners {
+        let (globals, reactor, reject_tx) = (globals.clone(), reactor.clone(), reject_tx.clone());
+        Builder::new().name("acceptor".to_string())
+            .spawn(move || accept(&listener, &globals, &reactor, &reject_tx, tls.as_ref()))
+            .unwrap_or_else(|e| {
+                log!(Error, "Failed to create acceptor thread: {e}");
+                std::process::exit(1);
+            });
     }
+    accept(&last, &globals, &reactor, &reject_tx, last_tls.as_ref());
 }
@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    net::{TcpStream, SocketAddr, IpAddr},
+    sync::{Arc, Mutex, mpsc, atomic::{AtomicUsize, Ordering}},
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use polling::{Poller, Events};
+use rustls::{ServerConfig, ServerConnection};
+
+use crate::{http, smrt, ws::{self, Frame, WsWriter}, wire::{Wire, Outbox}};
+
+use publichat::log;
+use publichat::helpers::*;
+use publichat::error::{Error, Kind};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);  // how often idle sockets are dropped
+const READS_PER_STEP: usize = 16;  // chunks; then others get a turn
+
+enum State {
+    Fresh(u8),  // protocol not known yet; number of HTTP requests handled
+    Smrt(smrt::Session),
+    Ws(Vec<u8>, smrt::Session),  // SMRT data from frames, short of a whole packet
+}
+
+pub struct Conn {
+    wire: Arc<Mutex<Wire>>,  // shared with subscriptions pushing to it
+    addr: SocketAddr,
+    key: usize,  // poller key
+    state: State,
+    input: Vec<u8>,  // arrived, but short of a whole request/packet/frame
+    started: Option<Instant>,  // when the unfinished input began
+    last_active: Instant,
+    closing: bool,  // done; only the rest of the output is left to go
+}
+
+impl Conn {
+    fn new(
+        tcp: TcpStream,
+        addr: SocketAddr,
+        key: usize,
+        poller: Arc<Poller>,
+        tls: Option<&Arc<ServerConfig>>,
+    ) -> Res<Self> {
+        let tls = match tls {
+            Some(config) => Some(ServerConnection::new(config.clone())
+                .map_err(|e| Error::with_source(Kind::Crypto, "Failed to start TLS", e))?),
+            None => None,
+        };
+        let wire = Wire::new(tcp, tls, poller, key)
+            .map_err(|e| Error::io("Failed to make socket non-blocking", e))?;
+        Ok(Self {
+            wire: Arc::new(Mutex::new(wire)),
+            addr,
+            key,
+            state: State::Fresh(0),
+            input: Vec::new(),
+            started: None,
+            last_active: Instant::now(),
+            closing: false,
+        })
+    }
+
+    fn report(&self, e: &Error) {
+        // only SMRT speakers understand error packets
+        if let State::Smrt(session) | State::Ws(_, session) = &self.state {
+            session.report(e);
+        }
+    }
+
+    fn overdue(&self, now: Instant, limits: &Limits) -> Option<&'static str> {
+        // why the connection should be dropped, if it should
+        let idle_limit = match self.state {
+            State::Fresh(_) => limits.read_timeout,  // say something or leave
+            _ => limits.idle_timeout,
+        };
+        let waiting = Wire::lock(&self.wire).ok()?.waiting();
+        if waiting.is_some_and(|since| now - since > limits.write_timeout) {
+            Some("Too slow to read")
+        } else if self.started.is_some_and(|since| now - since > limits.read_timeout) {
+            Some("Too slow to finish a request")
+        } else if !self.closing && now - self.last_active > idle_limit {
+            Some("Idle for too long")
+        } else {
+            None
+        }
+    }
+
+    fn step(&mut self, globals: &Arc<Globals>) -> Res<bool> {
+        // handles whatever the socket became ready for.
+        // Returns false once the connection should be closed.
+        let mut wire = Wire::lock(&self.wire).map_err(|e| Error::io("Failed to lock wire", e))?;
+        wire.flush().map_err(|e| Error::io("Failed to send", e))?;
+        if self.closing { return Ok(wire.pending()) }
+        drop(wire);  // handling packets writes to it
+
+        for _ in 0..READS_PER_STEP {
+            let read = Wire::lock(&self.wire)
+                .and_then(|mut wire| wire.fill(&mut self.input))
+                .map_err(|e| Error::io("Failed to read", e))?;
+            let Some(read) = read else {
+                self.handle(globals)?;  // answer what came before the hang up
+                return Ok(false);
+            };
+            if read == 0 { break }
+            self.last_active = Instant::now();
+            if !self.handle(globals)? { return Ok(false) }
+        }
+        Ok(true)
+    }
+
+    fn handle(&mut self, globals: &Arc<Globals>) -> Res<bool> {
+        // answers every whole request/packet in input.
+        // Returns false once the connection should be closed.
+        loop {
+            match &mut self.state {
+                State::Fresh(http_handled) => {
+                    let Some(pad_buf) = self.input.get(..4) else { break };
+                    if pad_buf == b"GET " {
+                        let Some(len) = http::request_len(&self.input) else { break };
+                        *http_handled += 1;  // TODO: better system for dropping connections
+                        let keep = *http_handled < 3;
+                        let req: Vec<u8> = self.input.drain(..len).collect();
+                        if http::handle(&req, &mut Outbox::new(&self.wire), globals)? {
+                            let writer = WsWriter(Outbox::new(&self.wire));
+                            self.state = State::Ws(Vec::new(), smrt::Session::new(writer, self.addr.ip()));
+                        } else if !keep {
+                            return Ok(false);
+                        }
+                    } else if pad_buf == b"SMRT" {
+                        self.input.drain(..4);
+                        let writer = Outbox::new(&self.wire);
+                        self.state = State::Smrt(smrt::Session::new(writer, self.addr.ip()));
+                    } else {
+                        return Err(Error::protocol("Failed to match protocol header"));
+                    }
+                },
+                State::Smrt(session) => {
+                    let Some(len) = smrt::packet_len(&self.input, session)? else { break };
+                    smrt::handle_packet(&mut &self.input[..len], session, globals)?;
+                    self.input.drain(..len);
+                },
+                State::Ws(data, session) => {
+                    if let Some(len) = smrt::packet_len(data, session)? {
+                        smrt::handle_packet(&mut &data[..len], session, globals)?;
+                        data.drain(..len);
+                        continue;
+                    }
+                    let Some((frame, len)) = ws::frame(&self.input)? else { break };
+                    self.input.drain(..len);
+                    match frame {
+                        Frame::Data(payload) => data.extend_from_slice(&payload),
+                        Frame::Ping(payload) => full_write(
+                            &mut Outbox::new(&self.wire),
+                            &ws::pong(&payload),
+                            "Failed to send pong",
+                        )?,
+                        Frame::Close => return Ok(false),
+                    }
+                },
+            }
+        }
+
+        let unfinished = !self.input.is_empty() || matches!(&self.state, State::Ws(data, _) if !data.is_empty());
+        self.started = match unfinished {
+            true => Some(self.started.unwrap_or_else(Instant::now)),
+            false => None,
+        };
+        Ok(true)
+    }
+}
+
+pub fn claim(globals: &Globals, ip: IpAddr) -> Result<(), &'static str> {
+    // reserves room for a new connection from ip, if allowed
+    let mut conns = globals.conns.lock().unwrap();  // only poisoned by panics in here
+    if conns.values().sum::<usize>() >= globals.limits.connections {
+        return Err("server full");
+    }
+    let count = conns.entry(ip).or_default();
+    if *count >= globals.limits.per_ip && !ip.is_loopback() {  // local is a proxy (or us)
+        return Err("too many connections from address");
+    }
+    *count += 1;
+    Ok(())
+}
+
+fn release(globals: &Globals, ip: IpAddr) {
+    let mut conns = globals.conns.lock().unwrap();
+    if let Some(count) = conns.get_mut(&ip) {
+        *count -= 1;
+        if *count == 0 { conns.remove(&ip); }
+    }
+}
+
+// Connections waiting for data are parked here and cost no thread.
+// When one becomes ready, the poller thread hands it to a worker, which
+// handles what has arrived and parks it again. Nothing blocks: partial
+// requests wait in the connection, and output waits in its wire.
+pub struct Reactor {
+    poller: Arc<Poller>,  // wires re-arm themselves when output is left
+    parked: Mutex<HashMap<usize, Conn>>,  // poller key -> connection
+    next_key: AtomicUsize,
+    globals: Arc<Globals>,
+}
+
+impl Reactor {
+    pub fn new(globals: Arc<Globals>) -> Res<Self> {
+        Ok(Self {
+            poller: Arc::new(Poller::new().map_err(|e| Error::io("Failed to create poller", e))?),
+            parked: Mutex::new(HashMap::new()),
+            next_key: AtomicUsize::new(0),
+            globals,
+        })
+    }
+
+    pub fn add(&self, tcp: TcpStream, addr: SocketAddr, tls: Option<&Arc<ServerConfig>>) {
+        // takes over a freshly claimed connection
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        match Conn::new(tcp, addr, key, self.poller.clone(), tls) {
+            Ok(conn) => self.park(conn),
+            Err(e) => {
+                release(&self.globals, addr.ip());
+                log!(Info, "Failed to set up {addr}:\n\t{e}");
+            },
+        }
+    }
+
+    fn park(&self, conn: Conn) {
+        // waits for conn to have something to say, or room for what it
+        // has to send. Polling is oneshot, so each park re-arms it once.
+        let (key, read, wire) = (conn.key, !conn.closing, conn.wire.clone());
+
+        // insert first: the event may fire before add/modify return
+        let mut parked = self.parked.lock().unwrap();
+        parked.insert(key, conn);
+        let res = Wire::lock(&wire).and_then(|mut wire| wire.park(read));
+
+        if let Err(e) = res {
+            let conn = parked.remove(&key).unwrap();  // just put it there
+            drop(parked);
+            self.close(conn, Err(Error::io("Failed to park connection", e)));
+        }
+    }
+
+    fn finish(&self, mut conn: Conn, res: Res) {
+        // closes conn once everything sent to it has gone out
+        let pending = Wire::lock(&conn.wire).is_ok_and(|wire| wire.pending());
+        if res.is_ok() && pending {
+            conn.closing = true;
+            self.park(conn);
+        } else {
+            self.close(conn, res);
+        }
+    }
+
+    fn close(&self, conn: Conn, res: Res) {
+        let Conn { wire, addr, state, .. } = conn;
+
+        // first, so pushers drop the session instead of queueing for it
+        if let Ok(mut wire) = Wire::lock(&wire) { wire.close() }
+        let closed = match state {
+            State::Smrt(session) | State::Ws(_, session) => session.close(&self.globals),
+            State::Fresh(_) => Ok(()),
+        };
+        release(&self.globals, addr.ip());
+
+        match res.and(closed) {
+            Ok(()) => log!(Debug, "Finished {addr} (no message)"),
+            Err(e) if e.kind() == Kind::Disconnected => log!(Debug, "Finished {addr} (disconnected)"),
+            Err(e) => log!(Info, "Finished {addr} with:\n\t{e}"),
+        }
+    }
+
+    fn sweep(&self) {
+        // drops connections that have been quiet or slow for too long
+        let now = Instant::now();
+        let overdue: Vec<(Conn, &str)> = {
+            let mut parked = self.parked.lock().unwrap();
+            let keys: Vec<(usize, &str)> = parked.iter()
+                .filter_map(|(&key, c)| Some((key, c.overdue(now, &self.globals.limits)?)))
+                .collect();
+            keys.into_iter().filter_map(|(key, why)| Some((parked.remove(&key)?, why))).collect()
+        };
+        for (conn, why) in overdue {
+            self.close(conn, Err(Error::limit(why)));
+        }
+    }
+
+    pub fn run(&self, work_tx: &mpsc::Sender<Conn>) {
+        // poller thread: hands ready connections to the workers
+        let mut events = Events::new();
+        let mut last_sweep = Instant::now();
+        loop {
+            events.clear();
+            if let Err(e) = self.poller.wait(&mut events, Some(SWEEP_INTERVAL)) {
+                log!(Error, "Poller failed: {e}");  // probably interrupted; try again
+                continue;
+            }
+
+            let mut parked = self.parked.lock().unwrap();
+            for event in events.iter() {
+                if let Some(conn) = parked.remove(&event.key) {
+                    if work_tx.send(conn).is_err() { return }  // workers are gone
+                }
+            }
+            drop(parked);
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.sweep();
+                last_sweep = Instant::now();
+            }
+        }
+    }
+
+    pub fn work(&self, rx: &Mutex<mpsc::Receiver<Conn>>) {
+        // worker thread: whatever arrived on one ready connection
+        loop {
+            // only one idle worker waits on the channel, the rest on the lock
+            let mut conn = match rx.lock().map(|rx| rx.recv()) {
+                Ok(Ok(conn)) => conn,
+                _ => return,  // poller is gone
+            };
+
+            // a panic must not cost us a worker
+            let res = panic::catch_unwind(AssertUnwindSafe(|| conn.step(&self.globals)))
+                .unwrap_or_else(|_| Err(Error::internal("Panicked handling connection")));
+
+            match res {
+                Ok(true) => self.park(conn),
+                Ok(false) => self.finish(conn, Ok(())),
+                Err(e) => {
+                    conn.report(&e);
+                    self.finish(conn, Err(e));
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Background compaction: forgets old messages by the configured rules.
+// Ids stay as they are; clients just can't go back as far anymore.
+
+use std::{sync::Arc, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use publichat::{db, log};
+
+use publichat::helpers::Res;
+
+pub struct Retention {
+    pub max_messages: u64,  // kept per chat; 0: all
+    pub max_age: Duration,  // zero: forever
+    pub disk_budget: u64,  // bytes over all chats; 0: no limit
+    pub interval: Duration,  // between compactions
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_messages: 0,
+            max_age: Duration::ZERO,
+            disk_budget: 0,
+            interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl Retention {
+    pub fn enabled(&self) -> bool {
+        self.max_messages > 0 || !self.max_age.is_zero() || self.disk_budget > 0
+    }
+}
+
+fn compact(files: &db::Files, rules: &Retention) -> Res<u64> {
+    // Returns bytes freed. A chat that fails is logged and skipped.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let min_time = match rules.max_age.is_zero() {
+        true => 0,
+        false => now.saturating_sub(rules.max_age).as_millis() as u64,  // fits for a while
+    };
+
+    let chats = files.chats()?;
+    let mut freed = 0;
+    for chat in &chats {
+        match files.retain(chat, rules.max_messages, min_time) {
+            Ok(n) => freed += n,
+            Err(e) => log!(Error, "Failed to apply retention to {}:\n\t{e}", files.path(chat).display()),
+        }
+    }
+    if rules.disk_budget == 0 { return Ok(freed) }
+
+    // over budget: oldest segments go first, whichever chat they are in
+    let mut used = 0;
+    let mut droppable = Vec::new();  // (time, size, chat)
+    for (i, chat) in chats.iter().enumerate() {
+        let segments = match files.segments(chat) {
+            Ok(segments) => segments,
+            Err(e) => { log!(Error, "Failed to size {}:\n\t{e}", files.path(chat).display()); continue },
+        };
+        used += segments.iter().map(|(_, size)| size).sum::<u64>();
+        let last = segments.len().saturating_sub(1);  // still written to; stays
+        droppable.extend(segments[..last].iter().map(|&(time, size)| (time, size, i)));
+    }
+    droppable.sort_unstable();
+
+    let mut drop = vec![0; chats.len()];  // oldest segments to drop, per chat
+    for (_, size, i) in droppable {
+        if used <= rules.disk_budget { break }
+        used -= size;
+        drop[i] += 1;
+    }
+    for (chat, count) in chats.iter().zip(drop) {
+        if count == 0 { continue }
+        match files.drop_segments(chat, count) {
+            Ok(n) => freed += n,
+            Err(e) => log!(Error, "Failed to drop segments of {}:\n\t{e}", files.path(chat).display()),
+        }
+    }
+    if used > rules.disk_budget {
+        log!(Info, "Over disk budget by {} MiB; only the newest segment of each chat is left", (used - rules.disk_budget) >> 20);
+    }
+    Ok(freed)
+}
+
+pub fn start(files: Arc<db::Files>, rules: Retention) -> std::io::Result<()> {
+    thread::Builder::new().name("retention".to_string()).spawn(move || loop {
+        match compact(&files, &rules) {
+            Ok(0) => log!(Debug, "Retention: nothing to free"),
+            Ok(freed) => log!(Info, "Retention: freed {} KiB", freed >> 10),
+            Err(e) => log!(Error, "Retention failed:\n\t{e}"),
+        }
+        thread::sleep(rules.interval);
+    }).map(drop)
+}
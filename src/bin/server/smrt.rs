@@ -1,21 +1,42 @@
-use std::{sync::Arc, io::{Read, Write}, path::Path, convert::TryInto};
-
-use crate::db::{
-    self,
-    MAX_FETCH_AMOUNT,
-    DEFAULT_FETCH_AMOUNT,
-};
+use std::{sync::{Arc, Mutex}, io::{Read, Write}, convert::TryInto};
+use std::{net::IpAddr, time::Instant};
 
+use publichat::log;
 use publichat::helpers::*;
+use publichat::constants::*;
+use publichat::error::{Error, Kind};
 use publichat::buffers::{
     pad,
     msg_head,
-    hash::{self, Buf as HashBuf}, 
+    msg_head_wide,
+    hash::{self, Buf as HashBuf},
+    stamp::{self, Buf as StampBuf},
     qry_arg::{self, Buf as QryArgBuf},
+    fetch,
+    fetch_v1,
+    query,
+    query_wide,
+    time_query,
+    sub,
+    unsub,
+    hello,
+    hello_out,
+    err_head,
+    ack,
+    msg_in_c,
+    msg_in_c_v2,
     msg_in_s::{self as msg_in, Buf as MsgInBuf},
     msg_out_s::{self as msg_out, Buf as MsgStBuf},
 };
 
+const REPLAY_WINDOW_MS: u64 = 10 * 60 * 1000;  // resends after this are new messages
+const REPLAY_MAX: usize = 256;  // remembered sends per chat
+
+const BUCKETS_PRUNE_AT: usize = 4096;  // full buckets are only forgotten past this
+
+const FEATURES: u32 =  // all this server does
+    FEATURE_FETCH_COUNT | FEATURE_TIME_QUERY | FEATURE_SEND_ACK | FEATURE_WIDE_IDS;
+
 fn query_bytes_to_args(data: &QryArgBuf) -> (u32, u8, bool) {
     let forward = data[0] & 0x80 != 0;  // check first bit
     let count = data[0] & 0x7f; // take the last 7 bits
@@ -23,19 +44,31 @@ fn query_bytes_to_args(data: &QryArgBuf) -> (u32, u8, bool) {
     (id, count, forward)
 }
 
-fn get_chat_file(chat_id: &HashBuf, data_dir: &Path) -> std::path::PathBuf {
-    // encode hash into b64 and append to data_dir
-    use base64::{Config, CharacterSet::UrlSafe};
-    data_dir.join(base64::encode_config(chat_id, Config::new(UrlSafe, false)))
+const WIRE_ID_END: u64 = 1 << (8 * MSG_ID_SIZE);  // without wide ids, the wire only fits three bytes
+
+fn wire_id(msg_id: u64, wide: bool) -> Res<Vec<u8>> {
+    // the id as sent: all 8 bytes with wide ids, else the last three
+    let bytes = msg_id.to_be_bytes();
+    if wide { return Ok(bytes.to_vec()) }
+    if msg_id >= WIRE_ID_END { return Err(Error::limit("Message id too large to send")) }
+    Ok(bytes[WIDE_ID_SIZE - MSG_ID_SIZE..].to_vec())
+}
+
+fn error_code(kind: Kind) -> Option<u8> {
+    match kind {
+        Kind::Disconnected => None,  // nobody to tell
+        Kind::Protocol => Some(ERR_BAD_FRAMING),
+        Kind::Unsupported => Some(ERR_UNSUPPORTED_VERSION),
+        Kind::Limit => Some(ERR_TOO_LARGE),
+        Kind::Io | Kind::Corruption | Kind::Crypto | Kind::Internal => Some(ERR_SERVER),
+    }
 }
 
 pub fn packet_to_storage(src: &MsgInBuf, dest: &mut MsgStBuf) -> HashBuf {
     // Takes bytes from client, copy data over into msg storage buffer
     // Return chat id
 
-    let msg_time: u64 = std::time::SystemTime::now()
-        .duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap()
-        .as_millis().try_into().expect("go play with your hoverboard");
+    let msg_time = server_time();  // the store sets it again, in order
 
     let (src_id, src_data) = msg_in::split(src);
     let (dest_time, dest_data) = msg_out::split_mut(dest);
@@ -51,98 +84,856 @@ pub fn packet_to_storage(src: &MsgInBuf, dest: &mut MsgStBuf) -> HashBuf {
 }
 
 fn send_messages(
-    stream: &mut (impl Read + Write),
+    stream: &mut (impl Write + ?Sized),
     chat_id: &HashBuf,
-    msg_id: u32,  // id of first message in msgs
+    msg_id: u64,  // id of first message in msgs
     forward: bool,
     count: u8,
-    msgs: Vec<u8>
+    msgs: Vec<u8>,
+    wide: bool,  // 8-byte id in the head
 ) -> Res {
     // converts MessageSt to MessageOut and sends each into stream
     // msg::storage_to_packet
     // TcpStream::write
-    if count > 127 { return Err("Tried to send too many messages") }
+    if count > MAX_MSG_COUNT { return Err(Error::limit("Tried to send too many messages")) }
+    let Ok(msg_id) = wire_id(msg_id, wide) else {  // chat went past what the client can read
+        return send_error(stream, ERR_TOO_LARGE, "Message id too large to send");
+    };
 
     // Use max size buffer - size not known, but stack is big anyway
-    let mut buffer = [0; msg_head::SIZE + msg_out::SIZE * MAX_FETCH_AMOUNT as usize];
-    let (  // this is horrible but idk how I could format it better...
-        buf_pad,
-        buf_chat_id,
-        buf_msg_id,
-        buf_count,
-    ) = msg_head::split_mut((&mut buffer[..msg_head::SIZE]).try_into().unwrap());
-    // ^ can't fail, perfect size
-
-    // construct header for messages
-    buf_pad.copy_from_slice(&msg_head::PAD);
-    buf_chat_id.copy_from_slice(&chat_id[..1]);
-    buf_msg_id.copy_from_slice(&msg_id.to_be_bytes()[1..]);
-    buf_count[0] = (u8::from(forward) << 7) | count;
+    let mut buffer = [0; msg_head_wide::SIZE + msg_out::SIZE * MAX_MSG_COUNT as usize];
+    let head_size = if wide { msg_head_wide::SIZE } else { msg_head::SIZE };
+    let (head, body) = buffer.split_at_mut(head_size);
+
+    // construct header for messages; both heads differ only in the id
+    head[..pad::SIZE].copy_from_slice(&msg_head::PAD);
+    head[pad::SIZE] = chat_id[0];
+    head[pad::SIZE + 1..][..msg_id.len()].copy_from_slice(&msg_id);
+    head[head_size - 1] = (u8::from(forward) << 7) | count;
 
     // fill buffer with messages
-    buffer[msg_head::SIZE..][..msgs.len()].copy_from_slice(&msgs);
+    body[..msgs.len()].copy_from_slice(&msgs);
 
     // send
     full_write(
         stream,
-        &buffer[..msg_head::SIZE + count as usize * msg_out::SIZE],
+        &buffer[..head_size + count as usize * msg_out::SIZE],
         "Failed to send messages in SMRT",
     )
 }
 
-pub fn handle(mut stream: (impl Read + Write), globals: &Arc<Globals>) -> Res {
+fn send_hello(stream: &mut (impl Write + ?Sized), version: u8, features: u32, pow_bits: u8) -> Res {
+    let mut buffer = hello_out::DEFAULT;
+    let (buf_pad, buf_version, buf_features) = hello_out::split_mut(&mut buffer);
+    buf_pad.copy_from_slice(&hello_out::PAD);
+    buf_version[0] = version;
+    buf_features.copy_from_slice(&features.to_be_bytes());
+    let pow_bits = [pow_bits];
+    let tail = if version >= POW_VERSION { pow_bits.as_slice() } else { &[] };  // v2 on
+    full_write(stream, &[&buffer, tail].concat(), "Failed to send hello")
+}
+
+pub fn send_error(stream: &mut (impl Write + ?Sized), code: u8, text: &str) -> Res {
+    // tells the client why it's about to be disconnected
+    let text = &text.as_bytes()[..text.len().min(ERR_TEXT_MAX)];
+    let mut head = err_head::DEFAULT;
+    let (buf_pad, buf_code, buf_len) = err_head::split_mut(&mut head);
+    buf_pad.copy_from_slice(&err_head::PAD);
+    buf_code[0] = code;
+    buf_len[0] = text.len() as u8;  // fits, see above
+    full_write(stream, &[&head, text].concat(), "Failed to send error")
+}
+
+fn send_ack(stream: &mut (impl Write + ?Sized), wide: bool, status: u8, msg_id: u64, time: u64) -> Res {
+    // ack and ack_wide differ only in the id
+    let msg_id = wire_id(msg_id, wide)?;
+    let packet = [&ack::PAD[..], &[status], &msg_id, &time.to_be_bytes()].concat();
+    full_write(stream, &packet, "Failed to send ack")
+}
+
+fn work_bits(msg_in: &MsgInBuf, stamp: &StampBuf) -> u32 {
+    // leading zero bits of the stamped packet's hash
+    use sha3::{Digest, Sha3_256};
+    let hash = Sha3_256::new().chain_update(msg_in).chain_update(stamp).finalize();
+    let zeros = hash.iter().take_while(|b| **b == 0).count();
+    8 * zeros as u32 + hash.get(zeros).map_or(0, |b| b.leading_zeros())
+}
+
+fn push_once(
+    globals: &Globals,
+    chat_id: &HashBuf,
+    msg_in: &MsgInBuf,
+    msg: &mut MsgStBuf,
+    wide: bool,  // the sender can be told any id
+) -> Res<(u64, u64, bool)> {
+    // pushes msg unless the same cypher and signature were pushed recently.
+    // Returns the message id, its server time, and whether it is new.
+    use sha3::{Digest, Sha3_256};
+    let (_, data) = msg_in::split(msg_in);
+    let hash: HashBuf = Sha3_256::digest(data).into();
+
+    // the chat's recent sends are held over check and push, so a resend
+    // racing its original still finds it; other chats aren't held up
+    let now = server_time();
+    let sent = {
+        let mut recent = globals.recent.lock().map_err(|_| Error::internal("Failed to lock recent"))?;
+        let recent = &mut *recent;
+        // forget chats quiet for the whole window; each push is looked at once
+        while let Some(&(time, quiet)) = recent.pushes.front() {
+            if time + REPLAY_WINDOW_MS >= now { break }
+            recent.pushes.pop_front();
+            let Some((sent, last)) = recent.chats.get(&quiet) else { continue };
+            if *last == time && Arc::strong_count(sent) == 1 { recent.chats.remove(&quiet); }
+        }
+        recent.pushes.push_back((now, *chat_id));
+        let (sent, last) = recent.chats.entry(*chat_id).or_default();
+        *last = now;
+        sent.clone()
+    };
+    let mut sent = sent.lock().map_err(|_| Error::internal("Failed to lock recent"))?;
+    while sent.front().is_some_and(|&(_, _, time)| time + REPLAY_WINDOW_MS < now) {
+        sent.pop_front();
+    }
+    if let Some(&(_, id, time)) = sent.iter().find(|(h, _, _)| h == &hash) {
+        return Ok((id, time, false))
+    }
+
+    // an id the sender can't be told would be stored, but never acked
+    if !wide && globals.store.len(chat_id)? >= WIRE_ID_END {
+        return Err(Error::limit("Chat is too long for this client; it needs wide ids"));
+    }
+    let id = globals.store.push(chat_id, msg)?;
+    let (time_buf, _) = msg_out::split(msg);
+    let time = u64::from_be_bytes(time_buf.try_into().unwrap());
+    sent.push_back((hash, id, time));
+    if sent.len() > REPLAY_MAX { sent.pop_front(); }
+    Ok((id, time, true))
+}
+
+fn throttled(globals: &Globals, op: pad::Buf, addr: IpAddr, chat_id: &HashBuf) -> Res<bool> {
+    // takes a token for op from the address's and the chat's bucket.
+    // True if either is empty; then neither loses one.
+    // A bucket is just the time it is full again: each token taken
+    // pushes that time by per/count, and it may be at most per ahead.
+    let limits = &globals.limits;
+    let (per_ip, per_chat) = match op {
+        pad::SEND_PADDING => (limits.send_ip, limits.send_chat),
+        pad::FETCH_PADDING => (limits.fetch_ip, limits.fetch_chat),
+        _ => (limits.query_ip, limits.query_chat),
+    };
+    let per_ip = if addr.is_loopback() { Rate::OFF } else { per_ip };  // local is a proxy (or us)
+
+    let now = Instant::now();
+    let mut buckets = globals.buckets.lock().map_err(|_| Error::internal("Failed to lock buckets"))?;
+    if buckets.len() >= BUCKETS_PRUNE_AT { buckets.retain(|_, full_at| *full_at > now) }
+
+    let keys = [(BucketKey::Peer(op, addr), per_ip), (BucketKey::Chat(op, *chat_id), per_chat)];
+    let mut full_at = [now; 2];
+    for ((key, rate), full_at) in keys.iter().zip(&mut full_at) {
+        if rate.count == 0 { continue }
+        *full_at = buckets.get(key).map_or(now, |t| now.max(*t)) + rate.per / rate.count;
+        if *full_at > now + rate.per { return Ok(true) }
+    }
+    for ((key, rate), full_at) in keys.into_iter().zip(full_at) {
+        if rate.count > 0 { buckets.insert(key, full_at); }
+    }
+    Ok(false)
+}
+
+fn subscribe(globals: &Globals, chat_id: &HashBuf, writer: &SharedWriter, wide: bool) -> Res {
+    let mut subs = globals.subs.lock().map_err(|_| Error::internal("Failed to lock subs"))?;
+    let chat_subs = subs.entry(*chat_id).or_default();
+    if !chat_subs.iter().any(|(w, _)| Arc::ptr_eq(w, writer)) {
+        chat_subs.push((writer.clone(), wide));
+    }
+    Ok(())
+}
+
+fn unsubscribe(globals: &Globals, chat_id: &HashBuf, writer: &SharedWriter) -> Res {
+    let mut subs = globals.subs.lock().map_err(|_| Error::internal("Failed to lock subs"))?;
+    if let Some(chat_subs) = subs.get_mut(chat_id) {
+        chat_subs.retain(|(w, _)| !Arc::ptr_eq(w, writer));
+        if chat_subs.is_empty() { subs.remove(chat_id); }
+    }
+    Ok(())
+}
+
+fn broadcast(globals: &Globals, chat_id: &HashBuf, msg_id: u64, msg: &MsgStBuf) -> Res {
+    // sends a freshly pushed message to everyone subscribed to its chat.
+    // Subscribers that can't be written to are dropped.
+    let chat_subs = {  // not held while writing; slow readers would hold up every chat
+        let subs = globals.subs.lock().map_err(|_| Error::internal("Failed to lock subs"))?;
+        match subs.get(chat_id) {
+            Some(chat_subs) => chat_subs.clone(),
+            None => return Ok(()),
+        }
+    };
+    for (w, wide) in chat_subs {
+        let sent = match w.lock() {
+            Ok(mut w) => send_messages(&mut *w, chat_id, msg_id, true, 1, msg.to_vec(), wide).is_ok(),
+            Err(_) => false,  // poisoned; owner thread died
+        };
+        if !sent { unsubscribe(globals, chat_id, &w)? }
+    }
+    Ok(())
+}
+
+pub struct Session {  // per-connection SMRT state, kept between packets
+    writer: SharedWriter,
+    subscribed: Vec<HashBuf>,  // chat ids; cleaned up on close
+    version: u8,  // agreed in the handshake; 0 without one
+    features: u32,  // shared by both sides
+    addr: IpAddr,  // for rate limits
+}
+
+impl Session {
+    pub fn new(writer: impl Write + Send + 'static, addr: IpAddr) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            subscribed: Vec::new(),
+            version: 0,
+            features: 0,
+            addr,
+        }
+    }
+
+    fn wide(&self) -> bool {
+        self.features & FEATURE_WIDE_IDS != 0
+    }
+
+    pub fn report(&self, e: &Error) {
+        // last words before the connection is dropped for e; best effort
+        let Some(code) = error_code(e.kind()) else { return };
+        if let Ok(mut w) = self.writer.lock() {
+            let _ = send_error(&mut *w, code, e.context());
+        }
+    }
+
+    pub fn close(self, globals: &Globals) -> Res {
+        for chat_id in &self.subscribed {
+            unsubscribe(globals, chat_id, &self.writer)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn packet_len(buf: &[u8], session: &Session) -> Res<Option<usize>> {
+    // Length of the packet at the start of buf, once enough has
+    // arrived to tell; handle_packet then never waits for more
+    let Some(pad_buf) = buf.get(..pad::SIZE) else { return Ok(None) };
+    let len = match pad_buf.try_into().unwrap() {  // can't fail
+        pad::HELLO_PADDING => hello::PREPAD.len(),
+        pad::SEND_PADDING if session.version >= POW_VERSION => msg_in_c_v2::PREPAD.len(),
+        pad::SEND_PADDING => msg_in_c::PREPAD.len(),
+        pad::FETCH_PADDING => match buf.get(pad::SIZE + fetch::SIZE) {
+            None => return Ok(None),
+            Some(&FCH_V1) => fetch_v1::PREPAD.len(),
+            Some(_) => fetch::PREPAD.len(),  // v0; wrong end pads are caught later
+        },
+        pad::QUERY_PADDING if session.wide() => query_wide::PREPAD.len(),
+        pad::QUERY_PADDING => query::PREPAD.len(),
+        pad::TIME_PADDING => time_query::PREPAD.len(),
+        pad::SUB_PADDING => sub::PREPAD.len(),
+        pad::UNSUB_PADDING => unsub::PREPAD.len(),
+        _ => return Err(Error::protocol("Recieved invalid SMRT header")),
+    };
+    Ok((buf.len() >= len).then_some(len))
+}
+
+pub fn handle_packet(
+    stream: &mut impl Read,
+    session: &mut Session,
+    globals: &Arc<Globals>,
+) -> Res {
+    // reads and answers exactly one packet
     let mut pad_buf: [u8; 3] = pad::DEFAULT;
     let mut snd_buf = msg_in::DEFAULT;  // size of msg packet
     let mut chat_id_buf = hash::DEFAULT;
     let mut qry_arg_buf = qry_arg::DEFAULT;
     let mut st_buf = msg_out::DEFAULT;
 
-    loop {
-        read_exact(
-            &mut stream,
-            &mut pad_buf,
-            "Failed to read SMRT pad. Socket timed out?",
-        )?;
-        match pad_buf {
-            pad::SEND_PADDING => {
-                read_exact(&mut stream, &mut snd_buf, "Failed to read cypher")?;
-                read_exact(&mut stream, &mut pad_buf, "Failed to read end pad (snd)")?;
-                if pad_buf != pad::END_PADDING { return Err("Incorrect end padding (snd)") }
- 
-                chat_id_buf = packet_to_storage(&snd_buf, &mut st_buf);
-                db::push(&get_chat_file(&chat_id_buf, &globals.data_dir), &st_buf)?;
-            },
-            pad::FETCH_PADDING => {
-                // fill fetch buffer
-                read_exact(&mut stream, &mut chat_id_buf, "Failed to read fetch chat id")?;
-                read_exact(&mut stream, &mut pad_buf, "Failed to read end pad (fch)")?;
-                if pad_buf != pad::END_PADDING { return Err("Incorrect end padding (fch)") }
-
-                // get arguments for the db fetch
-                let path = get_chat_file(&chat_id_buf, &globals.data_dir);
-                // todo: add count to fetch message
-
-                // fetch from db & send to client
-                let (count, msg_id, messages) = db::fetch(&path, DEFAULT_FETCH_AMOUNT)?;
-                send_messages(&mut stream, &chat_id_buf, msg_id, true, count, messages)?;
-            },
-            pad::QUERY_PADDING => {
-                // fill chat_id and arg buffer
-                // TODO: read in one go, then split with buffers?
-                read_exact(&mut stream, &mut chat_id_buf, "Failed to read query chat id")?;
-                read_exact(&mut stream, &mut qry_arg_buf, "Failed to read query args")?;
-                read_exact(&mut stream, &mut pad_buf, "Failed to read end pad (qry)")?;
-                if pad_buf != pad::END_PADDING { return Err("Incorrect end padding (qry)") }
-                
-                // get arguments for the db fetch
-                let (msg_id, count, forward) = query_bytes_to_args(&qry_arg_buf);
-                let path = get_chat_file(&chat_id_buf, &globals.data_dir);
-
-                // return query
-                let (count, msg_id, messages) = db::query(&path, msg_id, count, forward)?;
-                send_messages(&mut stream, &chat_id_buf, msg_id, forward, count, messages)?;
-            },
-            _ => return Err("Recieved invalid SMRT header"),
+    // mutex lock shortcut
+    macro_rules! out { () => {
+        session.writer.lock().map_err(|_| Error::internal("Failed to lock writer"))?
+    } }
+
+    // answers (and skips) the packet if it goes over a rate limit
+    macro_rules! throttle { ($op:expr, $chat_id:expr) => {
+        if throttled(globals, $op, session.addr, $chat_id)? {
+            send_error(&mut *out!(), ERR_RATE_LIMITED, "Too many requests; slow down")?;
+            return Ok(())
+        }
+    } }
+
+    read_exact(stream, &mut pad_buf, "Failed to read SMRT pad")?;
+    match pad_buf {
+        pad::HELLO_PADDING => {
+            let mut hlo_buf = hello::DEFAULT;
+            read_exact(stream, &mut hlo_buf, "Failed to read hello")?;
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (hlo)")?;
+            if pad_buf != pad::END_PADDING { return Err(Error::protocol("Incorrect end padding (hlo)")) }
+
+            let (version_buf, features_buf) = hello::split(&hlo_buf);
+            if version_buf[0] < MIN_PROTOCOL_VERSION {
+                return Err(Error::unsupported("Protocol version too old"));
+            }
+            session.version = version_buf[0].min(PROTOCOL_VERSION);  // newer clients step down
+            session.features = u32::from_be_bytes(features_buf.try_into().unwrap()) & FEATURES;
+            send_hello(&mut *out!(), session.version, FEATURES, globals.limits.pow_bits)?;
+        },
+        pad::SEND_PADDING => {
+            read_exact(stream, &mut snd_buf, "Failed to read cypher")?;
+            let mut stamp_buf = stamp::DEFAULT;
+            if session.version >= POW_VERSION {
+                read_exact(stream, &mut stamp_buf, "Failed to read stamp")?;
+            }
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (snd)")?;
+            if pad_buf != pad::END_PADDING { return Err(Error::protocol("Incorrect end padding (snd)")) }
+
+            let acks = session.features & FEATURE_SEND_ACK != 0;
+            let pow_bits = globals.limits.pow_bits;
+            if pow_bits > 0 {  // cheap to check, expensive to make
+                if session.version < POW_VERSION {
+                    return Err(Error::unsupported("Proof of work needed; update the client"))
+                }
+                if work_bits(&snd_buf, &stamp_buf) < pow_bits.into() {
+                    if !acks { return Err(Error::protocol("Proof of work too weak")) }
+                    send_ack(&mut *out!(), session.wide(), ERR_NEEDS_WORK, 0, 0)?;
+                    return Ok(())
+                }
+            }
+
+            let (cid_buf, _) = msg_in::split(&snd_buf);
+            if throttled(globals, pad::SEND_PADDING, session.addr, cid_buf.try_into().unwrap())? {
+                match acks {  // a sender that knows acks expects the answer there
+                    true => send_ack(&mut *out!(), session.wide(), ERR_RATE_LIMITED, 0, 0)?,
+                    false => send_error(&mut *out!(), ERR_RATE_LIMITED, "Sending too fast; slow down")?,
+                }
+                return Ok(())
+            }
+
+            chat_id_buf = packet_to_storage(&snd_buf, &mut st_buf);
+            match push_once(globals, &chat_id_buf, &snd_buf, &mut st_buf, session.wide()) {
+                Ok((msg_id, time, fresh)) => {
+                    if acks {  // before the broadcast, so the sender knows its own echo
+                        send_ack(&mut *out!(), session.wide(), ACK_STORED, msg_id, time)?;
+                    }
+                    if fresh { broadcast(globals, &chat_id_buf, msg_id, &st_buf)? }
+                },
+                Err(e) if acks => {  // client can retry; keep the connection
+                    log!(Info, "Failed to store message:\n\t{e}");
+                    send_ack(&mut *out!(), session.wide(), error_code(e.kind()).unwrap_or(ERR_SERVER), 0, 0)?;
+                },
+                Err(e) => return Err(e),
+            }
+        },
+        pad::FETCH_PADDING => {
+            // fill fetch buffer
+            read_exact(stream, &mut chat_id_buf, "Failed to read fetch chat id")?;
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (fch)")?;
+
+            // v0 ends here; v1 has a version and a count first
+            let count = match pad_buf {
+                pad::END_PADDING => 0,
+                [FCH_V1, count, e] => {
+                    let mut nd_buf = [0; 2];
+                    read_exact(stream, &mut nd_buf, "Failed to read end pad (fch v1)")?;
+                    if [e, nd_buf[0], nd_buf[1]] != pad::END_PADDING {
+                        return Err(Error::protocol("Incorrect end padding (fch v1)"))
+                    }
+                    count
+                },
+                _ => return Err(Error::protocol("Incorrect end padding (fch)")),
+            };
+            throttle!(pad::FETCH_PADDING, &chat_id_buf);
+            let count = match count {
+                0 => globals.limits.fetch_default,
+                n => n.min(globals.limits.fetch_max),  // too many: send max amount
+            };
+
+            // fetch from the store & send to client
+            let (count, msg_id, messages) = globals.store.fetch(&chat_id_buf, count)?;
+            send_messages(&mut *out!(), &chat_id_buf, msg_id, true, count, messages, session.wide())?;
+        },
+        pad::QUERY_PADDING if session.wide() => {
+            let mut qry_buf = query_wide::DEFAULT;
+            read_exact(stream, &mut qry_buf, "Failed to read query")?;
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (qry)")?;
+            if pad_buf != pad::END_PADDING { return Err(Error::protocol("Incorrect end padding (qry)")) }
+
+            let (cid_buf, args_buf, mid_buf) = query_wide::split(&qry_buf);
+            chat_id_buf.copy_from_slice(cid_buf);
+            throttle!(pad::QUERY_PADDING, &chat_id_buf);
+            let forward = args_buf[0] & 0x80 != 0;
+            let count = (args_buf[0] & 0x7f).min(globals.limits.fetch_max);
+            let msg_id = u64::from_be_bytes(mid_buf.try_into().unwrap());  // can't fail
+
+            let (count, msg_id, messages) = globals.store.query(&chat_id_buf, msg_id, count, forward)?;
+            send_messages(&mut *out!(), &chat_id_buf, msg_id, forward, count, messages, true)?;
+        },
+        pad::QUERY_PADDING => {
+            // fill chat_id and arg buffer
+            // TODO: read in one go, then split with buffers?
+            read_exact(stream, &mut chat_id_buf, "Failed to read query chat id")?;
+            read_exact(stream, &mut qry_arg_buf, "Failed to read query args")?;
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (qry)")?;
+            if pad_buf != pad::END_PADDING { return Err(Error::protocol("Incorrect end padding (qry)")) }
+            throttle!(pad::QUERY_PADDING, &chat_id_buf);
+
+            // get arguments for the store query
+            let (msg_id, count, forward) = query_bytes_to_args(&qry_arg_buf);
+            let count = count.min(globals.limits.fetch_max);  // too many: send max amount
+
+            // return query
+            let (count, msg_id, messages) = globals.store.query(&chat_id_buf, msg_id.into(), count, forward)?;
+            send_messages(&mut *out!(), &chat_id_buf, msg_id, forward, count, messages, false)?;
+        },
+        pad::TIME_PADDING => {
+            let mut tim_buf = time_query::DEFAULT;
+            read_exact(stream, &mut tim_buf, "Failed to read time query")?;
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (tim)")?;
+            if pad_buf != pad::END_PADDING { return Err(Error::protocol("Incorrect end padding (tim)")) }
+
+            let (cid_buf, args_buf, time_buf) = time_query::split(&tim_buf);
+            chat_id_buf.copy_from_slice(cid_buf);
+            throttle!(pad::QUERY_PADDING, &chat_id_buf);  // shares the bucket
+            let forward = args_buf[0] & 0x80 != 0;
+            let count = (args_buf[0] & 0x7f).min(globals.limits.fetch_max);
+            let time = u64::from_be_bytes(time_buf.try_into().unwrap());  // can't fail
+
+            let (count, msg_id, messages) = globals.store.query_time(&chat_id_buf, time, count, forward)?;
+            send_messages(&mut *out!(), &chat_id_buf, msg_id, forward, count, messages, session.wide())?;
+        },
+        pad::SUB_PADDING => {
+            read_exact(stream, &mut chat_id_buf, "Failed to read sub chat id")?;
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (sub)")?;
+            if pad_buf != pad::END_PADDING { return Err(Error::protocol("Incorrect end padding (sub)")) }
+
+            subscribe(globals, &chat_id_buf, &session.writer, session.wide())?;
+            if !session.subscribed.contains(&chat_id_buf) { session.subscribed.push(chat_id_buf) }
+        },
+        pad::UNSUB_PADDING => {
+            read_exact(stream, &mut chat_id_buf, "Failed to read unsub chat id")?;
+            read_exact(stream, &mut pad_buf, "Failed to read end pad (uns)")?;
+            if pad_buf != pad::END_PADDING { return Err(Error::protocol("Incorrect end padding (uns)")) }
+
+            unsubscribe(globals, &chat_id_buf, &session.writer)?;
+            session.subscribed.retain(|id| id != &chat_id_buf);
+        },
+        _ => return Err(Error::protocol("Recieved invalid SMRT header")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, io, mem, net::Ipv4Addr};
+    use publichat::store::{ChatStore, ChatStat, Memory, Messages};
+
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);  // what the server wrote to a client
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    struct Broken;  // a client that went away
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(io::ErrorKind::BrokenPipe.into()) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn globals(store: impl ChatStore + 'static, limits: Limits) -> Arc<Globals> {
+        Arc::new(Globals {
+            store: Arc::new(store),
+            git_hash: [b'0'; 40],
+            limits,
+            subs: Mutex::new(HashMap::new()),
+            conns: Mutex::new(HashMap::new()),
+            recent: Mutex::new(Recent::default()),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    struct Client {  // one connection, driven a packet at a time
+        session: Session,
+        out: Sink,
+        globals: Arc<Globals>,
+    }
+
+    impl Client {
+        fn new(globals: &Arc<Globals>) -> Self {
+            Self::from(globals, IpAddr::V4(Ipv4Addr::LOCALHOST))
+        }
+
+        fn from(globals: &Arc<Globals>, addr: IpAddr) -> Self {
+            let out = Sink::default();
+            Self { session: Session::new(out.clone(), addr), out, globals: globals.clone() }
+        }
+
+        fn send(&mut self, packet: &[u8]) -> Res<Vec<u8>> {
+            // the answer to packet, which packet_len must find whole and no sooner
+            assert_eq!(packet_len(&packet[..packet.len() - 1], &self.session)?, None);
+            assert_eq!(packet_len(packet, &self.session)?, Some(packet.len()));
+            handle_packet(&mut &packet[..], &mut self.session, &self.globals)?;
+            Ok(self.take())
+        }
+
+        fn handle(&mut self, packet: &[u8]) -> Kind {
+            // the kind of error packet gets dropped for
+            handle_packet(&mut &packet[..], &mut self.session, &self.globals).unwrap_err().kind()
+        }
+
+        fn take(&self) -> Vec<u8> {
+            mem::take(&mut *self.out.0.lock().unwrap())
+        }
+
+        fn hello(&mut self, version: u8, features: u32) -> Vec<u8> {
+            self.send(&[b"hlo", &[version][..], &features.to_be_bytes(), b"end"].concat()).unwrap()
+        }
+    }
+
+    const CHAT: HashBuf = [7; HASH_SIZE];
+
+    fn snd(tag: u8, stamp: Option<u64>) -> Vec<u8> {
+        // a send to CHAT; the stamp is there from version 2 on
+        let stamp = stamp.map(u64::to_be_bytes);
+        [&b"snd"[..], &CHAT, &[tag; msg_in::SIZE - HASH_SIZE], stamp.as_ref().map_or(&[][..], |s| s), b"end"].concat()
+    }
+
+    fn fch(count: Option<u8>) -> Vec<u8> {
+        let v1 = count.map(|count| [FCH_V1, count]);
+        [&b"fch"[..], &CHAT, v1.as_ref().map_or(&[][..], |v| v), b"end"].concat()
+    }
+
+    fn head(out: &[u8], wide: bool) -> (u64, u8, bool) {
+        // id, count and direction of a msg packet
+        assert_eq!(out[..3], *b"msg");
+        let id_size = if wide { WIDE_ID_SIZE } else { MSG_ID_SIZE };
+        let id = out[4..][..id_size].iter().fold(0, |id, &b| id << 8 | u64::from(b));
+        let args = out[4 + id_size];
+        assert_eq!(out.len(), 5 + id_size + usize::from(args & 0x7f) * msg_out::SIZE);
+        (id, args & 0x7f, args & 0x80 != 0)
+    }
+
+    fn ack_of(out: &[u8], wide: bool) -> (u8, u64) {
+        // status and id of an ack packet
+        assert_eq!(out[..3], *b"ack");
+        let id_size = if wide { WIDE_ID_SIZE } else { MSG_ID_SIZE };
+        assert_eq!(out.len(), 4 + id_size + TIME_SIZE);
+        (out[3], out[4..][..id_size].iter().fold(0, |id, &b| id << 8 | u64::from(b)))
+    }
+
+    struct Far(Memory);  // a chat whose first 2^24 messages were dropped by retention
+
+    impl ChatStore for Far {
+        fn push(&self, chat: &HashBuf, msg: &mut MsgStBuf) -> Res<u64> {
+            Ok(self.0.push(chat, msg)? + WIRE_ID_END)
         }
+        fn fetch(&self, chat: &HashBuf, count: u8) -> Res<Messages> {
+            let (count, start, msgs) = self.0.fetch(chat, count)?;
+            Ok((count, start + WIRE_ID_END, msgs))
+        }
+        fn query(&self, chat: &HashBuf, id: u64, count: u8, forward: bool) -> Res<Messages> {
+            let (count, start, msgs) = self.0.query(chat, id - WIRE_ID_END, count, forward)?;
+            Ok((count, start + WIRE_ID_END, msgs))
+        }
+        fn query_time(&self, chat: &HashBuf, time: u64, count: u8, forward: bool) -> Res<Messages> {
+            let (count, start, msgs) = self.0.query_time(chat, time, count, forward)?;
+            Ok((count, start + WIRE_ID_END, msgs))
+        }
+        fn stat(&self, chat: &HashBuf) -> Res<ChatStat> {
+            let stat = self.0.stat(chat)?;
+            Ok(ChatStat { first_id: WIRE_ID_END, len: stat.len + WIRE_ID_END, bytes: stat.bytes })
+        }
+    }
+
+    struct Full;  // a store that takes nothing
+
+    impl ChatStore for Full {
+        fn push(&self, _: &HashBuf, _: &mut MsgStBuf) -> Res<u64> { Err(Error::limit("Chat is full")) }
+        fn fetch(&self, _: &HashBuf, _: u8) -> Res<Messages> { Ok((0, 0, vec![])) }
+        fn query(&self, _: &HashBuf, _: u64, _: u8, _: bool) -> Res<Messages> { Ok((0, 0, vec![])) }
+        fn query_time(&self, _: &HashBuf, _: u64, _: u8, _: bool) -> Res<Messages> { Ok((0, 0, vec![])) }
+        fn stat(&self, _: &HashBuf) -> Res<ChatStat> { Ok(ChatStat { first_id: 0, len: 0, bytes: 0 }) }
+    }
+
+    #[test]
+    fn wide_ids() {
+        let globals = globals(Far(Memory::default()), Limits::default());
+        let mut wide = Client::new(&globals);
+        wide.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK | FEATURE_WIDE_IDS);
+        assert_eq!(ack_of(&wide.send(&snd(1, Some(0))).unwrap(), true), (ACK_STORED, WIRE_ID_END));
+        wide.send(&snd(2, Some(0))).unwrap();
+        assert_eq!(head(&wide.send(&fch(Some(10))).unwrap(), true), (WIRE_ID_END, 2, true));
+        let qry = [&b"qry"[..], &CHAT, &[0x80 | 10], &(WIRE_ID_END).to_be_bytes(), b"end"].concat();
+        assert_eq!(head(&wide.send(&qry).unwrap(), true), (WIRE_ID_END + 1, 1, true));
+
+        // without wide ids, nothing past 2^24 can be sent or told
+        let mut narrow = Client::new(&globals);
+        narrow.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK);
+        assert_eq!(ack_of(&narrow.send(&snd(3, Some(0))).unwrap(), false), (ERR_TOO_LARGE, 0));
+        let out = narrow.send(&fch(Some(10))).unwrap();
+        assert_eq!(out[..4], [b'e', b'r', b'r', ERR_TOO_LARGE]);
+        assert_eq!(globals.store.len(&CHAT).unwrap(), WIRE_ID_END + 2);
+    }
+
+    #[test]
+    fn fetch_counts() {
+        let globals = globals(Memory::default(), Limits { fetch_max: 5, fetch_default: 3, ..Limits::default() });
+        let mut client = Client::new(&globals);
+        for tag in 0..8 { client.send(&snd(tag, None)).unwrap(); }
+        assert_eq!(head(&client.send(&fch(None)).unwrap(), false), (5, 3, true));  // v0: the default
+        assert_eq!(head(&client.send(&fch(Some(0))).unwrap(), false), (5, 3, true));
+        assert_eq!(head(&client.send(&fch(Some(2))).unwrap(), false), (6, 2, true));
+        assert_eq!(head(&client.send(&fch(Some(100))).unwrap(), false), (3, 5, true));  // at most fetch_max
+
+        // the byte after the chat id tells v0 from v1, so a pad can't be mistaken for a count
+        let v1 = fch(Some(2));
+        assert_eq!(packet_len(&v1[..38], &client.session).unwrap(), None);
+        assert_eq!(packet_len(&v1[..35], &client.session).unwrap(), None);
+        let bad_end = [&v1[..v1.len() - 1], b"x"].concat();
+        assert_eq!(client.handle(&bad_end), Kind::Protocol);
+        assert_eq!(client.handle(&[&b"fch"[..], &CHAT, &[2, 2], b"end"].concat()), Kind::Protocol);
+    }
+
+    #[test]
+    fn hello_versions() {
+        let globals = globals(Memory::default(), Limits { pow_bits: 9, ..Limits::default() });
+        let reply = |version: u8, pow: &[u8]| {
+            [&b"hlo"[..], &[version], &FEATURES.to_be_bytes(), pow].concat()
+        };
+
+        let mut old = Client::new(&globals);  // v1 gets no pow byte
+        assert_eq!(old.hello(1, FEATURE_FETCH_COUNT | 1 << 31), reply(1, &[]));
+        assert_eq!((old.session.version, old.session.features), (1, FEATURE_FETCH_COUNT));
+        assert_eq!(packet_len(&snd(0, None), &old.session).unwrap(), Some(snd(0, None).len()));
+
+        let mut new = Client::new(&globals);  // newer clients step down
+        assert_eq!(new.hello(PROTOCOL_VERSION + 5, u32::MAX), reply(PROTOCOL_VERSION, &[9]));
+        assert_eq!((new.session.version, new.session.features), (PROTOCOL_VERSION, FEATURES));
+        assert_eq!(packet_len(&snd(0, Some(0)), &new.session).unwrap(), Some(snd(0, Some(0)).len()));
+
+        let mut ancient = Client::new(&globals);
+        let hlo = [&b"hlo"[..], &[MIN_PROTOCOL_VERSION - 1], &[0; 4], b"end"].concat();
+        assert_eq!(ancient.handle(&hlo), Kind::Unsupported);
+        assert_eq!(ancient.session.version, 0);
+        let hlo = [&b"hlo"[..], &[1], &[0; 4], b"edn"].concat();
+        assert_eq!(ancient.handle(&hlo), Kind::Protocol);
+        assert!(ancient.take().is_empty());  // errors are sent by whoever drops the connection
+    }
+
+    #[test]
+    fn error_packets() {
+        let globals = globals(Memory::default(), Limits::default());
+        let err = |code: u8, text: &str| [&b"err"[..], &[code, text.len() as u8], text.as_bytes()].concat();
+
+        // the packet a dropped connection gets for each kind of error
+        let mut client = Client::new(&globals);
+        assert_eq!(client.handle(b"xyzend"), Kind::Protocol);
+        assert_eq!(packet_len(b"xyz", &client.session).unwrap_err().kind(), Kind::Protocol);
+        client.session.report(&Error::protocol("Recieved invalid SMRT header"));
+        assert_eq!(client.take(), err(ERR_BAD_FRAMING, "Recieved invalid SMRT header"));
+        for (e, code) in [
+            (Error::unsupported("old"), Some(ERR_UNSUPPORTED_VERSION)),
+            (Error::limit("big"), Some(ERR_TOO_LARGE)),
+            (Error::internal("ours"), Some(ERR_SERVER)),
+            (Error::io("disk", io::Error::other("full")), Some(ERR_SERVER)),
+            (Error::io("gone", io::ErrorKind::UnexpectedEof.into()), None),
+        ] {
+            client.session.report(&e);
+            assert_eq!(client.take(), code.map_or(vec![], |code| err(code, e.context())), "{e}");
+        }
+
+        // long texts are cut to fit their length byte
+        let mut out = Vec::new();
+        send_error(&mut out, ERR_SERVER, &"x".repeat(300)).unwrap();
+        assert_eq!(out, err(ERR_SERVER, &"x".repeat(ERR_TEXT_MAX)));
+        assert_eq!(send_error(&mut Broken, ERR_SERVER, "x").unwrap_err().kind(), Kind::Disconnected);
+    }
+
+    #[test]
+    fn acks() {
+        let globals = globals(Memory::default(), Limits::default());
+        let mut plain = Client::new(&globals);
+        assert!(plain.send(&snd(0, None)).unwrap().is_empty());  // only when asked for
+
+        let mut client = Client::new(&globals);
+        client.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK);
+        client.send(&[&b"sub"[..], &CHAT, b"end"].concat()).unwrap();
+        let out = client.send(&snd(1, Some(0))).unwrap();
+        let (ack, echo) = out.split_at(4 + MSG_ID_SIZE + TIME_SIZE);  // the ack comes before the echo
+        assert_eq!(ack_of(ack, false), (ACK_STORED, 1));
+        assert_eq!(head(echo, false), (1, 1, true));
+
+        // the ack's time is the one stored with the message
+        let time = &ack[4 + MSG_ID_SIZE..];
+        let (_, _, msgs) = globals.store.fetch(&CHAT, 1).unwrap();
+        assert_eq!(&msgs[..TIME_SIZE], time);
+        assert_eq!(&echo[msg_head::SIZE..][..TIME_SIZE], time);
+        assert_eq!(ack_of(&client.send(&snd(2, Some(0))).unwrap()[..ack.len()], false), (ACK_STORED, 2));
+
+        // a failed push is acked too, and the sender can retry
+        let globals = self::globals(Full, Limits::default());
+        let mut client = Client::new(&globals);
+        client.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK);
+        assert_eq!(ack_of(&client.send(&snd(1, Some(0))).unwrap(), false), (ERR_TOO_LARGE, 0));
+        let mut plain = Client::new(&globals);
+        assert_eq!(plain.handle(&snd(1, None)), Kind::Limit);
+    }
+
+    fn age(globals: &Globals) {
+        // moves every remembered send out of the replay window
+        let by = REPLAY_WINDOW_MS + 1;
+        let mut recent = globals.recent.lock().unwrap();
+        recent.pushes.iter_mut().for_each(|(time, _)| *time -= by);
+        for (sent, last) in recent.chats.values_mut() {
+            *last -= by;
+            sent.lock().unwrap().iter_mut().for_each(|(_, _, time)| *time -= by);
+        }
+    }
+
+    #[test]
+    fn replays() {
+        let globals = globals(Memory::default(), Limits::default());
+        let mut viewer = Client::new(&globals);
+        viewer.send(&[&b"sub"[..], &CHAT, b"end"].concat()).unwrap();
+        let mut client = Client::new(&globals);
+        client.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK);
+
+        // a resend gets the first ack again, and isn't stored or broadcast twice
+        let first = client.send(&snd(1, Some(0))).unwrap();
+        assert_eq!(client.send(&snd(1, Some(5))).unwrap(), first);  // the stamp isn't part of it
+        assert_eq!(globals.store.len(&CHAT).unwrap(), 1);
+        assert_eq!(head(&viewer.take(), false), (0, 1, true));
+        assert_eq!(ack_of(&client.send(&snd(2, Some(0))).unwrap(), false), (ACK_STORED, 1));
+        assert_eq!(head(&viewer.take(), false), (1, 1, true));
+        let other = [&b"snd"[..], &[8; HASH_SIZE], &[1; msg_in::SIZE - HASH_SIZE], &[0; 8], b"end"].concat();
+        assert_eq!(ack_of(&client.send(&other).unwrap(), false), (ACK_STORED, 0));  // same message, another chat
+
+        // past the window it is a new message, and quiet chats are forgotten
+        age(&globals);
+        assert_eq!(ack_of(&client.send(&snd(1, Some(0))).unwrap(), false), (ACK_STORED, 2));
+        assert_eq!(head(&viewer.take(), false), (2, 1, true));
+        let recent = globals.recent.lock().unwrap();
+        assert!(recent.chats.contains_key(&CHAT));
+        assert!(!recent.chats.contains_key(&[8; HASH_SIZE]));
+        assert_eq!(recent.pushes.len(), 1);
+        assert_eq!(recent.chats[&CHAT].0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn proof_of_work() {
+        let globals = globals(Memory::default(), Limits { pow_bits: 8, ..Limits::default() });
+        let msg_in: MsgInBuf = snd(1, None)[pad::SIZE..][..msg_in::SIZE].try_into().unwrap();
+        let bits = |stamp: u64| work_bits(&msg_in, &stamp.to_be_bytes());
+        let good = (0..).find(|&stamp| bits(stamp) >= 8).unwrap();
+        let weak = (0..).find(|&stamp| bits(stamp) < 8).unwrap();
+
+        let mut client = Client::new(&globals);
+        client.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK);
+        assert_eq!(ack_of(&client.send(&snd(1, Some(weak))).unwrap(), false), (ERR_NEEDS_WORK, 0));
+        assert_eq!(ack_of(&client.send(&snd(1, Some(good))).unwrap(), false), (ACK_STORED, 0));
+
+        // without acks a weak stamp drops the connection, and so does having none
+        let mut plain = Client::new(&globals);
+        plain.hello(PROTOCOL_VERSION, 0);
+        assert_eq!(plain.handle(&snd(1, Some(weak))), Kind::Protocol);
+        assert!(plain.send(&snd(1, Some(good))).unwrap().is_empty());
+        let mut old = Client::new(&globals);
+        old.hello(1, FEATURE_SEND_ACK);
+        assert_eq!(old.handle(&snd(1, None)), Kind::Unsupported);
+        assert_eq!(globals.store.len(&CHAT).unwrap(), 1);
+
+        // and with work off, any stamp will do
+        let globals = self::globals(Memory::default(), Limits::default());
+        let mut client = Client::new(&globals);
+        client.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK);
+        assert_eq!(ack_of(&client.send(&snd(1, Some(weak))).unwrap(), false), (ACK_STORED, 0));
+    }
+
+    #[test]
+    fn rate_limits() {
+        let limits = Limits {
+            send_ip: Rate::new(2, 60),
+            send_chat: Rate::new(3, 60),
+            fetch_ip: Rate::new(1, 60),
+            ..Limits::default()
+        };
+        let globals = globals(Memory::default(), limits);
+        let limited = [&b"err"[..], &[ERR_RATE_LIMITED]].concat();
+        let mut a = Client::from(&globals, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let mut b = Client::from(&globals, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+        b.hello(PROTOCOL_VERSION, FEATURE_SEND_ACK);
+
+        // the address runs out first, then the chat; the connection stays up
+        assert!(a.send(&snd(1, None)).unwrap().is_empty());
+        assert!(a.send(&snd(2, None)).unwrap().is_empty());
+        assert!(a.send(&snd(3, None)).unwrap().starts_with(&limited));
+        assert_eq!(ack_of(&b.send(&snd(4, Some(0))).unwrap(), false), (ACK_STORED, 2));
+        assert_eq!(ack_of(&b.send(&snd(5, Some(0))).unwrap(), false), (ERR_RATE_LIMITED, 0));
+        assert_eq!(globals.store.len(&CHAT).unwrap(), 3);
+
+        // a refused send costs its address nothing
+        let chat = [9; HASH_SIZE];
+        assert!(!throttled(&globals, pad::SEND_PADDING, b.session.addr, &chat).unwrap());
+        assert!(throttled(&globals, pad::SEND_PADDING, b.session.addr, &chat).unwrap());
+
+        // each packet has its own buckets, and local addresses have none
+        assert_eq!(head(&a.send(&fch(None)).unwrap(), false), (0, 3, true));
+        assert!(a.send(&fch(None)).unwrap().starts_with(&limited));
+        let mut local = Client::new(&globals);
+        for _ in 0..5 { assert_eq!(head(&local.send(&fch(None)).unwrap(), false), (0, 3, true)); }
+        assert!(local.send(&snd(6, None)).unwrap().starts_with(&limited));  // but chats do
+
+        // full again once their time has come
+        globals.buckets.lock().unwrap().values_mut().for_each(|full_at| *full_at = Instant::now());
+        assert!(a.send(&snd(7, None)).unwrap().is_empty());
+        assert_eq!(globals.store.len(&CHAT).unwrap(), 4);
+    }
+
+    #[test]
+    fn subscriptions() {
+        let globals = globals(Memory::default(), Limits::default());
+        let sub = [&b"sub"[..], &CHAT, b"end"].concat();
+        let uns = [&b"uns"[..], &CHAT, b"end"].concat();
+        let subs = || globals.subs.lock().unwrap().get(&CHAT).map_or(0, Vec::len);
+        let mut sender = Client::new(&globals);
+        let mut viewer = Client::new(&globals);
+        assert!(viewer.send(&sub).unwrap().is_empty());
+        viewer.send(&sub).unwrap();  // once is enough
+        assert_eq!(subs(), 1);
+
+        // each new message is pushed to the viewer, and only while subscribed
+        sender.send(&snd(1, None)).unwrap();
+        let out = viewer.take();
+        assert_eq!(head(&out, false), (0, 1, true));
+        let cypher = &snd(1, None)[pad::SIZE + HASH_SIZE..][..msg_in::SIZE - HASH_SIZE];
+        assert_eq!(&out[msg_head::SIZE + TIME_SIZE..], cypher);
+        viewer.send(&uns).unwrap();
+        assert_eq!(subs(), 0);
+        assert!(!globals.subs.lock().unwrap().contains_key(&CHAT));
+        sender.send(&snd(2, None)).unwrap();
+        assert!(viewer.take().is_empty());
+
+        // a viewer that can't be written to is dropped, the rest still get it
+        let mut gone = Client::new(&globals);
+        gone.session = Session::new(Broken, gone.session.addr);
+        gone.send(&sub).unwrap();
+        viewer.send(&sub).unwrap();
+        assert_eq!(subs(), 2);
+        sender.send(&snd(3, None)).unwrap();
+        assert_eq!(subs(), 1);
+        assert_eq!(head(&viewer.take(), false), (2, 1, true));
+
+        // closing unsubscribes
+        viewer.session.close(&globals).unwrap();
+        assert_eq!(subs(), 0);
     }
 }
@@ -0,0 +1,184 @@
+// The server's end of a socket, never blocking. Reads take what has
+// arrived; writes are queued whole and sent as fast as the socket takes
+// them, so no thread waits on a slow or silent peer. Whatever is left
+// makes the poller wake the connection again once there's room.
+
+use std::{
+    io::{self, Read, Write, ErrorKind},
+    net::{TcpStream, Shutdown},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use polling::{Poller, Event};
+use rustls::ServerConnection;
+
+const READ_CHUNK: usize = 16 * 1024;
+const OUT_MAX: usize = 1024 * 1024;  // queued for one reader before it's dropped
+
+pub struct Wire {
+    tcp: TcpStream,
+    tls: Option<ServerConnection>,
+    out: Vec<u8>,  // sent (and encrypted), but not taken by the socket yet
+    waiting: Option<Instant>,  // since the socket last took some of out
+    broken: bool,  // a write failed; the stream would be out of sync
+    poller: Arc<Poller>,
+    key: usize,
+    registered: bool,  // with the poller
+}
+
+// write handle on a wire, for whoever has something to send
+pub struct Outbox(Arc<Mutex<Wire>>);
+
+impl Wire {
+    pub fn new(tcp: TcpStream, tls: Option<ServerConnection>, poller: Arc<Poller>, key: usize) -> io::Result<Self> {
+        tcp.set_nonblocking(true)?;
+        Ok(Self {
+            tcp,
+            tls,
+            out: Vec::new(),
+            waiting: None,
+            broken: false,
+            poller,
+            key,
+            registered: false,
+        })
+    }
+
+    pub fn lock(wire: &Mutex<Self>) -> io::Result<MutexGuard<'_, Self>> {
+        wire.lock().map_err(|_| io::Error::other("Wire poisoned"))
+    }
+
+    pub fn waiting(&self) -> Option<Instant> { self.waiting }
+
+    pub fn park(&mut self, read: bool) -> io::Result<()> {
+        // oneshot: the poller wakes the connection once, when there is
+        // something to read (if read) or room for what is left to send
+        let event = Event::new(self.key, read, !self.out.is_empty());
+        if self.registered { return self.poller.modify(&self.tcp, event) }
+        // SAFETY: close deletes it, and nothing else closes the socket
+        unsafe { self.poller.add(&self.tcp, event)? };
+        self.registered = true;
+        Ok(())
+    }
+
+    pub fn fill(&mut self, input: &mut Vec<u8>) -> io::Result<Option<usize>> {
+        // moves a chunk of what has arrived (decrypted) to input and
+        // returns its size, 0 if nothing has; None once the peer is done
+        let mut chunk = [0; READ_CHUNK];
+        let Some(tls) = &mut self.tls else {
+            return match (&self.tcp).read(&mut chunk) {
+                Ok(0) => Ok(None),
+                Ok(len) => { input.extend_from_slice(&chunk[..len]); Ok(Some(len)) },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => Ok(Some(0)),
+                Err(e) => Err(e),
+            }
+        };
+
+        let open = match tls.read_tls(&mut &self.tcp) {
+            Ok(len) => len > 0,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => true,
+            Err(e) => return Err(e),
+        };
+        let res = tls.process_new_packets();
+        self.queue_tls()?;  // handshake messages or alerts
+        self.flush()?;
+        res.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+        // all of it: the poller can't see what rustls holds
+        let tls = self.tls.as_mut().unwrap();  // checked above
+        let start = input.len();
+        loop {
+            match tls.reader().read(&mut chunk) {
+                Ok(0) => return Ok(None),  // close_notify
+                Ok(len) => input.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(open.then_some(input.len() - start))
+    }
+
+    fn queue_tls(&mut self) -> io::Result<()> {
+        if let Some(tls) = &mut self.tls {
+            while tls.wants_write() { tls.write_tls(&mut self.out)?; }
+        }
+        Ok(())
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        // queues all of data, and sends what the socket takes now
+        if self.broken { return Err(ErrorKind::BrokenPipe.into()) }
+        if self.out.len() + data.len() > OUT_MAX {
+            self.broken = true;
+            return Err(io::Error::new(ErrorKind::TimedOut, "Too much unsent; reader too slow"))
+        }
+        match &mut self.tls {
+            Some(tls) => {
+                tls.writer().write_all(data)?;
+                self.queue_tls()?;
+            },
+            None => self.out.extend_from_slice(data),
+        }
+        self.flush()?;
+        if !self.out.is_empty() && self.registered {
+            // whoever has the connection parks it again the same way
+            let _ = self.poller.modify(&self.tcp, Event::all(self.key));
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        // sends what the socket takes right now
+        let mut sent = 0;
+        let res = loop {
+            if sent == self.out.len() { break Ok(()) }
+            match (&self.tcp).write(&self.out[sent..]) {
+                Ok(0) => break Err(ErrorKind::WriteZero.into()),
+                Ok(len) => sent += len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.out.drain(..sent);
+        self.waiting = match self.out.is_empty() {
+            true => None,
+            false if sent > 0 => Some(Instant::now()),
+            false => Some(self.waiting.unwrap_or_else(Instant::now)),
+        };
+        if res.is_err() { self.broken = true }
+        res
+    }
+
+    pub fn pending(&self) -> bool { !self.out.is_empty() }
+
+    pub fn close(&mut self) {
+        // tells the other side we're done; errors don't matter anymore
+        if self.registered { let _ = self.poller.delete(&self.tcp); }
+        self.registered = false;
+        if !self.broken {
+            if let Some(tls) = &mut self.tls { tls.send_close_notify() }
+            let _ = self.queue_tls().and_then(|_| self.flush());
+        }
+        self.broken = true;  // pushers drop it on their next write
+        let _ = self.tcp.shutdown(Shutdown::Both);
+    }
+}
+
+impl Outbox {
+    pub fn new(wire: &Arc<Mutex<Wire>>) -> Self {
+        Self(wire.clone())
+    }
+}
+
+impl Write for Outbox {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // all or nothing; a packet is never cut in half
+        Wire::lock(&self.0)?.send(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }  // goes as soon as it can anyway
+}
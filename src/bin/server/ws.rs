@@ -1,207 +1,155 @@
-use std::net::TcpStream;
-use std::collections::VecDeque;
-use std::io::{self, Write, Read, Error, ErrorKind};
+use std::io::{self, Write};
 use sha1_smol::Sha1;
 
 use publichat::helpers::*;
+use publichat::error::Error;
 
-pub struct WsStream {
-    tcp: TcpStream,
-    data: VecDeque<u8>,
-}
-
-impl WsStream {
-    pub fn new(tcp: TcpStream) -> Self {
-        // expects handshake to already be completed!
-        WsStream{ tcp, data: VecDeque::new() }
-    }
-
-    pub fn handshake(stream: &mut TcpStream, key_in: &str) -> Res {
-        // Takes a TcpStream and a key_in, responds with HTTP handshake packet
-        let mut hasher = Sha1::new();
-        hasher.update(key_in.as_bytes());
-        hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-        let key_out = base64::encode(hasher.digest().bytes());
-
-        const HS_END: &[u8] = b"\r\n\r\n";  // no allocs, but is it faster?
-        const HS_START: &[u8] = b"\
-            HTTP/1.1 101 Switching Protocols\r\n\
-            Upgrade: websocket\r\n\
-            Connection: Upgrade\r\n\
-            Sec-WebSocket-Accept: ";
-
-        full_write(
-            stream,
-            &[HS_START, key_out.as_bytes(), HS_END].concat(),
-            "Failed to send handshake response",
-        )
-    }
-
-    fn wrap(data: &[u8]) -> Option<Vec<u8>> {
-        let len = data.len();
-        let mut res: Vec<u8> = Vec::with_capacity(1 + 1 + 8 + len);
-
-        // push starting header
-        #[allow(clippy::unusual_byte_groupings)]  // this is ok
-        res.push(0b1_000_0010);  // header (fin=1; op=2(binary))
-
-        // push payload length
-        if len <= 125 {
-            res.push(len as u8);  // can't fail
-        } else if let Ok(len) = u16::try_from(len) {
-            res.push(126);
-            res.extend_from_slice(&len.to_be_bytes());
-        } else if let Ok(len) = u64::try_from(len) {
-            // happens if len > 65535 bytes. Should never happen in our case.
-            res.push(127);
-            res.extend_from_slice(&len.to_be_bytes());
-        } else {
-            return None;
-        }
-
-        // push the actual data
-        res.extend_from_slice(data);
-        Some(res)
-    }
+const MAX_PAYLOAD: usize = 64 * 1024;  // far more than any SMRT packet
 
-    fn parse_header(header: &[u8; 2]) -> Option<(bool, u8)> {
-        // parses first two bytes of WS packet
-        // returns None if header is invalid
-        // otherwise returns length of packet and whether it's a ping
-
-        let fin = header[0] & 0b1000_0000 != 0;
-        // RSVn bits ignored
-        let opc = header[0] & 0b0000_1111;
-        let msk = header[1] & 0b1000_0000 != 0;
-        let len = header[1] & 0b0111_1111;
+pub enum Frame {
+    Data(Vec<u8>),  // unmasked payload
+    Ping(Vec<u8>),
+    Close,
+}
 
-        if !msk { return None }  // clients MUST mask
-        if !fin && opc > 0x2 { return None }  // frag only defined for 0, 1, 2
+// Wraps each write in one binary frame
+pub struct WsWriter<W: Write>(pub W);
+
+pub fn handshake(stream: &mut impl Write, key_in: &str) -> Res {
+    // Takes a TcpStream and a key_in, responds with HTTP handshake packet
+    let mut hasher = Sha1::new();
+    hasher.update(key_in.as_bytes());
+    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let key_out = base64::encode(hasher.digest().bytes());
+
+    const HS_END: &[u8] = b"\r\n\r\n";  // no allocs, but is it faster?
+    const HS_START: &[u8] = b"\
+        HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: ";
+
+    full_write(
+        stream,
+        &[HS_START, key_out.as_bytes(), HS_END].concat(),
+        "Failed to send handshake response",
+    )
+}
 
-        match opc {
-            0xA => None,  // todo: all recieved pongs should be ignored
-            0x9 => if len > 125 { None } else { Some((true, len)) },  // pings musn't be longer than 125
-            _   => Some((false, len)),  // valid non-ping packet
-        }
+fn wrap(data: &[u8], op: u8) -> Option<Vec<u8>> {
+    let len = data.len();
+    let mut res: Vec<u8> = Vec::with_capacity(1 + 1 + 8 + len);
+
+    // push starting header
+    res.push(0b1000_0000 | op);  // header (fin=1)
+
+    // push payload length
+    if len <= 125 {
+        res.push(len as u8);  // can't fail
+    } else if let Ok(len) = u16::try_from(len) {
+        res.push(126);
+        res.extend_from_slice(&len.to_be_bytes());
+    } else if let Ok(len) = u64::try_from(len) {
+        // happens if len > 65535 bytes. Should never happen in our case.
+        res.push(127);
+        res.extend_from_slice(&len.to_be_bytes());
+    } else {
+        return None;
     }
 
-    fn pong(&mut self, header: &[u8; 2]) -> Option<()> {
-        // return a pong for a given ping
-        // reads data from self.tcp, so after parsing the header
-        // the tcp stream should be left untouched.
-        // assumes mask is given.
-
-        let len = header[1] & 0b0111_1111;
-        assert!(len <= 125);
-        let mut data = vec![0; (2 + len).into()];
+    // push the actual data
+    res.extend_from_slice(data);
+    Some(res)
+}
 
-        // add modified header
-        data.copy_from_slice(header);
-        data[0] ^= 0b11;  // flips last two bits op opcode (turn 0x9 into 0xA)
+fn parse_header(header: &[u8; 2]) -> Option<(bool, u8)> {
+    // parses first two bytes of WS packet
+    // returns None if header is invalid
+    // otherwise returns length of packet and whether it's a ping
 
-        // read remaining data into vec
-        self.tcp.read_exact(&mut data[2..]).ok()?;
-        
-        // send it off
-        self.tcp.write_all(&data).ok()?;
-        self.tcp.flush().ok()?;
+    let fin = header[0] & 0b1000_0000 != 0;
+    // RSVn bits ignored
+    let opc = header[0] & 0b0000_1111;
+    let msk = header[1] & 0b1000_0000 != 0;
+    let len = header[1] & 0b0111_1111;
 
-        Some(())
-    }
+    if !msk { return None }  // clients MUST mask
+    if !fin && opc > 0x2 { return None }  // frag only defined for 0, 1, 2
 
-    fn get_true_len(&mut self, len: u8) -> Option<usize> {
-        // reads following bytes from tcp if needed
-        match len {
-            len if len <= 125 => Some(len.into()),
-            126 => {
-                let mut buf = [0; 2];
-                self.tcp.read_exact(&mut buf).ok()?;
-                Some(u16::from_be_bytes(buf).into())
-            },
-            127 => {
-                let mut buf = [0; 8];
-                self.tcp.read_exact(&mut buf).ok()?;
-                Some(u64::from_be_bytes(buf).try_into().expect("can't unwrap u64 into usize"))
-            },
-            _ => None,  // bigger handled already
-        }
+    match opc {
+        0xA => None,  // todo: all recieved pongs should be ignored
+        0x9 => if len > 125 { None } else { Some((true, len)) },  // pings musn't be longer than 125
+        _   => Some((false, len)),  // valid non-ping packet
     }
+}
 
-    fn decode(data: &mut [u8]) {
-        // First four bytes are the mask. Xor the rest of the bytes with these
-        // let mut mask = [0; 4];
-        let mask = [
-            data[0],
-            data[1],
-            data[2],
-            data[3],
-        ];
-
-        data[4..].iter_mut()
-            .zip(mask.iter().cycle())
-            .for_each(|(byte, mask)| *byte ^= mask);
-    }
+pub fn pong(ping: &[u8]) -> Vec<u8> {
+    // return a pong for a given ping's payload
+    wrap(ping, 0xA).unwrap()  // pings are at most 125 bytes
 }
 
-impl Read for WsStream {
-    fn read(&mut self, dest_buf: &mut [u8]) -> io::Result<usize> {
-        // Reads some data to the buffer. IF reading from TCP is
-        // needed, will read exactly ONE ws packet. Call read_exact
-        // to fill buffer completely.
-
-        while self.data.is_empty() {  // `if` would've probably been ok too
-            // no data in the buffer: read from TCP
-
-            let mut header_buf = [0; 2];
-            self.tcp.read_exact(&mut header_buf)?;
-    
-            // get len byte from packet
-            let len = loop {  // loop to get rid of all pings
-                match Self::parse_header(&header_buf) {
-                    None => {
-                        return Err(Error::from(ErrorKind::Other))
-                    },
-                    Some((true, _)) => if self.pong(&header_buf).is_none() {
-                        return Err(Error::from(ErrorKind::Other))
-                    },
-                    Some((false, len)) => break len,
-                }
-            };
-    
-            // convert len byte into actual length
-            let len = match self.get_true_len(len) {
-                Some(len) => len,
-                None => return Err(Error::from(ErrorKind::Other))
-            };
-    
-            // get data
-            let mut recieved_data = vec![0; 4+len];
-            self.tcp.read_exact(&mut recieved_data)?;
-
-            // decode (first four bytes are mask)
-            Self::decode(&mut recieved_data);
-    
-            // add to main buffer
-            self.data.extend(&recieved_data[4..]);
-        }
+fn decode(data: &mut [u8]) {
+    // First four bytes are the mask. Xor the rest of the bytes with these
+    // let mut mask = [0; 4];
+    let mask = [
+        data[0],
+        data[1],
+        data[2],
+        data[3],
+    ];
+
+    data[4..].iter_mut()
+        .zip(mask.iter().cycle())
+        .for_each(|(byte, mask)| *byte ^= mask);
+}
 
-        // There's something in the queue - move it to the buffer
-        let len = self.data.len().min(dest_buf.len());  // how many bytes can be filled in
-        dest_buf[..len].fill_with(|| self.data.pop_front().unwrap());  // can't fail
-        Ok(len)
-    }
+pub fn frame(buf: &[u8]) -> Res<Option<(Frame, usize)>> {
+    // The frame at the start of buf and its size, once all of it has arrived
+    let Some(&[b0, b1]) = buf.get(..2) else { return Ok(None) };
+    let Some((ping, len)) = parse_header(&[b0, b1]) else {
+        return Err(Error::protocol("Bad WS header"));
+    };
+    let close = b0 & 0b0000_1111 == 0x8;
+
+    // convert len byte into actual length
+    let (len, start) = match len {
+        126 => match buf.get(2..4) {
+            Some(len) => (u16::from_be_bytes(len.try_into().unwrap()).into(), 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => (usize::try_from(u64::from_be_bytes(len.try_into().unwrap())).unwrap_or(usize::MAX), 10),
+            None => return Ok(None),
+        },
+        len => (len.into(), 2),
+    };
+    if len > MAX_PAYLOAD { return Err(Error::limit("WS frame too large")) }
+
+    // get data (first four bytes are mask)
+    let end = start + 4 + len;
+    let Some(data) = buf.get(start..end) else { return Ok(None) };
+    let mut data = data.to_vec();
+    decode(&mut data);
+    let payload = data.split_off(4);
+
+    let frame = match (ping, close) {
+        (true, _) => Frame::Ping(payload),
+        (_, true) => Frame::Close,
+        _ => Frame::Data(payload),
+    };
+    Ok(Some((frame, end)))
 }
 
-impl Write for WsStream {
+impl<W: Write> Write for WsWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match Self::wrap(buf) {
+        match wrap(buf, 0x2) {  // binary
             Some(data) => {
-                self.tcp.write_all(&data)?;
+                self.0.write_all(&data)?;
                 Ok(buf.len())
             },
-            None => Err(Error::from(ErrorKind::Other)),
+            None => Err(io::Error::from(io::ErrorKind::Other)),
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> { self.tcp.flush() }
+    fn flush(&mut self) -> io::Result<()> { self.0.flush() }
 }
@@ -19,15 +19,15 @@ macro_rules! build_buf {
             type NTupleMut<'a> = ($( rep!($len; &'a mut [u8]), )*);
 
             #[allow(unused_assignments)]
-            #[allow(clippy::eval_order_dependence)]
-            pub fn split(buf: &Buf) -> NTuple {
+            #[allow(clippy::mixed_read_write_in_expression)]
+            pub fn split(buf: &Buf) -> NTuple<'_> {
                 let mut buf = buf.as_slice();
                 ($({let (cur, new) = buf.split_at($len); buf = new; cur},)*)
             }
 
             #[allow(unused_assignments)]
-            #[allow(clippy::eval_order_dependence)]
-            pub fn split_mut(buf: &mut Buf) -> NTupleMut {
+            #[allow(clippy::mixed_read_write_in_expression)]
+            pub fn split_mut(buf: &mut Buf) -> NTupleMut<'_> {
                 let mut buf = buf.as_mut_slice();
                 ($({let (cur, new) = buf.split_at_mut($len); buf = new; cur},)*)
             }
@@ -56,10 +56,10 @@ macro_rules! prepad {  // apply pad_buf
         use super::pad;
         pub type PadBuf = [u8; SIZE + 2*PADDING_SIZE];
         pub const PREPAD: PadBuf = super::pad_buf($pad);
-        pub fn pad_split(buf: &PadBuf) -> NTuple {
+        pub fn pad_split(buf: &PadBuf) -> NTuple<'_> {
             split(buf[PADDING_SIZE..][..SIZE].try_into().unwrap())
         }
-        pub fn pad_split_mut(buf: &mut PadBuf) -> NTupleMut {
+        pub fn pad_split_mut(buf: &mut PadBuf) -> NTupleMut<'_> {
             split_mut((&mut buf[PADDING_SIZE..][..SIZE]).try_into().unwrap())
         }
     };
@@ -77,26 +77,66 @@ build_buf!(pad; PADDING_SIZE;
     pub const SEND_PADDING:  [u8; PADDING_SIZE] = *b"snd";
     pub const FETCH_PADDING: [u8; PADDING_SIZE] = *b"fch";
     pub const QUERY_PADDING: [u8; PADDING_SIZE] = *b"qry";
+    pub const TIME_PADDING:  [u8; PADDING_SIZE] = *b"tim";
+    pub const HELLO_PADDING: [u8; PADDING_SIZE] = *b"hlo";  // both ways
+    pub const ACK_PADDING:   [u8; PADDING_SIZE] = *b"ack";
+    pub const SUB_PADDING:   [u8; PADDING_SIZE] = *b"sub";
+    pub const UNSUB_PADDING: [u8; PADDING_SIZE] = *b"uns";
     pub const END_PADDING:   [u8; PADDING_SIZE] = *b"end";
 
     // client -> server
     pub const MSG_PADDING:   [u8; PADDING_SIZE] = *b"msg";
+    pub const ERROR_PADDING: [u8; PADDING_SIZE] = *b"err";
 );
 
 // server -> client
 build_buf!(msg_head; PADDING_SIZE, 1, MSG_ID_SIZE, 1;
     pub use super::pad::MSG_PADDING as PAD;  // includes padding
 );
+build_buf!(hello_out; PADDING_SIZE, 1, FEATURES_SIZE;
+    pub use super::pad::HELLO_PADDING as PAD;  // includes padding
+);
+build_buf!(err_head; PADDING_SIZE, 1, 1;  // followed by the text
+    pub use super::pad::ERROR_PADDING as PAD;  // includes padding
+);
+build_buf!(ack; PADDING_SIZE, 1, MSG_ID_SIZE, TIME_SIZE;
+    pub use super::pad::ACK_PADDING as PAD;  // includes padding
+);
+build_buf!(msg_head_wide; PADDING_SIZE, 1, WIDE_ID_SIZE, 1;  // with FEATURE_WIDE_IDS
+    pub use super::pad::MSG_PADDING as PAD;  // includes padding
+);
+build_buf!(ack_wide; PADDING_SIZE, 1, WIDE_ID_SIZE, TIME_SIZE;  // with FEATURE_WIDE_IDS
+    pub use super::pad::ACK_PADDING as PAD;  // includes padding
+);
 build_buf!(msg_out_c; TIME_SIZE, CYPHER_SIZE, SIGNATURE_SIZE);
 build_buf!(msg_out_s; TIME_SIZE, CYPHER_SIZE + SIGNATURE_SIZE);
     // same size as msg_out, but combines cypher with signature
     // because server doesn't need the distinction.
 
 // client -> server
+build_buf!(hello; 1, FEATURES_SIZE; prepad!(pad::HELLO_PADDING););
 build_buf!(fetch; CHAT_ID_SIZE; prepad!(pad::FETCH_PADDING););
+build_buf!(fetch_v1; CHAT_ID_SIZE, 1, 1; prepad!(pad::FETCH_PADDING););
+    // chat id, version, count
 build_buf!(query; CHAT_ID_SIZE, 1, MSG_ID_SIZE; prepad!(pad::QUERY_PADDING););
+build_buf!(query_wide; CHAT_ID_SIZE, 1, WIDE_ID_SIZE; prepad!(pad::QUERY_PADDING););
+build_buf!(time_query; CHAT_ID_SIZE, 1, TIME_SIZE; prepad!(pad::TIME_PADDING););
+    // same count/direction byte as query, then a server time (ms)
+build_buf!(sub; CHAT_ID_SIZE; prepad!(pad::SUB_PADDING););
+build_buf!(unsub; CHAT_ID_SIZE; prepad!(pad::UNSUB_PADDING););
 build_buf!(msg_in_c; CHAT_ID_SIZE, CYPHER_SIZE, SIGNATURE_SIZE; prepad!(pad::SEND_PADDING););
+build_buf!(msg_in_c_v2; CHAT_ID_SIZE, CYPHER_SIZE, SIGNATURE_SIZE, STAMP_SIZE; prepad!(pad::SEND_PADDING););
 build_buf!(msg_in_s; CHAT_ID_SIZE, CYPHER_SIZE + SIGNATURE_SIZE);
+build_buf!(stamp; STAMP_SIZE);
 
 // client-side
 build_buf!(cypher; CYPHER_CHAT_KEY_SIZE, TIME_SIZE, HASH_SIZE, CYPHER_PAD_MSG_SIZE);
+build_buf!(cypher_v1;
+    CYPHER_VERSION_SIZE, CYPHER_NONCE_SIZE,  // plaintext IV
+    CYPHER_CHAT_KEY_SIZE, TIME_SIZE, HASH_SIZE, CYPHER_V1_PAD_MSG_SIZE
+);
+build_buf!(cypher_v2;
+    CYPHER_VERSION_SIZE, CYPHER_AEAD_NONCE_SIZE,  // plaintext
+    TIME_SIZE, HASH_SIZE, CYPHER_MSG_LEN_SIZE, CYPHER_V2_PAD_MSG_SIZE,
+    CYPHER_AEAD_TAG_SIZE
+);
@@ -5,12 +5,23 @@ Get message from client:
     - PADDING                                       3
     - Chat ID                                       32
     - Cypher  // content unknown to server          440
+        - Version (v1 only, plaintext)              1
+        - Nonce (v1 only, plaintext)                15
         - Chat key (first 4 bytes)                  4
         - Client time                               8
         - Public key                                32
-        - Encrypted Message                         <396
-        - Padding to cypher size                    <=396
+        - Encrypted Message                         <380 (v0: <396)
+        - Padding to cypher size                    <=380 (v0: <=396)
     - Signature                                     64
+
+    Cypher v2 (AES-256-GCM)                         440
+        - Version (plaintext, authenticated)        1
+        - Nonce (plaintext)                         12
+        - Client time                               8
+        - Public key                                32
+        - Message length                            2
+        - Message and padding                       369
+        - Authentication tag                        16
     - PADDING                                       3
 
 Storage block                                       512
@@ -31,6 +42,7 @@ pub const CHAT_ID_SIZE: usize = HASH_SIZE;  // chat ID is a hash
 pub const QUERY_ARG_SIZE: usize = std::mem::size_of::<u32>();
 pub const TIME_SIZE: usize = std::mem::size_of::<u64>();
 pub const MSG_ID_SIZE: usize = QUERY_ARG_SIZE - 1;
+pub const MAX_MSG_COUNT: u8 = 0x7f;  // message counts are 7 bits on the wire
 //pub const QUERY_DIRECTION_COUNT: usize = QUERY_ARG_SIZE - MSG_ID_SIZE;
 
 // Sizes of incoming message
@@ -63,11 +75,72 @@ pub const MSG_OUT_SIZE: usize           = MSG_OUT_SIG + SIGNATURE_SIZE;
 pub const FCH_CHAT_ID: usize            = 0;
 pub const FCH_SIZE: usize               = FCH_CHAT_ID + CHAT_ID_SIZE;
 
+// Fetch v1 adds a version and a count (0: server default) after the chat id.
+// v0 has the end pad right there, which is how the server tells them apart.
+pub const FCH_V1: u8                    = 1;  // version marker; must not be b'e'
+pub const FCH_V1_VERSION: usize         = FCH_SIZE;
+pub const FCH_V1_COUNT: usize           = FCH_V1_VERSION + 1;
+pub const FCH_V1_SIZE: usize            = FCH_V1_COUNT + 1;
+
 // Sizes of incoming query packets
 pub const QRY_CHAT_ID: usize            = 0;
 pub const QRY_ARGS: usize               = QRY_CHAT_ID + CHAT_ID_SIZE;  // direction and amount
 pub const QRY_SIZE: usize               = QRY_ARGS + QUERY_ARG_SIZE;
 
+// Handshake: an optional first packet, client and server each send
+// a protocol version and the features they know. Both use the lower
+// version and the features they share. No handshake means version 0
+// and no optional features.
+// Version 2: the server's hello ends with the proof of work difficulty (bits),
+// and snd packets have a stamp before their end pad. The stamp is good if
+// SHA3-256 of the packet from chat id to stamp starts with that many zero bits.
+pub const PROTOCOL_VERSION: u8          = 2;
+pub const MIN_PROTOCOL_VERSION: u8      = 1;  // lower ones get an error packet
+pub const FEATURES_SIZE: usize          = std::mem::size_of::<u32>();
+pub const FEATURE_FETCH_COUNT: u32      = 1 << 0;  // fetch v1
+pub const FEATURE_TIME_QUERY: u32       = 1 << 1;  // tim packets
+pub const FEATURE_SEND_ACK: u32         = 1 << 2;  // ack packets
+pub const FEATURE_WIDE_IDS: u32         = 1 << 3;  // 8-byte ids, see below
+pub const POW_VERSION: u8               = 2;  // first version with stamps
+pub const POW_BITS_MAX: u8              = 32;  // more takes clients too long
+pub const STAMP_SIZE: usize             = std::mem::size_of::<u64>();
+
+// Message ids are 3 bytes on the wire, which covers a chat's first 2^24
+// messages. With FEATURE_WIDE_IDS, msg heads, acks and qry packets carry
+// 8-byte ids instead. Clients without it can't send to a chat past that,
+// and get ERR_TOO_LARGE for messages they can't be told the id of.
+pub const WIDE_ID_SIZE: usize           = std::mem::size_of::<u64>();
+
+// Acks answer each snd packet, in order, when both sides know them:
+// status (0: stored, else an error code below), message id, server time.
+// Id and time are zero if the message wasn't stored.
+pub const ACK_STORED: u8                = 0;
+
+// Error packets: code, text length, text (for people, not programs).
+// The connection is closed after, except for ERR_RATE_LIMITED: then only
+// that request was dropped (sends with acks get it in their ack instead).
+pub const ERR_TEXT_MAX: usize           = u8::MAX as usize;
+pub const ERR_UNSUPPORTED_VERSION: u8   = 1;
+pub const ERR_BAD_FRAMING: u8           = 2;  // pads, lengths, unknown packets
+pub const ERR_RATE_LIMITED: u8          = 3;  // slow down and try again
+pub const ERR_TOO_LARGE: u8             = 4;  // chat (or request) too large
+pub const ERR_SERVER_BUSY: u8           = 5;  // too many connections; try later
+pub const ERR_SERVER: u8                = 6;  // storage or other trouble on our end
+pub const ERR_NEEDS_WORK: u8            = 7;  // stamp missing or too weak (acks only)
+
+
+// CLIENT-SIDE: KEY DERIVATION
+// Chat key (from title) and signing secret (from username) are derived with
+// Argon2id. Every client MUST use the same parameters and salts, otherwise
+// they will end up in different chats with different identities.
+// Version 0 derived both with a single SHA3-256; chats from then keep their
+// (SHA3) ids, clients fall back to them if the v1 chat is empty.
+pub const KDF_VERSION: u8               = 1;
+pub const KDF_MEM_KIB: u32              = 19 * 1024;
+pub const KDF_ITERATIONS: u32           = 2;
+pub const KDF_LANES: u32                = 1;
+pub const KDF_TITLE_SALT: &[u8]         = b"publichat-title-v1";
+pub const KDF_USER_SALT: &[u8]          = b"publichat-user-v1";
 
 // CLIENT-SIDE: CYPHER CONTENTS
 pub const CYPHER_CHAT_KEY_SIZE: usize   = 4;
@@ -78,3 +151,36 @@ pub const CYPHER_TIME: usize            = CYPHER_CHAT_KEY + CYPHER_CHAT_KEY_SIZE
 pub const CYPHER_PUB_KEY: usize         = CYPHER_TIME + TIME_SIZE;
 pub const CYPHER_PAD_MSG: usize         = CYPHER_PUB_KEY + HASH_SIZE;
 pub const CYPHER_SIZE: usize            = CYPHER_PAD_MSG + CYPHER_PAD_MSG_SIZE;
+
+// CLIENT-SIDE: CYPHER CONTENTS v1
+// Version and nonce are not encrypted; together they form the AES-CTR IV.
+// v0 (above) is encrypted as a whole with a fixed IV.
+pub const CYPHER_V1: u8                 = 1;  // version marker
+pub const CYPHER_VERSION_SIZE: usize    = 1;
+pub const CYPHER_NONCE_SIZE: usize      = 15;
+pub const CYPHER_IV_SIZE: usize         = CYPHER_VERSION_SIZE + CYPHER_NONCE_SIZE;
+pub const CYPHER_V1_PAD_MSG_SIZE: usize = CYPHER_PAD_MSG_SIZE - CYPHER_IV_SIZE;
+
+pub const CYPHER_V1_VERSION: usize      = 0;
+pub const CYPHER_V1_NONCE: usize        = CYPHER_V1_VERSION + CYPHER_VERSION_SIZE;
+pub const CYPHER_V1_CHAT_KEY: usize     = CYPHER_V1_NONCE + CYPHER_NONCE_SIZE;
+pub const CYPHER_V1_TIME: usize         = CYPHER_V1_CHAT_KEY + CYPHER_CHAT_KEY_SIZE;
+pub const CYPHER_V1_PUB_KEY: usize      = CYPHER_V1_TIME + TIME_SIZE;
+pub const CYPHER_V1_PAD_MSG: usize      = CYPHER_V1_PUB_KEY + HASH_SIZE;
+pub const CYPHER_V1_SIZE: usize         = CYPHER_V1_PAD_MSG + CYPHER_V1_PAD_MSG_SIZE;
+
+// CLIENT-SIDE: CYPHER CONTENTS v2
+// AEAD; the tag replaces the chat key check, the length replaces the pad indicator.
+pub const CYPHER_V2: u8                 = 2;  // version marker
+pub const CYPHER_AEAD_NONCE_SIZE: usize = 12;
+pub const CYPHER_AEAD_TAG_SIZE: usize   = 16;
+pub const CYPHER_MSG_LEN_SIZE: usize    = std::mem::size_of::<u16>();
+
+pub const CYPHER_V2_VERSION: usize      = 0;
+pub const CYPHER_V2_NONCE: usize        = CYPHER_V2_VERSION + CYPHER_VERSION_SIZE;
+pub const CYPHER_V2_TIME: usize         = CYPHER_V2_NONCE + CYPHER_AEAD_NONCE_SIZE;  // encrypted from here
+pub const CYPHER_V2_PUB_KEY: usize      = CYPHER_V2_TIME + TIME_SIZE;
+pub const CYPHER_V2_MSG_LEN: usize      = CYPHER_V2_PUB_KEY + HASH_SIZE;
+pub const CYPHER_V2_PAD_MSG: usize      = CYPHER_V2_MSG_LEN + CYPHER_MSG_LEN_SIZE;
+pub const CYPHER_V2_TAG: usize          = CYPHER_SIZE - CYPHER_AEAD_TAG_SIZE;  // encrypted until here
+pub const CYPHER_V2_PAD_MSG_SIZE: usize = CYPHER_V2_TAG - CYPHER_V2_PAD_MSG;
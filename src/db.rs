@@ -0,0 +1,1220 @@
+// Each chat is a directory:
+//     index               header, then the server time of each segment's first message
+//     0000000000000000    segments of (up to) segment length messages, named in hex.
+//     0000000000000001    Only the last one is still appended to.
+// Message ids count from the very first message of the chat, so
+// id = segment number * segment length + position in the segment.
+// Retention forgets messages from the front: ids below the first id are gone,
+// and so are the segments holding only those (the last one always stays).
+// Chats from before segments (one flat file) are converted when first opened.
+// A crash can leave half a message at the end of a segment (or index). Readers
+// ignore it; the next push moves it to a .torn file next to the segment.
+
+use std::io::{self, Seek, SeekFrom, BufReader, Read, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockWriteGuard};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use std::{mem, thread};
+
+use crate::log;
+use crate::layout::{self, Layout};
+
+use crate::helpers::*;
+use crate::error::Error;
+use crate::constants::{TIME_SIZE, MAX_MSG_COUNT};
+use crate::buffers::hash::Buf as HashBuf;
+use crate::store::{ChatStore, ChatStat, Messages, fetch_range, query_range, time_range, time_of};
+use crate::buffers::msg_out_s::{
+    Buf as MsgBuf,
+    SIZE as MSG_SIZE,
+};
+
+const MSG_SIZE_U64: u64 = MSG_SIZE as u64;
+
+const SEGMENT_LEN: u64 = 1 << 16;  // messages per new segment; 32 MiB
+
+const INDEX_FILE: &str = "index";
+const INDEX_MAGIC: [u8; 4] = *b"PCI2";
+const INDEX_HEADER_SIZE: u64 = 24;  // magic, segment length (u32), first segment, first id (u64s)
+const INDEX_MAGIC_V1: [u8; 4] = *b"PCI1";  // still read; first id is where the first segment starts
+const INDEX_HEADER_SIZE_V1: u64 = 16;
+const INDEX_ENTRY_SIZE: u64 = TIME_SIZE as u64;
+
+// Each chat in use has a lock in Files.chats. Pushing and trimming hold it
+// exclusively, so a reader always sees whole messages and an id is taken
+// by exactly one push. The lock also holds a cache of the chat: its length
+// and newest messages, so reads near the end don't touch the disk.
+// Idle ones are dropped once there are more than limits.cache_chats.
+type ChatLock = Arc<RwLock<ChatCache>>;
+
+#[derive(Default)]
+struct ChatCache {  // what the first read found, kept up to date by pushes
+    warm: bool,  // false: the rest means nothing yet
+    first_id: u64,  // oldest message kept
+    len: u64,  // id of the next message
+    newest: VecDeque<MsgBuf>,  // the ones just before len, oldest first
+}
+
+pub struct Files {  // the ChatStore in the data directory
+    dir: PathBuf,
+    layout: Layout,
+    cache_msgs: usize,
+    cache_chats: usize,
+    chats: Mutex<HashMap<PathBuf, ChatLock>>,  // chat path -> its lock and cache
+}
+
+#[derive(Clone, Copy)]
+pub enum Fsync {
+    Never,  // the OS writes back whenever
+    Always,  // every message, before it is acked
+    Every(Duration),  // by a background thread; a crash loses at most this much
+}
+
+static FSYNC: OnceLock<Fsync> = OnceLock::new();  // Never if not set
+static DIRTY: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);  // for Fsync::Every
+
+pub fn init(fsync: Fsync) -> io::Result<()> {
+    let _ = FSYNC.set(fsync);
+    let Fsync::Every(interval) = fsync else { return Ok(()) };
+    thread::Builder::new().name("fsync".to_string()).spawn(move || loop {
+        thread::sleep(interval);
+        let dirty = mem::take(&mut *DIRTY.lock().unwrap_or_else(|e| e.into_inner()));
+        for path in dirty.into_iter().flatten() {
+            match File::open(&path).and_then(|f| f.sync_all()) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {  // trimmed is fine
+                    log!(Error, "Failed to sync {}:\n\t{e}", path.display())
+                },
+                _ => {},
+            }
+        }
+    }).map(drop)
+}
+
+fn sync(file: &File, path: &Path) -> Res {
+    // makes writes to file durable, now or soon, as configured
+    match FSYNC.get().unwrap_or(&Fsync::Never) {
+        Fsync::Never => Ok(()),
+        Fsync::Always => file.sync_data().map_err(|e| Error::file("Failed to sync", e)),
+        Fsync::Every(_) => {
+            let mut dirty = DIRTY.lock().map_err(|_| Error::internal("Failed to lock dirty files"))?;
+            dirty.get_or_insert_with(HashSet::new).insert(path.to_path_buf());
+            Ok(())
+        },
+    }
+}
+
+fn sync_dir(dir: &Path) -> Res {
+    // same, for new (or renamed) files in dir
+    match FSYNC.get().unwrap_or(&Fsync::Never) {
+        Fsync::Never => Ok(()),
+        _ => sync(&File::open(dir).map_err(|e| Error::file("Failed to open directory", e))?, dir),
+    }
+}
+
+fn quarantine(path: &Path, file: &File, keep: u64) -> Res {
+    // cuts file down to keep bytes; the rest goes to path.torn, for people to look at
+    let mut tail = Vec::new();
+    let mut reader = File::open(path).map_err(|e| Error::file("Failed to open torn file", e))?;
+    reader.seek(SeekFrom::Start(keep)).map_err(|e| Error::file("Failed to seek", e))?;
+    reader.read_to_end(&mut tail).map_err(|e| Error::file("Failed to read torn data", e))?;
+
+    let torn = path.with_extension("torn");
+    let mut out = OpenOptions::new().append(true).create(true).open(&torn)
+        .map_err(|e| Error::file("Failed to open quarantine", e))?;
+    full_write(&mut out, &tail, "Failed to quarantine torn data")?;
+    out.sync_all().map_err(|e| Error::file("Failed to sync quarantine", e))?;  // before it's gone
+
+    file.set_len(keep).map_err(|e| Error::file("Failed to truncate torn file", e))?;
+    log!(Info, "Moved {} torn bytes from {} to {}", tail.len(), path.display(), torn.display());
+    Ok(())
+}
+
+fn warm(cache: &mut ChatCache, path: &Path, keep: usize) -> Res {
+    // fills the cache from disk
+    *cache = ChatCache::default();
+    if let Some(chat) = Chat::open(path)? {
+        let count = (chat.len - chat.first_id).min(keep as u64);
+        let start = chat.len - count;
+        for id in (start..chat.len).step_by(MAX_MSG_COUNT.into()) {
+            let count = (chat.len - id).min(MAX_MSG_COUNT.into()) as u8;  // fits, it's a min
+            let msgs = chat.read(id, count)?;
+            cache.newest.extend(msgs.chunks_exact(MSG_SIZE).map(|m| MsgBuf::try_from(m).unwrap()));
+        }
+        (cache.first_id, cache.len) = (chat.first_id, chat.len);
+    }
+    cache.warm = true;
+    Ok(())
+}
+
+fn read(path: &Path, cache: &ChatCache, start: u64, count: u8) -> Res<Vec<u8>> {
+    // messages start..start+count, from memory if they are all there
+    let cached = cache.len - cache.newest.len() as u64;  // first id in memory
+    if start < cached {
+        let chat = Chat::open(path)?.ok_or(Error::corruption("Chat missing"))?;
+        return chat.read(start, count);
+    }
+    let mut res = Vec::with_capacity(count as usize * MSG_SIZE);
+    let first = (start - cached) as usize;
+    for msg in cache.newest.range(first..first + count as usize) { res.extend_from_slice(msg) }
+    Ok(res)
+}
+
+fn find_time(path: &Path, cache: &ChatCache, time: u64) -> Res<u64> {
+    // like Chat::find_time, but looks in memory first
+    let cached = cache.len - cache.newest.len() as u64;
+    if cached == cache.first_id || cache.newest.front().is_some_and(|msg| time_of(msg) < time) {
+        return Ok(cached + cache.newest.partition_point(|msg| time_of(msg) < time) as u64);
+    }
+    Chat::open(path)?.ok_or(Error::corruption("Chat missing"))?.find_time(time)
+}
+
+fn trimmed(cache: &mut ChatCache, chat: &Chat, ok: bool) {
+    // forgets what the chat just forgot
+    if !ok { cache.warm = false; return }
+    let forgotten = chat.first_id.saturating_sub(cache.len - cache.newest.len() as u64);
+    cache.newest.drain(..(forgotten as usize).min(cache.newest.len()));
+    cache.first_id = chat.first_id;
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{segment:016x}"))
+}
+
+fn has_segments(dir: &Path) -> Res<bool> {
+    let files = fs::read_dir(dir).map_err(|e| Error::file("Failed to list chat", e))?;
+    for file in files {
+        let name = file.map_err(|e| Error::file("Failed to list chat", e))?.file_name();
+        if name.len() == 16 && name.to_str().is_some_and(|n| u64::from_str_radix(n, 16).is_ok()) {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
+
+fn open(path: &Path, options: &OpenOptions) -> Res<Option<File>> {
+    // None if the file doesn't exist
+    match options.open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::file("Failed to open file", e)),
+    }
+}
+
+fn read_time(file: &mut File, pos: u64) -> Res<u64> {
+    let mut time = [0; TIME_SIZE];
+    file.seek(SeekFrom::Start(pos)).map_err(|e| Error::file("Failed to seek", e))?;
+    read_file_exact(file, &mut time, "Failed to read time")?;
+    Ok(u64::from_be_bytes(time))
+}
+
+fn partition(len: u64, mut before: impl FnMut(u64) -> Res<bool>) -> Res<u64> {
+    // binary search: first i in 0..len that isn't before, or len
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if before(mid)? { lo = mid + 1 } else { hi = mid }
+    }
+    Ok(lo)
+}
+
+fn file_len(file: &File) -> Res<u64> {
+    Ok(file.metadata().map_err(|e| Error::file("Failed to get metadata", e))?.len())
+}
+
+struct Chat {
+    dir: PathBuf,
+    segment_len: u64,
+    first_segment: u64,  // oldest segment kept
+    segments: u64,  // number of segments, from first_segment on
+    first_id: u64,  // oldest message kept
+    len: u64,  // id of the next message
+    entries_at: u64,  // index header size
+}
+
+impl Chat {
+    fn open(dir: &Path) -> Res<Option<Self>> {
+        // None if the chat has no messages yet
+        let flat = dir.with_extension("flat");
+        if !dir.exists() && flat.is_file() {  // conversion died between renames
+            fs::rename(&flat, dir).map_err(|e| Error::file("Failed to restore flat chat", e))?;
+        }
+        if dir.is_file() { convert(dir)? }
+
+        let Some(mut index) = open(&dir.join(INDEX_FILE), OpenOptions::new().read(true))? else {
+            return Ok(None);
+        };
+        if file_len(&index)? < INDEX_HEADER_SIZE && !has_segments(dir)? {
+            return Ok(None);  // a crash while creating it, before anything was pushed
+        }
+        let mut header = [0; INDEX_HEADER_SIZE as usize];
+        read_file_exact(&mut index, &mut header[..INDEX_HEADER_SIZE_V1 as usize], "Failed to read index header")?;
+        let segment_len = u32::from_be_bytes(header[4..8].try_into().unwrap()).into();
+        let first_segment = u64::from_be_bytes(header[8..16].try_into().unwrap());
+        let (first_id, entries_at) = match header[..4].try_into().unwrap() {
+            INDEX_MAGIC => {
+                read_file_exact(&mut index, &mut header[16..], "Failed to read index header")?;
+                (u64::from_be_bytes(header[16..].try_into().unwrap()), INDEX_HEADER_SIZE)
+            },
+            INDEX_MAGIC_V1 => (first_segment * segment_len, INDEX_HEADER_SIZE_V1),
+            _ => return Err(Error::corruption("Unknown index format")),
+        };
+
+        if segment_len == 0 { return Err(Error::corruption("Index has no segment length")) }
+        let entries = file_len(&index)? - entries_at;  // can't underflow, header was read
+        let segments = entries / INDEX_ENTRY_SIZE;  // a torn entry is ignored
+
+        let len = match segments.checked_sub(1) {
+            None => first_segment * segment_len,
+            Some(last) => {
+                let last = first_segment + last;
+                let size = match open(&segment_path(dir, last), OpenOptions::new().read(true))? {
+                    Some(file) => file_len(&file)?,
+                    None => 0,  // indexed, but the first message never made it
+                };
+                last * segment_len + (size / MSG_SIZE_U64).min(segment_len)  // a torn one is ignored
+            },
+        };
+        if first_id < first_segment * segment_len || first_id > len {
+            return Err(Error::corruption("First id outside of the chat"));
+        }
+
+        let dir = dir.to_path_buf();
+        Ok(Some(Self { dir, segment_len, first_segment, segments, first_id, len, entries_at }))
+    }
+
+    fn create(dir: &Path) -> Res<Self> {
+        // makes an empty chat where open found none; replaces a half-made index
+        let parent = dir.parent().unwrap();  // in the data directory
+        fs::create_dir_all(parent).map_err(|e| Error::file("Failed to create shard", e))?;
+        match fs::create_dir(dir) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+                return Err(Error::file("Failed to create chat directory", e))
+            },
+            _ => {},
+        }
+        let chat = Self {
+            dir: dir.to_path_buf(),
+            segment_len: SEGMENT_LEN,
+            first_segment: 0,
+            segments: 0,
+            first_id: 0,
+            len: 0,
+            entries_at: INDEX_HEADER_SIZE,
+        };
+
+        // written next to it and renamed in, so the index is never half a header
+        let (path, new) = (dir.join(INDEX_FILE), dir.join(INDEX_FILE).with_extension("new"));
+        let mut index = File::create(&new).map_err(|e| Error::file("Failed to create index", e))?;
+        full_write(&mut index, &chat.header(), "Failed to write index header")?;
+        index.sync_all().map_err(|e| Error::file("Failed to sync index", e))?;  // once per chat
+        fs::rename(&new, &path).map_err(|e| Error::file("Failed to move index", e))?;
+        sync_dir(dir)?;
+        sync_dir(parent)?;
+        Ok(chat)
+    }
+
+    fn header(&self) -> [u8; INDEX_HEADER_SIZE as usize] {
+        let mut header = [0; INDEX_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&INDEX_MAGIC);
+        header[4..8].copy_from_slice(&(self.segment_len as u32).to_be_bytes());  // came from a u32
+        header[8..16].copy_from_slice(&self.first_segment.to_be_bytes());
+        header[16..].copy_from_slice(&self.first_id.to_be_bytes());
+        header
+    }
+
+    fn first_id(&self) -> u64 {
+        self.first_id
+    }
+
+    fn push(&mut self, msg: &MsgBuf) -> Res<u64> {
+        // Returns id of the pushed message
+        // Files are checked against what open() saw; anything more is
+        // left by a crash (half a message, or a segment never indexed).
+        let segment = self.len / self.segment_len;
+        if segment == self.first_segment + self.segments {  // starts a new segment
+            let path = self.dir.join(INDEX_FILE);
+            let mut index = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .map_err(|e| Error::file("Failed to open index", e))?;
+            let size = self.entries_at + self.segments * INDEX_ENTRY_SIZE;
+            if file_len(&index)? != size {  // half a time; nothing to keep
+                index.set_len(size).map_err(|e| Error::file("Failed to truncate index", e))?;
+            }
+            full_write(&mut index, &msg[..TIME_SIZE], "Failed to extend index")?;
+            sync(&index, &path)?;  // before the segment, so it is never unindexed
+            self.segments += 1;
+        }
+
+        let path = segment_path(&self.dir, segment);
+        let mut file = OpenOptions::new()
+            .append(true)  // no reading or writing, only append
+            .create(true)  // create file if it doesn't already exist
+            .open(&path)
+            .map_err(|e| Error::file("Failed to open segment", e))?;
+        let size = (self.len - segment * self.segment_len) * MSG_SIZE_U64;
+        match file_len(&file)? {
+            n if n == size => {},
+            n if n > size => quarantine(&path, &file, size)?,
+            _ => return Err(Error::corruption("Segment shrank")),
+        }
+        full_write(&mut file, msg, "Failed to write to segment")?;
+        sync(&file, &path)?;
+        if size == 0 { sync_dir(&self.dir)? }  // new file
+
+        self.len += 1;
+        Ok(self.len - 1)
+    }
+
+    fn find_time(&self, time: u64) -> Res<u64> {
+        // id of the first message sent at or after time (len if none).
+        // Server times only go up, so this is two binary searches:
+        // over the index for the segment, then over the segment.
+        let mut index = File::open(self.dir.join(INDEX_FILE))
+            .map_err(|e| Error::file("Failed to open index", e))?;
+        let after = partition(self.segments, |i| {
+            Ok(read_time(&mut index, self.entries_at + i * INDEX_ENTRY_SIZE)? < time)
+        })?;
+        let Some(i) = after.checked_sub(1) else { return Ok(self.first_id()) };  // all are later
+
+        let segment = self.first_segment + i;
+        let start = segment * self.segment_len;
+        let len = (self.len - start).min(self.segment_len);
+        let Some(mut file) = open(&segment_path(&self.dir, segment), OpenOptions::new().read(true))? else {
+            return Ok(start.max(self.first_id));  // indexed, but empty
+        };
+        let id = start + partition(len, |pos| Ok(read_time(&mut file, pos * MSG_SIZE_U64)? < time))?;
+        Ok(id.max(self.first_id))  // the segment may be partly forgotten
+    }
+
+    fn trim(&mut self, first_id: u64) -> Res<u64> {
+        // forgets messages before first_id, and deletes the segments that
+        // held only those (never the last one). Returns bytes freed.
+        let first_id = first_id.clamp(self.first_id, self.len);
+        if first_id == self.first_id || self.segments == 0 { return Ok(0) }
+        let last = self.first_segment + self.segments - 1;
+        let first_segment = (first_id / self.segment_len).min(last);
+
+        // new index: same entries, minus the dropped segments'
+        let mut index = File::open(self.dir.join(INDEX_FILE))
+            .map_err(|e| Error::file("Failed to open index", e))?;
+        let skip = self.entries_at + (first_segment - self.first_segment) * INDEX_ENTRY_SIZE;
+        index.seek(SeekFrom::Start(skip)).map_err(|e| Error::file("Failed to seek", e))?;
+        let mut entries = Vec::new();
+        index.read_to_end(&mut entries).map_err(|e| Error::file("Failed to read index", e))?;
+
+        let trimmed = Self {
+            dir: self.dir.clone(),
+            first_segment,
+            segments: last - first_segment + 1,
+            first_id,
+            entries_at: INDEX_HEADER_SIZE,
+            ..*self
+        };
+        let new = self.dir.join(INDEX_FILE).with_extension("new");
+        let mut file = File::create(&new).map_err(|e| Error::file("Failed to create index", e))?;
+        full_write(&mut file, &[trimmed.header().as_slice(), &entries].concat(), "Failed to write index")?;
+        file.sync_all().map_err(|e| Error::file("Failed to sync index", e))?;  // rare; always worth it
+        fs::rename(&new, self.dir.join(INDEX_FILE)).map_err(|e| Error::file("Failed to replace index", e))?;
+        sync_dir(&self.dir)?;
+        *self = trimmed;
+
+        // the index doesn't know them anymore; also catches ones left by a crash
+        let mut freed = 0;
+        let files = fs::read_dir(&self.dir).map_err(|e| Error::file("Failed to list chat", e))?;
+        for file in files {
+            let file = file.map_err(|e| Error::file("Failed to list chat", e))?;
+            let name = file.file_name();
+            let Some(segment) = name.to_str().and_then(|n| u64::from_str_radix(n, 16).ok()) else { continue };
+            if segment >= self.first_segment { continue }
+            freed += file.metadata().map_err(|e| Error::file("Failed to get metadata", e))?.len();
+            fs::remove_file(file.path()).map_err(|e| Error::file("Failed to delete segment", e))?;
+        }
+        Ok(freed)
+    }
+
+    fn read(&self, start: u64, count: u8) -> Res<Vec<u8>> {
+        // messages start..start+count, which the caller keeps in range
+        let mut res = vec![0; count as usize * MSG_SIZE];
+        let mut buf = &mut res[..];
+        let mut id = start;
+        while !buf.is_empty() {  // once per segment touched, usually one
+            let (segment, pos) = (id / self.segment_len, id % self.segment_len);
+            let n = (self.segment_len - pos).min((buf.len() / MSG_SIZE) as u64);
+
+            let mut file = open(&segment_path(&self.dir, segment), OpenOptions::new().read(true))?
+                .ok_or(Error::corruption("Segment missing"))?;
+            file.seek(SeekFrom::Start(pos * MSG_SIZE_U64))
+                .map_err(|e| Error::file("Failed to seek", e))?;
+            let (now, rest) = buf.split_at_mut(n as usize * MSG_SIZE);
+            read_file_exact(&mut file, now, "Failed to read from segment")?;
+            (buf, id) = (rest, id + n);
+        }
+        Ok(res)
+    }
+
+    fn segments(&self) -> Res<Vec<(u64, u64)>> {
+        // server time of the first message and size of each segment, oldest first
+        let mut index = File::open(self.dir.join(INDEX_FILE))
+            .map_err(|e| Error::file("Failed to open index", e))?;
+        (0..self.segments).map(|i| {
+            let time = read_time(&mut index, self.entries_at + i * INDEX_ENTRY_SIZE)?;
+            let file = open(&segment_path(&self.dir, self.first_segment + i), OpenOptions::new().read(true))?;
+            Ok((time, file.map_or(Ok(0), |f| file_len(&f))?))
+        }).collect()
+    }
+}
+
+fn convert(path: &Path) -> Res {
+    // turns a flat chat file into a chat directory.
+    // Built next to it, then swapped in with two renames.
+    let (new, flat) = (path.with_extension("new"), path.with_extension("flat"));
+    match fs::remove_dir_all(&new) {  // left over from an earlier attempt
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(Error::file("Failed to remove old conversion", e))
+        },
+        _ => {},
+    }
+
+    let file = File::open(path).map_err(|e| Error::file("Failed to open flat chat", e))?;
+    let count = file_len(&file)? / MSG_SIZE_U64;
+    if file_len(&file)? != count * MSG_SIZE_U64 {  // crashed mid-write, long ago
+        let writable = OpenOptions::new().write(true).open(path)
+            .map_err(|e| Error::file("Failed to open flat chat", e))?;
+        quarantine(path, &writable, count * MSG_SIZE_U64)?;
+    }
+
+    let mut chat = Chat::create(&new)?;
+    let mut reader = BufReader::new(file);
+    let mut block = [0; MSG_SIZE];
+    for segment in 0..count.div_ceil(SEGMENT_LEN) {
+        // the first message goes through push to index the segment, the rest are copied
+        read_file_exact(&mut reader, &mut block, "Failed to read flat chat")?;
+        chat.push(&block)?;
+
+        let rest = (count - segment * SEGMENT_LEN).min(SEGMENT_LEN) - 1;
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&new, segment))
+            .map_err(|e| Error::file("Failed to open segment", e))?;
+        let copied = io::copy(&mut (&mut reader).take(rest * MSG_SIZE_U64), &mut file)
+            .map_err(|e| Error::file("Failed to copy flat chat", e))?;
+        if copied != rest * MSG_SIZE_U64 { return Err(Error::corruption("Flat chat shrank while converting")) }
+        chat.len += rest;
+    }
+
+    fs::rename(path, &flat).map_err(|e| Error::file("Failed to move flat chat", e))?;
+    fs::rename(&new, path).map_err(|e| Error::file("Failed to move converted chat", e))?;
+    fs::remove_file(&flat).map_err(|e| Error::file("Failed to remove flat chat", e))
+}
+
+// Offline checks, for `publichat-admin fsck`; the server must be stopped.
+#[derive(Default)]
+pub struct Check {  // what fsck found in one chat
+    pub messages: u64,  // kept ones
+    pub bytes: u64,  // on disk
+    pub problems: Vec<String>,  // still there
+    pub repaired: Vec<String>,
+    pub strays: Vec<PathBuf>,  // files the chat doesn't know; the caller moves them
+}
+
+impl Check {
+    fn found(&mut self, problem: String, repaired: bool) {
+        match repaired {
+            true => self.repaired.push(problem),
+            false => self.problems.push(problem),
+        }
+    }
+}
+
+fn torn(path: &Path, what: &str, size: u64, keep: u64, repair: bool, check: &mut Check) -> Res {
+    // anything after keep bytes is half a message; repairing moves it to a .torn file
+    if size <= keep { return Ok(()) }
+    if repair {
+        let file = OpenOptions::new().write(true).open(path)
+            .map_err(|e| Error::file("Failed to open torn file", e))?;
+        quarantine(path, &file, keep)?;
+    }
+    check.found(format!("{} torn bytes at the end of {what}", size - keep), repair);
+    Ok(())
+}
+
+fn times(path: &Path, count: u64, prev: &mut u64) -> Res<(Option<u64>, u64)> {
+    // server time of the first of count messages in path,
+    // and how many of them are older than the one before
+    let file = File::open(path).map_err(|e| Error::file("Failed to open file", e))?;
+    let mut reader = BufReader::new(file);
+    let mut block = [0; MSG_SIZE];
+    let (mut first, mut back) = (None, 0);
+    for _ in 0..count {
+        read_file_exact(&mut reader, &mut block, "Failed to read message")?;
+        let time = time_of(&block);
+        if time < *prev { back += 1 }
+        first.get_or_insert(time);
+        *prev = time;
+    }
+    Ok((first, back))
+}
+
+fn leftovers(path: &Path, repair: bool, check: &mut Check) -> Res {
+    // what a conversion that stopped part way left next to the chat (see convert);
+    // repairing finishes it as far as opening the chat would
+    let (new, flat) = (path.with_extension("new"), path.with_extension("flat"));
+    if flat.is_file() {
+        match (path.exists(), path.is_dir()) {
+            (false, _) => {  // died between the renames
+                if repair { fs::rename(&flat, path).map_err(|e| Error::file("Failed to restore flat chat", e))? }
+                check.found("the chat was left as a .flat file by a conversion".to_string(), repair);
+            },
+            (true, true) => {  // died just before the end; the directory has it all
+                if repair { fs::remove_file(&flat).map_err(|e| Error::file("Failed to remove flat chat", e))? }
+                check.found("a .flat copy was left by a conversion".to_string(), repair);
+            },
+            (true, false) => check.found("a .flat copy next to an unconverted chat".to_string(), false),
+        }
+    }
+    if new.exists() {
+        if !path.exists() && !flat.exists() {  // nothing to tell whether it is whole; the caller moves it
+            check.strays.push(new);
+        } else {
+            if repair {  // convert starts over anyway
+                let removed = if new.is_dir() { fs::remove_dir_all(&new) } else { fs::remove_file(&new) };
+                removed.map_err(|e| Error::file("Failed to remove old conversion", e))?;
+            }
+            check.found("a half-built .new chat was left by a conversion".to_string(), repair);
+        }
+    }
+    Ok(())
+}
+
+pub fn check(path: &Path, repair: bool) -> Res<Check> {
+    // Finds what the server would trip over in a chat; with repair, fixes
+    // what can be fixed in place. Err(Corruption) if it can't be read at all.
+    let mut check = Check::default();
+    leftovers(path, repair, &mut check)?;
+    if !path.exists() { return Ok(check) }
+
+    let mut prev = 0;  // server time of the message before
+    let mut back = 0;  // messages older than the one before them
+    if path.is_file() {  // flat, from before segments
+        let size = file_len(&File::open(path).map_err(|e| Error::file("Failed to open flat chat", e))?)?;
+        let count = size / MSG_SIZE_U64;
+        torn(path, "the chat", size, count * MSG_SIZE_U64, repair, &mut check)?;
+        back += times(path, count, &mut prev)?.1;
+        (check.messages, check.bytes) = (count, size);
+    } else {
+        let half_index = path.join(INDEX_FILE).with_extension("new");
+        if half_index.exists() {  // a crash in create; it starts over
+            if repair { fs::remove_file(&half_index).map_err(|e| Error::file("Failed to remove index", e))? }
+            check.found("a half-made index.new was left by a crash".to_string(), repair);
+        }
+
+        // path exists, so opening won't convert or restore anything
+        let Some(chat) = Chat::open(path)? else { return Ok(check) };  // no index, no messages
+
+        let index_path = path.join(INDEX_FILE);
+        let mut index = File::open(&index_path).map_err(|e| Error::file("Failed to open index", e))?;
+        let (size, entries) = (file_len(&index)?, chat.entries_at + chat.segments * INDEX_ENTRY_SIZE);
+        if size > entries {
+            if repair {  // nothing to keep, as in push
+                OpenOptions::new().write(true).open(&index_path)
+                    .and_then(|index| index.set_len(entries))
+                    .map_err(|e| Error::file("Failed to truncate index", e))?;
+            }
+            check.found("half an entry at the end of the index".to_string(), repair);
+        }
+        check.bytes += size;
+
+        for i in 0..chat.segments {
+            let segment = chat.first_segment + i;
+            let last = i + 1 == chat.segments;
+            let seg_path = segment_path(path, segment);
+            let Some(file) = open(&seg_path, OpenOptions::new().read(true))? else {
+                if last { continue }  // indexed, but the first message never made it
+                return Err(Error::corruption("Segment missing"));
+            };
+            let size = file_len(&file)?;
+            let count = (size / MSG_SIZE_U64).min(chat.segment_len);
+            if !last && count < chat.segment_len { return Err(Error::corruption("Segment cut short")) }
+            let what = format!("segment {segment:016x}");
+            torn(&seg_path, &what, size, count * MSG_SIZE_U64, repair, &mut check)?;
+
+            let (first, n) = times(&seg_path, count, &mut prev)?;
+            back += n;
+            let indexed = read_time(&mut index, chat.entries_at + i * INDEX_ENTRY_SIZE)?;
+            if first.is_some_and(|first| first != indexed) {
+                check.found(format!("index has the wrong time for {what}"), false);
+            }
+            check.bytes += size;
+        }
+        check.messages = chat.len - chat.first_id;
+
+        let segments = chat.first_segment..chat.first_segment + chat.segments;
+        let files = fs::read_dir(path).map_err(|e| Error::file("Failed to list chat", e))?;
+        for file in files {
+            let file = file.map_err(|e| Error::file("Failed to list chat", e))?;
+            let name = file.file_name();
+            let Some(segment) = name.to_str().and_then(|n| u64::from_str_radix(n, 16).ok()) else { continue };
+            if !segments.contains(&segment) { check.strays.push(file.path()) }
+        }
+    }
+    if back > 0 {  // find_time would miss some; nothing to repair without changing ids
+        check.found(format!("{back} messages are older than the one before them"), false);
+    }
+    Ok(check)
+}
+
+impl Files {
+    pub fn new(dir: PathBuf, layout: Layout, limits: &Limits) -> Self {
+        Self {
+            dir,
+            layout,
+            cache_msgs: limits.cache_msgs,
+            cache_chats: limits.cache_chats,
+            chats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn path(&self, chat_id: &HashBuf) -> PathBuf {
+        layout::path(&self.dir, self.layout, chat_id)
+    }
+
+    pub fn chats(&self) -> Res<Vec<HashBuf>> {
+        layout::chats(&self.dir)
+    }
+
+    fn lock(&self, path: &Path) -> Res<ChatLock> {
+        // the chat's lock, made on first use
+        let mut chats = self.chats.lock().map_err(|_| Error::internal("Failed to lock chats"))?;
+        if chats.len() >= self.cache_chats { chats.retain(|_, lock| Arc::strong_count(lock) > 1) }
+        Ok(chats.entry(path.to_path_buf()).or_default().clone())
+    }
+
+    fn locate(&self, chat_id: &HashBuf, path: &Path) -> Res {
+        // brings the chat to its shard, if it's still in the flat spot.
+        // Needs the chat's lock; only done before the cache is filled.
+        if self.layout == Layout::Sharded && !path.exists() && layout::shard(&self.dir, chat_id)? {
+            log!(Debug, "Moved {} to its shard", path.display());
+        }
+        Ok(())
+    }
+
+    fn reading<T>(&self, chat_id: &HashBuf, f: impl FnOnce(&Path, &ChatCache) -> Res<T>) -> Res<T> {
+        // runs f on the chat as it is now; pushes wait for it, other reads don't
+        let path = self.path(chat_id);
+        let lock = self.lock(&path)?;
+        {
+            let cache = lock.read().map_err(|_| Error::internal("Failed to lock chat"))?;
+            if cache.warm { return f(&path, &cache) }
+        }
+        let mut cache = exclusive(&lock)?;  // opening may also move or convert the chat
+        if !cache.warm {
+            self.locate(chat_id, &path)?;
+            warm(&mut cache, &path, self.cache_msgs)?;
+        }
+        f(&path, &cache)
+    }
+
+    fn writing<T>(&self, chat_id: &HashBuf, f: impl FnOnce(&Path, &mut ChatCache) -> Res<T>) -> Res<T> {
+        // runs f with the chat to itself
+        let path = self.path(chat_id);
+        let lock = self.lock(&path)?;
+        let mut cache = exclusive(&lock)?;
+        if !cache.warm { self.locate(chat_id, &path)? }
+        f(&path, &mut cache)
+    }
+
+    pub fn retain(
+        &self,
+        chat_id: &HashBuf,
+        max_messages: u64,  // newest ones kept; 0 for all
+        min_time: u64,  // server time (ms) of the oldest message kept
+    ) -> Res<u64> {
+        // Returns bytes freed
+        self.writing(chat_id, |path, cache| {
+            let Some(mut chat) = Chat::open(path)? else { return Ok(0) };
+            let by_count = match max_messages {
+                0 => 0,
+                n => chat.len.saturating_sub(n),
+            };
+            let by_time = chat.find_time(min_time)?;
+            let freed = chat.trim(by_count.max(by_time));
+            trimmed(cache, &chat, freed.is_ok());
+            freed
+        })
+    }
+
+    pub fn segments(&self, chat_id: &HashBuf) -> Res<Vec<(u64, u64)>> {
+        // Returns server time of the first message and size of each segment, oldest first
+        self.writing(chat_id, |path, _| match Chat::open(path)? {  // only retention asks; may convert
+            Some(chat) => chat.segments(),
+            None => Ok(Vec::new()),
+        })
+    }
+
+    pub fn drop_segments(&self, chat_id: &HashBuf, count: u64) -> Res<u64> {
+        // forgets the oldest count segments (but never the last); returns bytes freed
+        self.writing(chat_id, |path, cache| {
+            let Some(mut chat) = Chat::open(path)? else { return Ok(0) };
+            let freed = chat.trim((chat.first_segment + count) * chat.segment_len);
+            trimmed(cache, &chat, freed.is_ok());
+            freed
+        })
+    }
+}
+
+fn exclusive(lock: &RwLock<ChatCache>) -> Res<RwLockWriteGuard<'_, ChatCache>> {
+    lock.write().map_err(|_| Error::internal("Failed to lock chat"))
+}
+
+impl ChatStore for Files {
+    fn push(&self, chat_id: &HashBuf, msg: &mut MsgBuf) -> Res<u64> {
+        self.writing(chat_id, |path, cache| {
+            msg[..TIME_SIZE].copy_from_slice(&server_time().to_be_bytes());  // under the lock
+            let pushed = match Chat::open(path)? {
+                Some(mut chat) => chat.push(msg),
+                None => Chat::create(path).and_then(|mut chat| chat.push(msg)),
+            };
+            match pushed {
+                Ok(id) if cache.warm && id == cache.len => {
+                    cache.newest.push_back(*msg);
+                    if cache.newest.len() > self.cache_msgs { cache.newest.pop_front(); }
+                    cache.len += 1;
+                },
+                _ => cache.warm = false,  // failed half way, or out of step; read it again
+            }
+            pushed
+        })
+    }
+
+    fn fetch(&self, chat_id: &HashBuf, count: u8) -> Res<Messages> {
+        self.reading(chat_id, |path, chat| {
+            fetch_range(chat.first_id, chat.len, count, |start, count| read(path, chat, start, count))
+        })
+    }
+
+    fn query(&self, chat_id: &HashBuf, id: u64, count: u8, forward: bool) -> Res<Messages> {
+        self.reading(chat_id, |path, chat| {
+            query_range(chat.first_id, chat.len, id, count, forward, |start, count| read(path, chat, start, count))
+        })
+    }
+
+    fn query_time(&self, chat_id: &HashBuf, time: u64, count: u8, forward: bool) -> Res<Messages> {
+        if count == 0 { return Ok((0, 0, Vec::new())) }  // don't bother searching
+        self.reading(chat_id, |path, chat| {
+            let at = find_time(path, chat, time)?;
+            time_range(chat.first_id, chat.len, at, count, forward, |start, count| read(path, chat, start, count))
+        })
+    }
+
+    fn stat(&self, chat_id: &HashBuf) -> Res<ChatStat> {
+        self.reading(chat_id, |path, chat| {
+            let bytes = match Chat::open(path)? {
+                Some(files) => files.segments()?.iter().map(|(_, size)| size).sum(),
+                None => 0,
+            };
+            Ok(ChatStat { first_id: chat.first_id, len: chat.len, bytes })
+        })
+    }
+
+    fn len(&self, chat_id: &HashBuf) -> Res<u64> {
+        self.reading(chat_id, |_, chat| Ok(chat.len))  // stat reads the directory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::HASH_SIZE;
+    use crate::store::Memory;
+    use crate::error::Kind;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TempDir(PathBuf);  // fresh and empty, one per test; gone after
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let dir = std::env::temp_dir().join(format!("publichat-db-{}-{n}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn join(&self, name: &str) -> PathBuf { self.0.join(name) }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+    }
+
+    fn msg(time: u64) -> MsgBuf {
+        let mut msg = [time as u8; MSG_SIZE];
+        msg[..TIME_SIZE].copy_from_slice(&time.to_be_bytes());
+        msg
+    }
+
+    fn small_chat(dir: &Path, segment_len: u64) -> Chat {
+        // as create, with segments small enough to fill
+        let mut chat = Chat::create(dir).unwrap();
+        chat.segment_len = segment_len;
+        fs::write(dir.join(INDEX_FILE), chat.header()).unwrap();
+        chat
+    }
+
+    fn ids(bytes: &[u8]) -> Vec<u64> {
+        bytes.chunks_exact(MSG_SIZE).map(|m| time_of(m.try_into().unwrap()) / 10).collect()
+    }
+
+    fn tags(bytes: &[u8]) -> Vec<u8> {
+        // what store_checks put after the time; stores stamp their own
+        bytes.chunks_exact(MSG_SIZE).map(|m| m[TIME_SIZE]).collect()
+    }
+
+    fn store_checks(store: &dyn ChatStore) {
+        // what any store must do, as the server sees it
+        let (chat, other) = ([1; HASH_SIZE], [2; HASH_SIZE]);
+        assert_eq!(store.fetch(&chat, 10).unwrap(), (0, 0, vec![]));
+        assert_eq!(store.len(&chat).unwrap(), 0);
+
+        let mut times = Vec::new();
+        for tag in 0..6 {
+            let mut msg = [tag; MSG_SIZE];
+            assert_eq!(store.push(&chat, &mut msg).unwrap(), u64::from(tag));
+            times.push(time_of(&msg));
+            thread::sleep(Duration::from_millis(2));  // so no two share a time
+        }
+        assert!(times.windows(2).all(|t| t[0] < t[1]));
+        assert_eq!((store.len(&chat).unwrap(), store.len(&other).unwrap()), (6, 0));
+        let stat = store.stat(&chat).unwrap();
+        assert_eq!((stat.first_id, stat.len), (0, 6));
+        assert!(stat.bytes >= 6 * MSG_SIZE_U64);
+
+        let read = |(count, start, msgs): Messages| {
+            assert_eq!(usize::from(count), msgs.len() / MSG_SIZE);
+            (start, tags(&msgs))
+        };
+        assert_eq!(read(store.fetch(&chat, 4).unwrap()), (2, vec![2, 3, 4, 5]));
+        assert_eq!(read(store.fetch(&chat, 100).unwrap()), (0, vec![0, 1, 2, 3, 4, 5]));
+        assert_eq!(read(store.query(&chat, 1, 2, true).unwrap()), (2, vec![2, 3]));
+        assert_eq!(read(store.query(&chat, 3, 10, false).unwrap()), (0, vec![0, 1, 2]));
+        assert_eq!(read(store.query(&chat, 5, 10, true).unwrap()), (0, vec![]));  // nothing ahead
+        assert_eq!(read(store.query(&chat, 0, 10, false).unwrap()), (0, vec![]));  // nothing behind
+        assert_eq!(read(store.query_time(&chat, times[3], 2, true).unwrap()), (3, vec![3, 4]));
+        assert_eq!(read(store.query_time(&chat, times[3], 2, false).unwrap()), (1, vec![1, 2]));
+        assert_eq!(read(store.query_time(&chat, times[5] + 1, 2, true).unwrap()), (6, vec![]));
+        assert_eq!(read(store.query_time(&chat, 0, 2, false).unwrap()), (0, vec![]));
+    }
+
+    #[test]
+    fn memory_store() {
+        store_checks(&Memory::default());
+    }
+
+    #[test]
+    fn files_store() {
+        // the newest 4 from memory, the rest from the files
+        let tmp = TempDir::new();
+        let limits = Limits { cache_msgs: 4, ..Limits::default() };
+        store_checks(&Files::new(tmp.0.clone(), Layout::Flat, &limits));
+        let tmp = TempDir::new();
+        store_checks(&Files::new(tmp.0.clone(), Layout::Sharded, &limits));
+    }
+
+    #[test]
+    fn push_and_read_across_segments() {
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        let mut chat = small_chat(&dir, 4);
+        for id in 0..10 { assert_eq!(chat.push(&msg(id * 10)).unwrap(), id) }
+
+        let chat = Chat::open(&dir).unwrap().unwrap();
+        assert_eq!((chat.first_id, chat.len, chat.segments), (0, 10, 3));
+        assert_eq!(ids(&chat.read(2, 7).unwrap()), (2..9).collect::<Vec<_>>());
+        assert_eq!(ids(&chat.read(8, 2).unwrap()), [8, 9]);
+        let sizes: Vec<u64> = chat.segments().unwrap().iter().map(|&(_, size)| size).collect();
+        assert_eq!(sizes, [4 * MSG_SIZE_U64, 4 * MSG_SIZE_U64, 2 * MSG_SIZE_U64]);
+    }
+
+    #[test]
+    fn no_index_is_no_chat() {
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        assert!(Chat::open(&dir).unwrap().is_none());
+        fs::create_dir(&dir).unwrap();
+        assert!(Chat::open(&dir).unwrap().is_none());
+    }
+
+    #[test]
+    fn half_made_index_is_no_chat() {
+        // a crash in create, before the header made it
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join(INDEX_FILE), [0; 5]).unwrap();
+        assert!(Chat::open(&dir).unwrap().is_none());
+
+        let mut chat = Chat::create(&dir).unwrap();
+        assert_eq!(chat.push(&msg(10)).unwrap(), 0);
+        assert!(!dir.join(INDEX_FILE).with_extension("new").exists());
+        assert_eq!(Chat::open(&dir).unwrap().unwrap().len, 1);
+
+        // with messages, a short index is damage, not a fresh chat
+        fs::write(dir.join(INDEX_FILE), [0; 5]).unwrap();
+        assert!(Chat::open(&dir).is_err());
+    }
+
+    #[test]
+    fn find_time_in_segments() {
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        let mut chat = small_chat(&dir, 4);
+        for id in 0..10 { chat.push(&msg(id * 10 + 10)).unwrap(); }  // 10, 20, ..., 100
+
+        assert_eq!(chat.find_time(10).unwrap(), 0);
+        assert_eq!(chat.find_time(40).unwrap(), 3);  // end of a segment
+        assert_eq!(chat.find_time(41).unwrap(), 4);  // start of the next
+        assert_eq!(chat.find_time(55).unwrap(), 5);
+        assert_eq!(chat.find_time(0).unwrap(), 0);  // before the first
+        assert_eq!(chat.find_time(100).unwrap(), 9);  // the last
+        assert_eq!(chat.find_time(101).unwrap(), 10);  // after the last: none
+    }
+
+    #[test]
+    fn query_time_at_the_edges() {
+        // from memory for the newest 4, from the files for the rest
+        let tmp = TempDir::new();
+        let chat_id = [3; HASH_SIZE];
+        let files = Files::new(tmp.0.clone(), Layout::Flat, &Limits { cache_msgs: 4, ..Limits::default() });
+        let mut chat = small_chat(&files.path(&chat_id), 4);
+        for id in 0..10 { chat.push(&msg(id * 10 + 5)).unwrap(); }  // 5, 15, ..., 95
+
+        let query = |time, forward| {
+            let (count, start, msgs) = files.query_time(&chat_id, time, 3, forward).unwrap();
+            assert_eq!(usize::from(count), ids(&msgs).len());
+            (start, ids(&msgs))
+        };
+        assert_eq!(query(0, true), (0, vec![0, 1, 2]));  // before the first
+        assert_eq!(query(0, false), (0, vec![]));
+        assert_eq!(query(5, true), (0, vec![0, 1, 2]));  // the first
+        assert_eq!(query(5, false), (0, vec![]));
+        assert_eq!(query(50, true), (5, vec![5, 6, 7]));  // between two
+        assert_eq!(query(50, false), (2, vec![2, 3, 4]));
+        assert_eq!(query(75, true), (7, vec![7, 8, 9]));  // in memory
+        assert_eq!(query(95, true), (9, vec![9]));  // the last
+        assert_eq!(query(95, false), (6, vec![6, 7, 8]));
+        assert_eq!(query(96, true), (10, vec![]));  // after the last
+        assert_eq!(query(96, false), (7, vec![7, 8, 9]));
+    }
+
+    #[test]
+    fn trim_drops_whole_segments() {
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        let mut chat = small_chat(&dir, 4);
+        for id in 0..10 { chat.push(&msg(id * 10)).unwrap(); }
+
+        assert_eq!(chat.trim(6).unwrap(), 4 * MSG_SIZE_U64);  // segment 0 only
+        assert!(!segment_path(&dir, 0).exists());
+        assert!(segment_path(&dir, 1).exists());  // still holds 6 and 7
+
+        let mut chat = Chat::open(&dir).unwrap().unwrap();
+        assert_eq!((chat.first_id, chat.len, chat.first_segment), (6, 10, 1));
+        assert_eq!(ids(&chat.read(6, 4).unwrap()), [6, 7, 8, 9]);
+        assert_eq!(chat.find_time(0).unwrap(), 6);  // forgotten ones aren't found
+
+        assert_eq!(chat.trim(100).unwrap(), 4 * MSG_SIZE_U64);  // never the last segment
+        assert_eq!((chat.first_id, chat.len, chat.first_segment), (10, 10, 2));
+        assert_eq!(chat.push(&msg(100)).unwrap(), 10);  // ids go on
+    }
+
+    #[test]
+    fn push_quarantines_torn_message() {
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        let mut chat = small_chat(&dir, 4);
+        for id in 0..2 { chat.push(&msg(id * 10)).unwrap(); }
+        let mut segment = OpenOptions::new().append(true).open(segment_path(&dir, 0)).unwrap();
+        full_write(&mut segment, &[7; 100], "test").unwrap();  // a crash mid-push
+
+        let mut chat = Chat::open(&dir).unwrap().unwrap();
+        assert_eq!(chat.len, 2);  // half a message isn't one
+        assert_eq!(chat.push(&msg(20)).unwrap(), 2);
+        assert_eq!(fs::read(segment_path(&dir, 0).with_extension("torn")).unwrap(), [7; 100]);
+        assert_eq!(ids(&chat.read(0, 3).unwrap()), [0, 1, 2]);
+    }
+
+    #[test]
+    fn check_repairs_torn_ends() {
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        let mut chat = small_chat(&dir, 2);
+        for id in 0..3 { chat.push(&msg(id * 10)).unwrap(); }
+        let append = |path: &Path, len| {
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            full_write(&mut file, &vec![7; len], "test").unwrap();
+        };
+        append(&segment_path(&dir, 1), 100);  // a crash mid-push
+        append(&dir.join(INDEX_FILE), 5);  // and mid-entry
+        let index_len = fs::metadata(dir.join(INDEX_FILE)).unwrap().len();
+
+        let found = check(&dir, false).unwrap();
+        assert_eq!((found.messages, found.problems.len(), found.repaired.len()), (3, 2, 0));
+        assert_eq!(fs::metadata(dir.join(INDEX_FILE)).unwrap().len(), index_len);  // looked, didn't touch
+
+        let found = check(&dir, true).unwrap();
+        assert_eq!((found.messages, found.problems.len(), found.repaired.len()), (3, 0, 2));
+        assert_eq!(fs::metadata(dir.join(INDEX_FILE)).unwrap().len(), index_len - 5);
+        assert_eq!(fs::read(segment_path(&dir, 1).with_extension("torn")).unwrap(), [7; 100]);
+        let found = check(&dir, false).unwrap();
+        assert!(found.problems.is_empty() && found.repaired.is_empty());
+        assert_eq!(ids(&Chat::open(&dir).unwrap().unwrap().read(0, 3).unwrap()), [0, 1, 2]);
+    }
+
+    #[test]
+    fn check_repairs_flat_chats() {
+        let tmp = TempDir::new();
+        let path = tmp.join("chat");
+        let mut flat: Vec<u8> = [20, 10].iter().flat_map(|&time| msg(time)).collect();
+        flat.extend_from_slice(&[7; 100]);
+        fs::write(&path, &flat).unwrap();
+
+        let found = check(&path, true).unwrap();
+        assert_eq!(found.repaired.len(), 1);
+        assert_eq!(found.problems.len(), 1);  // out of order; ids would change
+        assert!(path.is_file());  // left for the server to convert
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * MSG_SIZE_U64);
+        assert_eq!(fs::read(path.with_extension("torn")).unwrap(), [7; 100]);
+    }
+
+    #[test]
+    fn check_finds_strays_and_broken_chats() {
+        let tmp = TempDir::new();
+        let dir = tmp.join("chat");
+        let mut chat = small_chat(&dir, 2);
+        for id in 0..5 { chat.push(&msg(id * 10)).unwrap(); }
+        fs::write(segment_path(&dir, 7), msg(70)).unwrap();
+        assert_eq!(check(&dir, true).unwrap().strays, [segment_path(&dir, 7)]);
+        assert!(segment_path(&dir, 7).exists());  // moving it is up to the caller
+
+        fs::remove_file(segment_path(&dir, 1)).unwrap();
+        assert_eq!(check(&dir, true).err().unwrap().kind(), Kind::Corruption);
+    }
+
+    #[test]
+    fn check_finishes_conversions() {
+        let tmp = TempDir::new();
+        let path = tmp.join("chat");
+        fs::write(path.with_extension("flat"), msg(10)).unwrap();  // died between the renames
+        Chat::create(&path.with_extension("new")).unwrap();
+        let found = check(&path, false).unwrap();
+        assert_eq!((found.problems.len(), found.messages), (2, 0));
+        assert!(!path.exists());  // looked, didn't touch
+
+        let found = check(&path, true).unwrap();
+        assert_eq!((found.problems.len(), found.repaired.len(), found.messages), (0, 2, 1));
+        assert!(path.is_file() && !path.with_extension("new").exists());
+
+        // died before the .flat file was removed, or while making an index
+        Chat::open(&path).unwrap().unwrap();
+        fs::write(path.with_extension("flat"), msg(10)).unwrap();
+        fs::write(path.join(INDEX_FILE).with_extension("new"), [0; 5]).unwrap();
+        let found = check(&path, true).unwrap();
+        assert_eq!((found.problems.len(), found.repaired.len(), found.messages), (0, 2, 1));
+        assert!(!path.with_extension("flat").exists());
+        assert!(!path.join(INDEX_FILE).with_extension("new").exists());
+
+        // without the chat, a .new one may be all there is; it isn't thrown away
+        let path = tmp.join("other");
+        Chat::create(&path.with_extension("new")).unwrap();
+        assert_eq!(check(&path, true).unwrap().strays, [path.with_extension("new")]);
+        assert!(path.with_extension("new").exists());
+    }
+
+    #[test]
+    fn shard_moves_a_chat_with_its_files() {
+        let tmp = TempDir::new();
+        let (chat, other) = ([0xab; HASH_SIZE], [0xcd; HASH_SIZE]);
+        let flat = layout::path(&tmp.0, Layout::Flat, &chat);
+        let sharded = layout::path(&tmp.0, Layout::Sharded, &chat);
+        assert!(sharded.starts_with(tmp.join("ab").join("ab")));
+        Chat::create(&flat).unwrap().push(&msg(10)).unwrap();
+        fs::write(flat.with_extension("torn"), [7; 100]).unwrap();
+
+        assert!(layout::shard(&tmp.0, &chat).unwrap());
+        assert!(!flat.exists() && !flat.with_extension("torn").exists());
+        assert_eq!(fs::read(sharded.with_extension("torn")).unwrap(), [7; 100]);
+        assert_eq!(Chat::open(&sharded).unwrap().unwrap().len, 1);
+        assert!(!layout::shard(&tmp.0, &chat).unwrap());  // already done
+        assert!(!layout::shard(&tmp.0, &other).unwrap());  // not there at all
+        assert!(layout::left_behind(&tmp.0, &chat).is_empty());
+
+        // a migration that stopped after the chat itself
+        fs::rename(sharded.with_extension("torn"), flat.with_extension("torn")).unwrap();
+        assert_eq!(layout::chats(&tmp.0).unwrap(), [chat]);
+        assert_eq!(layout::left_behind(&tmp.0, &chat), [flat.with_extension("torn")]);
+        assert!(layout::shard(&tmp.0, &chat).unwrap());
+        assert!(layout::left_behind(&tmp.0, &chat).is_empty());
+    }
+
+    #[test]
+    fn sharded_store_finds_flat_chats() {
+        let tmp = TempDir::new();
+        let chat = [1; HASH_SIZE];
+        Files::new(tmp.0.clone(), Layout::Flat, &Limits::default()).push(&chat, &mut msg(10)).unwrap();
+        let files = Files::new(tmp.0.clone(), Layout::Sharded, &Limits::default());
+        assert_eq!(files.len(&chat).unwrap(), 1);
+        assert!(files.path(&chat).is_dir());
+        assert!(!layout::path(&tmp.0, Layout::Flat, &chat).exists());
+    }
+
+    #[test]
+    fn chat_in_both_layouts() {
+        let tmp = TempDir::new();
+        let (both, flat, sharded) = ([1; HASH_SIZE], [2; HASH_SIZE], [3; HASH_SIZE]);
+        for chat in [both, flat] { Chat::create(&layout::path(&tmp.0, Layout::Flat, &chat)).unwrap(); }
+        for chat in [both, sharded] { Chat::create(&layout::path(&tmp.0, Layout::Sharded, &chat)).unwrap(); }
+        fs::create_dir(tmp.join("quarantine")).unwrap();  // neither a chat nor a shard
+        fs::write(tmp.join("01").join("01").join("notes"), "").unwrap();
+
+        assert_eq!(layout::chats(&tmp.0).unwrap(), [both, flat, sharded]);  // once each
+        let converting = [4; HASH_SIZE];  // only what a conversion left
+        fs::write(layout::path(&tmp.0, Layout::Flat, &converting).with_extension("flat"), msg(10)).unwrap();
+        fs::write(tmp.join("notes.txt"), "").unwrap();
+        assert_eq!(layout::chats(&tmp.0).unwrap(), [both, flat, sharded, converting]);
+        let e = layout::shard(&tmp.0, &both).err().unwrap();
+        assert_eq!(e.kind(), Kind::Corruption);
+        assert!(layout::path(&tmp.0, Layout::Flat, &both).exists());  // left for fsck
+    }
+
+    #[test]
+    fn flat_chat_is_converted() {
+        let tmp = TempDir::new();
+        let path = tmp.join("chat");
+        let mut flat: Vec<u8> = (0..5).flat_map(|id| msg(id * 10)).collect();
+        flat.extend_from_slice(&[7; 100]);  // torn, long ago
+        fs::write(&path, &flat).unwrap();
+
+        let chat = Chat::open(&path).unwrap().unwrap();
+        assert!(path.is_dir());
+        assert_eq!((chat.first_id, chat.len, chat.segments), (0, 5, 1));
+        assert_eq!(ids(&chat.read(0, 5).unwrap()), [0, 1, 2, 3, 4]);
+        assert_eq!(fs::read(path.with_extension("torn")).unwrap(), [7; 100]);
+        assert!(!path.with_extension("flat").exists());
+        assert!(!path.with_extension("new").exists());
+    }
+
+    #[test]
+    fn interrupted_conversion_is_restored() {
+        // died between the two renames: only the .flat file is left
+        let tmp = TempDir::new();
+        let path = tmp.join("chat");
+        fs::write(path.with_extension("flat"), msg(10)).unwrap();
+        let chat = Chat::open(&path).unwrap().unwrap();
+        assert_eq!(chat.len, 1);
+        assert_eq!(chat.find_time(10).unwrap(), 0);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{fmt, io};
+
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Io,  // file or socket operation failed
+    Disconnected,  // other side went away (EOF, reset, timeout...)
+    Protocol,  // other side sent something we don't understand
+    Unsupported,  // other side is too old (or new) for us
+    Corruption,  // stored data doesn't look like it should
+    Limit,  // something is too big or too many
+    Crypto,  // en/decryption, keys or signatures
+    Internal,  // poisoned locks, hung up channels; our own fault
+}
+
+#[derive(Debug)]
+pub struct Error {
+    kind: Kind,
+    context: &'static str,  // what we were trying to do
+    source: Option<Source>,
+}
+
+impl Error {
+    pub fn new(kind: Kind, context: &'static str) -> Self {
+        Self { kind, context, source: None }
+    }
+
+    pub fn with_source(kind: Kind, context: &'static str, source: impl Into<Source>) -> Self {
+        Self { kind, context, source: Some(source.into()) }
+    }
+
+    pub fn io(context: &'static str, source: io::Error) -> Self {
+        // sort out the io errors that just mean "they're gone"
+        use io::ErrorKind::*;
+        let kind = match source.kind() {
+            UnexpectedEof
+            | ConnectionReset
+            | ConnectionAborted
+            | BrokenPipe
+            | NotConnected
+            | TimedOut
+            | WouldBlock => Kind::Disconnected,
+            InvalidData => Kind::Protocol,  // eg. bad websocket frames
+            _ => Kind::Io,
+        };
+        Self::with_source(kind, context, source)
+    }
+
+    pub fn file(context: &'static str, source: io::Error) -> Self {
+        // for our own files; one that ends early was cut short
+        let kind = match source.kind() {
+            io::ErrorKind::UnexpectedEof => Kind::Corruption,
+            _ => Kind::Io,
+        };
+        Self::with_source(kind, context, source)
+    }
+
+    pub fn protocol(context: &'static str) -> Self { Self::new(Kind::Protocol, context) }
+    pub fn unsupported(context: &'static str) -> Self { Self::new(Kind::Unsupported, context) }
+    pub fn corruption(context: &'static str) -> Self { Self::new(Kind::Corruption, context) }
+    pub fn limit(context: &'static str) -> Self { Self::new(Kind::Limit, context) }
+    pub fn crypto(context: &'static str) -> Self { Self::new(Kind::Crypto, context) }
+    pub fn internal(context: &'static str) -> Self { Self::new(Kind::Internal, context) }
+
+    pub fn kind(&self) -> Kind { self.kind }
+    pub fn context(&self) -> &'static str { self.context }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Io => "I/O error",
+            Kind::Disconnected => "disconnected",
+            Kind::Protocol => "protocol violation",
+            Kind::Unsupported => "unsupported",
+            Kind::Corruption => "corruption",
+            Kind::Limit => "limit exceeded",
+            Kind::Crypto => "crypto failure",
+            Kind::Internal => "internal error",
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.context, self.kind)?;
+        if let Some(source) = &self.source {
+            write!(f, ": {source}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_deref().map(|e| e as _)
+    }
+}
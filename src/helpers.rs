@@ -1,27 +1,126 @@
 use std::io::{Write, Read};
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub type Res = Result<(), &'static str>;
+use crate::buffers::{hash::Buf as HashBuf, pad::Buf as PadBuf};
+use crate::error::Error;
+use crate::store::ChatStore;
 
-pub fn full_write(stream: &mut impl Write, buf: &[u8], err: &'static str) -> Res {
+pub type Res<T = ()> = Result<T, Error>;
+
+pub fn full_write(stream: &mut (impl Write + ?Sized), buf: &[u8], err: &'static str) -> Res {
     // writes buffer to stream and flushes it
-    match stream.write(buf).and(stream.flush()) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(err),
-    }
+    stream.write_all(buf)
+        .and_then(|_| stream.flush())
+        .map_err(|e| Error::io(err, e))
 }
 
 pub fn read_exact(stream: &mut impl Read, buf: &mut [u8], err: &'static str) -> Res {
-    stream.read_exact(buf).map_err(|_| err)
+    stream.read_exact(buf).map_err(|e| Error::io(err, e))
+}
+
+pub fn read_file_exact(file: &mut impl Read, buf: &mut [u8], err: &'static str) -> Res {
+    // as read_exact, but running out is corruption rather than a hang up
+    file.read_exact(buf).map_err(|e| Error::file(err, e))
+}
+
+pub fn server_time() -> u64 {
+    // ms since the epoch, as stored with messages
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH).unwrap()
+        .as_millis().try_into().expect("go play with your hoverboard")
+}
+
+// write half of a connection, shared between its own thread and pushers
+pub type SharedWriter = Arc<Mutex<dyn Write + Send>>;
+
+#[derive(Clone, Copy)]
+pub struct Rate {  // token bucket: `count` at once, refilled over `per`
+    pub count: u32,  // 0: unlimited
+    pub per: Duration,
+}
+
+impl Rate {
+    pub const OFF: Self = Self::new(0, 0);
+    pub const fn new(count: u32, secs: u64) -> Self {
+        Self { count, per: Duration::from_secs(secs) }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+pub enum BucketKey {  // packet pad, and who it is counted against
+    Peer(PadBuf, IpAddr),
+    Chat(PadBuf, HashBuf),
+}
+
+// message hash, id, server time
+pub type RecentSend = (HashBuf, u64, u64);
+pub type RecentSends = Arc<Mutex<VecDeque<RecentSend>>>;  // held over check and push
+
+#[derive(Default)]
+pub struct Recent {
+    pub chats: HashMap<HashBuf, (RecentSends, u64)>,  // chat id -> sends, time of the last
+    pub pushes: VecDeque<(u64, HashBuf)>,  // time and chat of each push, oldest first
+}
+
+pub struct Limits {
+    pub workers:        usize,  // threads handling packets; idle sockets don't need one
+    pub connections:    usize,  // max open connections
+    pub per_ip:         usize,  // max connections from a single (non-local) address
+    pub fetch_max:      u8,  // most messages sent for one query (at most 127)
+    pub fetch_default:  u8,  // messages sent for a fetch
+    pub cache_msgs:     usize,  // newest messages kept in memory per chat (file store)
+    pub cache_chats:    usize,  // chats kept in memory before idle ones are dropped
+    pub read_timeout:   Duration,  // for the rest of a request/packet once it started
+    pub idle_timeout:   Duration,  // SMRT sessions that don't send anything
+    pub write_timeout:  Duration,  // readers that take nothing for this long are dropped
+    pub pow_bits:       u8,  // leading zero bits a snd stamp must give; 0 turns it off
+    pub send_ip:        Rate,  // snd packets per (non-local) address
+    pub send_chat:      Rate,  // snd packets per chat
+    pub fetch_ip:       Rate,  // fch
+    pub fetch_chat:     Rate,
+    pub query_ip:       Rate,  // qry and tim
+    pub query_chat:     Rate,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            workers:        16,
+            connections:    16 * 1024,
+            per_ip:         8,
+            fetch_max:      50,
+            fetch_default:  25,
+            cache_msgs:     128,  // 64 KiB per chat
+            cache_chats:    1024,
+            read_timeout:   Duration::from_secs(10),
+            idle_timeout:   Duration::from_secs(120),  // clients keepalive faster
+            write_timeout:  Duration::from_secs(10),
+            pow_bits:       0,
+            send_ip:        Rate::new(30, 60),
+            send_chat:      Rate::new(120, 60),
+            fetch_ip:       Rate::new(60, 60),
+            fetch_chat:     Rate::OFF,  // busy chats are fetched a lot
+            query_ip:       Rate::new(300, 60),  // scrolling back queries often
+            query_chat:     Rate::OFF,
+        }
+    }
 }
 
 pub struct Globals {  // owns all its data!
-    pub data_dir:    PathBuf,
+    pub store:       Arc<dyn ChatStore>,
     pub git_hash:    [u8; 40],
+    pub limits:      Limits,
+    pub subs:        Mutex<HashMap<HashBuf, Vec<(SharedWriter, bool)>>>,  // chat id -> listeners, if they take wide ids
+    pub conns:       Mutex<HashMap<IpAddr, usize>>,  // open connections per address
+    pub recent:      Mutex<Recent>,  // sends per chat, to spot replays
+    pub buckets:     Mutex<HashMap<BucketKey, Instant>>,  // rate limits; when each is full again
 }
 
 pub const FILE_FAVICON_ICO: &[u8] = include_bytes!("../page/favicon.ico");
-pub const FILE_TOOLS_HTML: &[u8] = include_bytes!("../page/tools.html");  // TODO: minify?
+pub const FILE_TOOLS_HTML: &[u8] = include_bytes!("../target/tools.html");  // TODO: minify?
 
 // I believe there is no nice way of doing this
 // no minify, no tls
@@ -0,0 +1,115 @@
+// Where chats are in the data directory. Each is named by its id in base64.
+//     flat     data/q80nd...            all chats right in the data directory
+//     sharded  data/ab/cd/q80nd...      under the first two bytes of the id, in hex
+// A sharded server still finds chats in the flat spot, and moves them to
+// their shard when it first opens them; `publichat-admin migrate` moves
+// the rest in one go.
+
+use std::{fs, io::ErrorKind, path::{Path, PathBuf}};
+
+use crate::helpers::Res;
+use crate::error::Error;
+use crate::buffers::hash::Buf as HashBuf;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Flat,
+    Sharded,  // 65536 directories, each with a few chats
+}
+
+// the chat itself, and what a crash or conversion can leave next to it
+const EXTENSIONS: [&str; 4] = ["", "flat", "new", "torn"];
+
+pub fn name(chat_id: &HashBuf) -> String {
+    use base64::{Config, CharacterSet::UrlSafe};
+    base64::encode_config(chat_id, Config::new(UrlSafe, false))
+}
+
+pub fn chat_id(name: &str) -> Option<HashBuf> {
+    // None if name isn't a chat
+    use base64::{Config, CharacterSet::UrlSafe};
+    base64::decode_config(name, Config::new(UrlSafe, false)).ok()?.try_into().ok()
+}
+
+fn flat(dir: &Path, chat_id: &HashBuf) -> PathBuf {
+    dir.join(name(chat_id))
+}
+
+fn sharded(dir: &Path, chat_id: &HashBuf) -> PathBuf {
+    dir.join(format!("{:02x}", chat_id[0])).join(format!("{:02x}", chat_id[1])).join(name(chat_id))
+}
+
+pub fn path(dir: &Path, layout: Layout, chat_id: &HashBuf) -> PathBuf {
+    match layout {
+        Layout::Flat => flat(dir, chat_id),
+        Layout::Sharded => sharded(dir, chat_id),
+    }
+}
+
+pub fn shard(dir: &Path, chat_id: &HashBuf) -> Res<bool> {
+    // moves a chat from the flat spot to its shard; false if there was nothing to move.
+    // Each file is one rename, so stopping half way is fine: just run it again.
+    let (from, to) = (flat(dir, chat_id), sharded(dir, chat_id));
+    let mut moved = false;
+    for ext in EXTENSIONS {
+        let (from, to) = (from.with_extension(ext), to.with_extension(ext));
+        if !from.exists() { continue }
+        if to.exists() { return Err(Error::corruption("Chat is in both layouts")) }
+
+        let parent = to.parent().unwrap();  // made by sharded()
+        fs::create_dir_all(parent).map_err(|e| Error::file("Failed to create shard", e))?;
+        match fs::rename(&from, &to) {
+            Ok(()) => moved = true,
+            Err(e) if e.kind() == ErrorKind::NotFound => {},  // someone else moved it
+            Err(e) => return Err(Error::file("Failed to move chat", e)),
+        }
+    }
+    Ok(moved)
+}
+
+pub fn left_behind(dir: &Path, chat_id: &HashBuf) -> Vec<PathBuf> {
+    // files of a sharded chat still in the flat spot, from a migration that stopped part way.
+    // shard() moves them over.
+    let (from, to) = (flat(dir, chat_id), sharded(dir, chat_id));
+    if from.exists() || !to.exists() { return Vec::new() }
+    EXTENSIONS.iter().map(|ext| from.with_extension(ext)).filter(|path| path.exists()).collect()
+}
+
+fn list(dir: &Path) -> Res<Vec<(String, PathBuf)>> {
+    let entries = fs::read_dir(dir).map_err(|e| Error::file("Failed to list data dir", e))?;
+    entries.map(|entry| {
+        let entry = entry.map_err(|e| Error::file("Failed to list data dir", e))?;
+        Ok((entry.file_name().to_string_lossy().into_owned(), entry.path()))
+    }).collect()
+}
+
+fn chat_of(name: &str) -> Option<HashBuf> {
+    // the chat a file belongs to, also for what a crash or conversion left
+    match name.split_once('.') {
+        Some((stem, ext)) if EXTENSIONS.contains(&ext) => chat_id(stem),
+        Some(_) => None,
+        None => chat_id(name),
+    }
+}
+
+fn is_shard(name: &str, path: &Path) -> bool {
+    name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit()) && path.is_dir()
+}
+
+pub fn chats(dir: &Path) -> Res<Vec<HashBuf>> {
+    // every chat, in either layout; also ones only half-converted or half-moved
+    let mut chats = Vec::new();
+    for (name, path) in list(dir)? {
+        if !is_shard(&name, &path) {
+            chats.extend(chat_of(&name));
+            continue;
+        }
+        for (name, path) in list(&path)? {
+            if !is_shard(&name, &path) { continue }
+            chats.extend(list(&path)?.iter().filter_map(|(name, _)| chat_of(name)));
+        }
+    }
+    chats.sort_unstable();
+    chats.dedup();  // caught while being moved, or with its .torn file
+    Ok(chats)
+}
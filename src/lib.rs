@@ -1,3 +1,9 @@
 pub mod constants;
 pub mod helpers;
 pub mod buffers;
+pub mod error;
+pub mod stream;
+pub mod store;
+pub mod log;
+pub mod layout;
+pub mod db;
@@ -0,0 +1,46 @@
+use std::{
+    fmt,
+    fs::File,
+    io::Write,
+    sync::{Mutex, OnceLock, atomic::{AtomicU8, Ordering}},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,  // the server itself is in trouble
+    Info,  // startup, rejections, connections that ended badly
+    Debug,  // every connection
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FILE: OnceLock<Mutex<File>> = OnceLock::new();  // stdout if not set
+
+pub fn init(level: Level, file: Option<File>) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    if let Some(file) = file {
+        let _ = FILE.set(Mutex::new(file));
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn write(args: fmt::Arguments) {
+    match FILE.get() {
+        Some(file) => if let Ok(mut file) = file.lock() {
+            let _ = writeln!(file, "{args}");
+        },
+        None => println!("{args}"),
+    }
+}
+
+// log!(Info, "Running on {addr}")
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::$level) {
+            $crate::log::write(format_args!($($arg)*))
+        }
+    };
+}
@@ -0,0 +1,164 @@
+// Where chats are kept. The server only talks to a ChatStore: the files
+// in its data directory (see the server's db module), or Memory, which
+// forgets everything when it's dropped (for tests and throwaway servers).
+//
+// A chat is a list of stored messages (msg_out_s buffers), with ids counting
+// from 0 in the order they were pushed. A store may forget messages from the
+// front; ids don't change when it does.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::buffers::{hash::Buf as HashBuf, msg_out_s::Buf as MsgBuf};
+use crate::constants::TIME_SIZE;
+use crate::error::Error;
+use crate::helpers::{Res, server_time};
+
+// number of messages, id of the first one and the message bytes
+pub type Messages = (u8, u64, Vec<u8>);
+
+const EMPTY: Messages = (0, 0, Vec::new());  // doesn't allocate
+
+pub struct ChatStat {
+    pub first_id: u64,  // oldest message kept
+    pub len: u64,  // id of the next message
+    pub bytes: u64,  // space taken
+}
+
+pub trait ChatStore: Send + Sync {
+    // Stamps msg with the server time and stores it as the chat's next
+    // message. Returns its id; times go up with ids.
+    fn push(&self, chat: &HashBuf, msg: &mut MsgBuf) -> Res<u64>;
+
+    // the newest count messages
+    fn fetch(&self, chat: &HashBuf, count: u8) -> Res<Messages>;
+
+    // up to count messages after id, or before it
+    fn query(&self, chat: &HashBuf, id: u64, count: u8, forward: bool) -> Res<Messages>;
+
+    // up to count messages sent at or after time (server time, ms), or before it.
+    // Either way the id returned is where the time falls, even with no messages.
+    fn query_time(&self, chat: &HashBuf, time: u64, count: u8, forward: bool) -> Res<Messages>;
+
+    fn stat(&self, chat: &HashBuf) -> Res<ChatStat>;
+
+    fn len(&self, chat: &HashBuf) -> Res<u64> {  // id of the next message
+        Ok(self.stat(chat)?.len)
+    }
+}
+
+// What each request returns, given where the chat starts and ends.
+// Shared by the stores; read(start, count) gets the messages themselves.
+
+pub fn fetch_range(
+    first_id: u64,
+    len: u64,
+    count: u8,
+    read: impl FnOnce(u64, u8) -> Res<Vec<u8>>,
+) -> Res<Messages> {
+    let count = (len - first_id).min(count.into()) as u8;  // fits, it's a min
+    let start = len - u64::from(count);
+    Ok((count, start, read(start, count)?))
+}
+
+pub fn query_range(
+    first_id: u64,
+    len: u64,
+    id: u64,  // from which message
+    count: u8,  // how many messages; caller caps it
+    forward: bool,  // search forward or backward in time
+    read: impl FnOnce(u64, u8) -> Res<Vec<u8>>,
+) -> Res<Messages> {
+    if count == 0 {return Ok(EMPTY)}  // nothing to return
+    if id > len {return Ok(EMPTY)} // outside of range, return nothing
+    if forward && id + 1 >= len {return Ok(EMPTY)}  // nothing ahead
+    if !forward && id <= first_id {return Ok(EMPTY)}  // nothing behind
+
+    // both `as u8` in the following cannot fail.
+    let (start, count) = match forward {
+        true => {
+            let start = (id + 1).max(first_id);  // forgotten ones are skipped
+            (start, (len - start).min(count.into()) as u8)  // don't overshoot
+        },
+        false => {
+            let start = id.saturating_sub(count.into()).max(first_id);  // not too far left
+            (start, (id - start) as u8)
+        },
+    };
+    Ok((count, start, read(start, count)?))
+}
+
+pub fn time_range(
+    first_id: u64,
+    len: u64,
+    at: u64,  // id of the first message sent at or after the time (len if none)
+    count: u8,  // how many messages; caller caps it
+    forward: bool,  // at and after, or before
+    read: impl FnOnce(u64, u8) -> Res<Vec<u8>>,
+) -> Res<Messages> {
+    let (start, count) = match forward {
+        true => (at, (len - at).min(count.into()) as u8),  // fits, it's a min
+        false => {
+            let start = at.saturating_sub(count.into()).max(first_id);
+            (start, (at - start) as u8)
+        },
+    };
+    if count == 0 {return Ok((0, start, Vec::new()))}  // still says where the time falls
+    Ok((count, start, read(start, count)?))
+}
+
+pub fn time_of(msg: &MsgBuf) -> u64 {
+    u64::from_be_bytes(msg[..TIME_SIZE].try_into().unwrap())
+}
+
+#[derive(Default)]
+pub struct Memory {
+    chats: RwLock<HashMap<HashBuf, Vec<MsgBuf>>>,  // never forgets a message
+}
+
+impl Memory {
+    fn reading<T>(&self, chat: &HashBuf, f: impl FnOnce(&[MsgBuf]) -> Res<T>) -> Res<T> {
+        let chats = self.chats.read().map_err(|_| Error::internal("Failed to lock chats"))?;
+        f(chats.get(chat).map_or(&[], Vec::as_slice))
+    }
+}
+
+fn read(msgs: &[MsgBuf], start: u64, count: u8) -> Res<Vec<u8>> {
+    Ok(msgs[start as usize..][..count.into()].concat())  // ranges come from the above
+}
+
+impl ChatStore for Memory {
+    fn push(&self, chat: &HashBuf, msg: &mut MsgBuf) -> Res<u64> {
+        let mut chats = self.chats.write().map_err(|_| Error::internal("Failed to lock chats"))?;
+        let msgs = chats.entry(*chat).or_default();
+        msg[..TIME_SIZE].copy_from_slice(&server_time().to_be_bytes());
+        msgs.push(*msg);
+        Ok(msgs.len() as u64 - 1)
+    }
+
+    fn fetch(&self, chat: &HashBuf, count: u8) -> Res<Messages> {
+        self.reading(chat, |msgs| {
+            fetch_range(0, msgs.len() as u64, count, |start, count| read(msgs, start, count))
+        })
+    }
+
+    fn query(&self, chat: &HashBuf, id: u64, count: u8, forward: bool) -> Res<Messages> {
+        self.reading(chat, |msgs| {
+            query_range(0, msgs.len() as u64, id, count, forward, |start, count| read(msgs, start, count))
+        })
+    }
+
+    fn query_time(&self, chat: &HashBuf, time: u64, count: u8, forward: bool) -> Res<Messages> {
+        self.reading(chat, |msgs| {
+            let at = msgs.partition_point(|msg| time_of(msg) < time) as u64;
+            time_range(0, msgs.len() as u64, at, count, forward, |start, count| read(msgs, start, count))
+        })
+    }
+
+    fn stat(&self, chat: &HashBuf) -> Res<ChatStat> {
+        self.reading(chat, |msgs| {
+            let len = msgs.len() as u64;
+            Ok(ChatStat { first_id: 0, len, bytes: len * std::mem::size_of::<MsgBuf>() as u64 })
+        })
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{TcpStream, Shutdown};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::Connection;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+use crate::error::{Error, Kind};
+use crate::helpers::Res;
+
+// A connection that may or may not be TLS. Several handles can share one
+// connection (see `share`): usually one reads while others write. For TLS,
+// the session is only locked to en/decrypt, never while waiting on the socket,
+// so a reader blocked on a quiet socket doesn't hold up writers.
+pub enum Stream {
+    Plain(Arc<TcpStream>),
+    Tls(TlsStream),
+}
+
+pub struct TlsStream {
+    tcp: Arc<TcpStream>,
+    tls: Arc<Mutex<Connection>>,
+    plain: Vec<u8>,  // decrypted, not yet read by this handle
+    pos: usize,
+}
+
+impl Stream {
+    pub fn plain(tcp: TcpStream) -> Self {
+        Self::Plain(Arc::new(tcp))
+    }
+
+    pub fn tls(tcp: TcpStream, tls: impl Into<Connection>) -> Self {
+        Self::Tls(TlsStream {
+            tcp: Arc::new(tcp),
+            tls: Arc::new(Mutex::new(tls.into())),
+            plain: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Plain(tcp) => tcp,
+            Self::Tls(s) => &s.tcp,
+        }
+    }
+
+    pub fn share(&self) -> Self {
+        // another handle on the same connection. Data already
+        // read by this handle is not seen by the new one!
+        match self {
+            Self::Plain(tcp) => Self::Plain(tcp.clone()),
+            Self::Tls(s) => Self::Tls(TlsStream {
+                tcp: s.tcp.clone(),
+                tls: s.tls.clone(),
+                plain: Vec::new(),
+                pos: 0,
+            }),
+        }
+    }
+
+    pub fn close(&self) {
+        // tells the other side we're done; errors don't matter anymore
+        if let Self::Tls(s) = self {
+            if let Ok(mut tls) = TlsStream::lock(&s.tls) {
+                tls.send_close_notify();
+                let _ = TlsStream::flush_tls(&mut tls, &s.tcp);
+            }
+        }
+        let _ = self.tcp().shutdown(Shutdown::Both);
+    }
+}
+
+impl TlsStream {
+    fn lock(tls: &Mutex<Connection>) -> io::Result<MutexGuard<'_, Connection>> {
+        tls.lock().map_err(|_| io::Error::other("TLS session poisoned"))
+    }
+
+    fn flush_tls(tls: &mut Connection, mut tcp: &TcpStream) -> io::Result<()> {
+        while tls.wants_write() {
+            tls.write_tls(&mut tcp)?;
+        }
+        Ok(())
+    }
+
+    fn take_plain(&mut self) -> io::Result<usize> {
+        // moves whatever the session has decrypted into self.plain
+        if self.pos == self.plain.len() {
+            self.plain.clear();
+            self.pos = 0;
+        }
+        let mut tls = Self::lock(&self.tls)?;
+        let mut chunk = [0; 4096];
+        match tls.reader().read(&mut chunk) {
+            Ok(len) => {
+                self.plain.extend_from_slice(&chunk[..len]);
+                Ok(len)
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),  // nothing yet
+            Err(e) => Err(e),
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<bool> {
+        // makes sure there's something in self.plain; false on EOF
+        while self.pos == self.plain.len() {
+            if self.take_plain()? > 0 { break }
+
+            // wait for the socket without holding the session
+            if self.tcp.peek(&mut [0])? == 0 { return Ok(false) }
+
+            let mut tls = Self::lock(&self.tls)?;
+            if tls.read_tls(&mut &*self.tcp)? == 0 { return Ok(false) }
+            let res = tls.process_new_packets();
+            Self::flush_tls(&mut tls, &self.tcp)?;  // handshake messages or alerts
+            if let Err(e) = res {
+                return Err(io::Error::new(ErrorKind::InvalidData, e));
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(tcp) => (&**tcp).read(buf),
+            Self::Tls(s) => {
+                if !s.fill()? { return Ok(0) }
+                let len = buf.len().min(s.plain.len() - s.pos);
+                buf[..len].copy_from_slice(&s.plain[s.pos..][..len]);
+                s.pos += len;
+                Ok(len)
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(tcp) => (&**tcp).write(buf),
+            Self::Tls(s) => {
+                let mut tls = TlsStream::lock(&s.tls)?;
+                tls.writer().write_all(buf)?;
+                TlsStream::flush_tls(&mut tls, &s.tcp)?;
+                Ok(buf.len())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(tcp) => (&**tcp).flush(),
+            Self::Tls(s) => TlsStream::flush_tls(&mut *TlsStream::lock(&s.tls)?, &s.tcp),
+        }
+    }
+}
+
+pub fn load_certs(path: &Path) -> Res<Vec<CertificateDer<'static>>> {
+    // all certificates in a PEM file
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| Error::with_source(Kind::Io, "Failed to read certificate file", e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::with_source(Kind::Crypto, "Invalid certificate file", e))?;
+    if certs.is_empty() { return Err(Error::crypto("No certificates in file")) }
+    Ok(certs)
+}
+
+pub fn load_key(path: &Path) -> Res<PrivateKeyDer<'static>> {
+    // first private key in a PEM file
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| Error::with_source(Kind::Crypto, "Failed to read private key file", e))
+}